@@ -0,0 +1,105 @@
+//! Measures how many records `Tree::insert` writes per second, one insert at a
+//! time and with many inserts in flight at once. Run with `cargo bench`.
+//!
+//! Writes don't wait for the WAL, otherwise every insert would wait for the
+//! WAL to flush its buffer and the bench would only measure the flush timer.
+
+use std::time::{Duration, Instant};
+
+use tokactordb::{Database, DatabaseOptions, Durability, FileSystem, SubTree, Tree, U64};
+
+const RECORDS: usize = 10_000;
+const IN_FLIGHT: usize = 100;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Item {
+    name: String,
+    count: usize,
+}
+
+impl Item {
+    fn new(count: usize) -> Self {
+        Self {
+            name: format!("item {}", count % 10),
+            count,
+        }
+    }
+}
+
+struct Bench {
+    _db: Database,
+    items: Tree<U64, Item>,
+    _by_name: Option<SubTree<String, Item>>,
+}
+
+async fn open(indexed: bool) -> Bench {
+    let options = DatabaseOptions::new().durability(Durability::None);
+    let db = Database::with_options(FileSystem::in_memory(()), options)
+        .await
+        .unwrap();
+    let items = db
+        .create::<U64, Item>("items")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    let by_name = if indexed {
+        let index = db
+            .create_index("items by name", &items, |item| Some(&item.name))
+            .await
+            .unwrap();
+        Some(index)
+    } else {
+        None
+    };
+    db.restore().await.unwrap();
+    Bench {
+        _db: db,
+        items,
+        _by_name: by_name,
+    }
+}
+
+async fn sequential(items: &Tree<U64, Item>) {
+    for count in 0..RECORDS {
+        items.insert(Item::new(count)).await.unwrap();
+    }
+}
+
+async fn concurrent(items: &Tree<U64, Item>) {
+    for start in (0..RECORDS).step_by(IN_FLIGHT) {
+        let inserts = (start..start + IN_FLIGHT).map(|count| items.insert(Item::new(count)));
+        futures::future::try_join_all(inserts).await.unwrap();
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let per_second = RECORDS as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<24} {:>8.1?} {:>10.0} inserts/s",
+        name, elapsed, per_second
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        for indexed in [false, true] {
+            let suffix = if indexed { " (indexed)" } else { "" };
+
+            let bench = open(indexed).await;
+            let started = Instant::now();
+            sequential(&bench.items).await;
+            report(&format!("sequential{}", suffix), started.elapsed());
+
+            let bench = open(indexed).await;
+            let started = Instant::now();
+            concurrent(&bench.items).await;
+            report(&format!("concurrent{}", suffix), started.elapsed());
+        }
+    });
+}
@@ -4,6 +4,7 @@ use std::{
     time::SystemTime,
 };
 
+use futures::TryStreamExt;
 use tokactordb::{
     Aggregate, AggregateTree, Change, Database, FileSystem, SubTree, Tree, Update, ID, U32, U64,
 };
@@ -209,11 +210,9 @@ async fn board<'a>(
                 board_store.insert(BoardV2::new(name)).await?;
             }
             "list" => {
-                let mut list = board_store.list().await;
-                while let Some((id, board)) = list.next().await {
-                    if let Some(board) = board {
-                        cli.write(format!("{} -> {}", id, board))?;
-                    }
+                let mut list = board_store.list();
+                while let Some((id, board)) = list.try_next().await? {
+                    cli.write(format!("{} -> {}", id, board))?;
                 }
             }
             "open" => {
@@ -278,6 +277,13 @@ async fn tickets<'a>(
                     cli.error(format!("ID {} does not exist", str_id.trim()))?;
                 }
             }
+            "delete" => {
+                let str_id = cli.read_line("Id > ")?;
+                let id = str_id.trim().parse::<u64>()?;
+                if ticket_store.delete(id).await?.is_none() {
+                    cli.error(format!("ID {} does not exist", str_id.trim()))?;
+                }
+            }
             "list" => {
                 let stat = board_stats.get(board.clone()).await?.unwrap_or_default();
                 cli.write(format!(
@@ -0,0 +1,255 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use tokactor::{Actor, AsyncAsk, Ctx};
+
+use crate::actors::{
+    fs::{DbFile, OpenFileOptions},
+    manifest::{ManifestEvent, ReserveTableId},
+    table::{table_file_name, Entries, Merge, Table, TableOptions, TableWriter},
+};
+
+use super::{messages::Compact, options::CompactionOptions};
+
+/// Merges table files in the background. Compactions of every tree are run one
+/// at a time, so together they never write faster then the configured rate.
+pub struct CompactionActor {
+    options: CompactionOptions,
+    table_options: TableOptions,
+}
+
+impl Actor for CompactionActor {}
+
+impl CompactionActor {
+    pub fn new(options: CompactionOptions, table_options: TableOptions) -> Self {
+        Self {
+            options,
+            table_options,
+        }
+    }
+
+    /// Merge the input tables into new tables that are split once they reach
+    /// `table_file_size`. Only the newest entry of each key is kept. The ids of
+    /// every table file that was created are added to `created`, so they can be
+    /// removed if the compaction fails.
+    async fn compact(&self, job: &Compact, created: &mut Vec<u64>) -> anyhow::Result<Vec<Table>> {
+        let mut tables = Vec::with_capacity(job.inputs.len());
+        for id in job.inputs.iter() {
+            let file = job
+                .fs
+                .open(OpenFileOptions::new(table_file_name(*id)).read())
+                .await?;
+            tables.push(Table::open(*id, file)?);
+        }
+
+        let mut limiter = RateLimiter::new(self.options.rate);
+        let mut outputs = Vec::new();
+        let mut writer: Option<(u64, TableWriter<DbFile>)> = None;
+        let sources = tables
+            .iter_mut()
+            .map(|table| Box::new(table.iter(false)) as Entries<'_>)
+            .collect();
+        for entry in Merge::new(sources, false) {
+            let (key, value) = entry?;
+            if value.is_none() && job.drop_tombstones {
+                continue;
+            }
+            let (_, table) = match writer.as_mut() {
+                Some(writer) => writer,
+                None => {
+                    let id = job.manifest.ask(ReserveTableId).await?;
+                    created.push(id);
+                    let options = OpenFileOptions::new(table_file_name(id))
+                        .read()
+                        .write()
+                        .create()
+                        .truncate();
+                    let file = job.fs.open(options).await?;
+                    writer.insert((id, TableWriter::new(file, &self.table_options)?))
+                }
+            };
+            let before = table.size();
+            table.add(key, value)?;
+            let size = table.size();
+            limiter.consume(size.saturating_sub(before)).await;
+            if size >= self.options.table_file_size {
+                let (id, table) = writer.take().unwrap();
+                outputs.push(finish(id, table)?);
+            }
+        }
+        if let Some((id, table)) = writer.take() {
+            outputs.push(finish(id, table)?);
+        }
+        let outputs = outputs
+            .into_iter()
+            .map(|table| table.with_cache(job.cache.clone()))
+            .collect::<Vec<_>>();
+
+        // The tables are swapped in a single event so that a restart either sees
+        // the inputs or the outputs, but never both
+        let event = ManifestEvent::TablesCompacted {
+            tree: job.tree.clone(),
+            level: job.level,
+            inputs: job.inputs.clone(),
+            outputs: outputs.iter().map(Table::id).collect(),
+        };
+        job.manifest.ask(event).await??;
+        Ok(outputs)
+    }
+}
+
+fn finish(id: u64, writer: TableWriter<DbFile>) -> anyhow::Result<Table> {
+    let mut file = writer.finish()?;
+    file.sync_all()?;
+    Table::open(id, file)
+}
+
+/// Slow down a compaction once it has written more bytes then it is allowed to
+/// for the time it has been running
+struct RateLimiter {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.rate == 0 {
+            return;
+        }
+        let allowed = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let ahead = allowed.saturating_sub(self.started.elapsed());
+        // Don't bother sleeping for tiny amounts of time
+        if ahead >= Duration::from_millis(10) {
+            tokio::time::sleep(ahead).await;
+        }
+    }
+}
+
+impl AsyncAsk<Compact> for CompactionActor {
+    type Output = anyhow::Result<Vec<Table>>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, job: Compact, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move {
+            let mut created = Vec::new();
+            let result = self.compact(&job, &mut created).await;
+            if result.is_err() {
+                for id in created {
+                    let _ = job.fs.remove_file(table_file_name(id)).await;
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Write};
+
+    use tokactor::Actor;
+
+    use crate::{
+        actors::{
+            fs::{FileSystemFacade, OpenFileOptions},
+            manifest::Manifest,
+            table::{table_file_name, write_table, BlockCache, Table, TableOptions},
+            tree::MemRecord,
+        },
+        FileSystem,
+    };
+
+    use super::{Compact, CompactionActor, CompactionOptions};
+
+    fn record(data: &str) -> Option<MemRecord> {
+        Some(MemRecord {
+            version: 0,
+            data: data.as_bytes().to_vec(),
+        })
+    }
+
+    async fn create_table(fs: &FileSystemFacade, id: u64, entries: &[(u8, Option<MemRecord>)]) {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| (vec![*key], value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let options = OpenFileOptions::new(table_file_name(id)).write().create();
+        let mut file = fs.open(options).await.unwrap();
+        let bytes = write_table(&entries, &TableOptions::default()).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.flush().unwrap();
+    }
+
+    async fn compact(drop_tombstones: bool) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let fs = FileSystemFacade::new(FileSystem::in_memory(&[] as &[(&str, &str)]).start());
+        fs.open_base_dir().await.unwrap();
+        let manifest = Manifest::recover(fs.clone()).await.unwrap().start();
+        create_table(
+            &fs,
+            0,
+            &[(1, record("old")), (2, record("old")), (3, record("old"))],
+        )
+        .await;
+        create_table(&fs, 1, &[(1, record("new")), (2, None)]).await;
+
+        let job = Compact {
+            tree: "tree".to_string(),
+            fs,
+            manifest,
+            cache: BlockCache::new(0),
+            inputs: vec![1, 0],
+            level: 1,
+            drop_tombstones,
+        };
+        let actor = CompactionActor::new(CompactionOptions::default(), TableOptions::default());
+        let mut created = Vec::new();
+        let mut outputs = actor.compact(&job, &mut created).await.unwrap();
+        assert_eq!(outputs.iter().map(Table::id).collect::<Vec<_>>(), created);
+        outputs
+            .iter_mut()
+            .flat_map(|table| table.iter(false).collect::<Vec<_>>())
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key, value.map(|record| record.data))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keep_only_the_newest_entry_of_each_key() {
+        let entries = compact(false).await;
+        assert_eq!(
+            entries,
+            vec![
+                (vec![1], Some(b"new".to_vec())),
+                (vec![2], None),
+                (vec![3], Some(b"old".to_vec())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_tombstones_in_the_last_level() {
+        let entries = compact(true).await;
+        assert_eq!(
+            entries,
+            vec![
+                (vec![1], Some(b"new".to_vec())),
+                (vec![3], Some(b"old".to_vec())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use tokactor::ActorRef;
+
+use crate::actors::{
+    fs::FileSystemFacade,
+    manifest::Manifest,
+    table::{BlockCache, Table},
+};
+
+/// Merge the table files of a tree into a new set of table files
+#[derive(Debug)]
+pub struct Compact {
+    pub tree: String,
+    /// Directory that stores all of the table files
+    pub fs: FileSystemFacade,
+    pub manifest: ActorRef<Manifest>,
+    /// Cache used by the tables that are created
+    pub cache: BlockCache,
+    /// Tables to merge, from newest to oldest
+    pub inputs: Vec<u64>,
+    /// Level the merged tables are added to
+    pub level: usize,
+    /// Tombstones only need to be kept while there are older tables below the
+    /// output level that could still hold the key they delete
+    pub drop_tombstones: bool,
+}
+
+/// Result of a compaction that is sent back to the tree that asked for it
+pub struct Compacted {
+    pub level: usize,
+    pub inputs: Vec<u64>,
+    /// Tables that replace the inputs, ordered by key
+    pub outputs: anyhow::Result<Vec<Table>>,
+}
@@ -0,0 +1,54 @@
+mod actor;
+mod messages;
+mod options;
+
+use tokactor::{Actor, ActorRef, Ctx, DeadActorResult, Handler};
+
+use super::table::TableOptions;
+
+pub use actor::CompactionActor;
+pub use messages::{Compact, Compacted};
+pub use options::CompactionOptions;
+
+#[derive(Clone)]
+pub struct Compactor {
+    inner: ActorRef<CompactionActor>,
+    options: CompactionOptions,
+}
+
+pub fn new_compaction_actor<A>(
+    ctx: &mut Ctx<A>,
+    options: CompactionOptions,
+    table_options: TableOptions,
+) -> Compactor
+where
+    A: Actor + Handler<DeadActorResult<CompactionActor>>,
+{
+    let address = ctx.spawn(CompactionActor::new(options.clone(), table_options));
+    Compactor {
+        inner: address,
+        options,
+    }
+}
+
+impl Compactor {
+    pub fn options(&self) -> &CompactionOptions {
+        &self.options
+    }
+
+    /// Run a compaction once every compaction that was asked for before it has
+    /// finished
+    pub async fn compact(&self, job: Compact) -> Compacted {
+        let level = job.level;
+        let inputs = job.inputs.clone();
+        let outputs = match self.inner.async_ask(job).await {
+            Ok(outputs) => outputs,
+            Err(err) => Err(anyhow::anyhow!("Failed to run compaction: {:?}", err)),
+        };
+        Compacted {
+            level,
+            inputs,
+            outputs,
+        }
+    }
+}
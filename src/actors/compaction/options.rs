@@ -0,0 +1,38 @@
+/// Settings that decide when table files are compacted and how much work a
+/// compaction can do at once
+#[derive(Debug, Clone)]
+pub struct CompactionOptions {
+    /// Number of table files in level 0 that start a compaction into level 1
+    pub l0_compaction_trigger: usize,
+    /// Number of bytes level 1 can hold before it is compacted into level 2.
+    /// Every level after it can hold 10 times more then the level before it.
+    pub level_size_base: u64,
+    /// Size a table file written by a compaction can grow to before a new one
+    /// is started
+    pub table_file_size: u64,
+    /// Number of bytes per second a compaction can write
+    pub rate: u64,
+}
+
+impl CompactionOptions {
+    /// Number of bytes a level can hold before it is compacted into the next
+    /// level. Level 0 is compacted based on its number of tables instead.
+    pub fn max_level_size(&self, level: usize) -> u64 {
+        let mut size = self.level_size_base;
+        for _ in 1..level {
+            size = size.saturating_mul(10);
+        }
+        size
+    }
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        Self {
+            l0_compaction_trigger: 4,
+            level_size_base: 10 * 1024 * 1024,
+            table_file_size: 2 * 1024 * 1024,
+            rate: 32 * 1024 * 1024,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, future::Future, pin::Pin, time::Duration};
+use std::{collections::HashMap, future::Future, pin::Pin};
 
 use tokactor::{
     util::builder::ActorAskRef, Actor, ActorRef, Ask, AsyncAsk, Ctx, DeadActorResult, Handler,
@@ -6,36 +6,75 @@ use tokactor::{
 
 use crate::{
     actors::{
+        compaction::{new_compaction_actor, CompactionActor, Compactor},
         fs::FileSystem,
-        subtree::{AggregateTreeActor, IndexTreeActor, UtilTreeAddress},
-        tree::{tree_actor, PrimaryKey, RecordValue, TreeActor},
-        wal::{Item, Wal, WalActor, WalRestoredItems},
+        manifest::Manifest,
+        subtree::{
+            AggregateTreeActor, IndexTreeActor, SortedIndexActor, SortedIndexAddress,
+            UniqueIndexActor, UniqueIndexAddress, UtilTreeAddress,
+        },
+        table::BlockCache,
+        tree::{tree_actor, PrimaryKey, RecordValue, RestoreTables, TreeActor},
+        wal::{new_wal_actor, Item, Wal, WalActor, WalRestoredItems},
     },
     Aggregate, AggregateTree, SubTree,
 };
 
 use super::{
-    messages::{NewTreeRoot, RestoreDbPath},
+    messages::{NewTreeRoot, OpenTree, RestoreStorage, RestoreWal},
     version::{UpgradeVersion, UpgradedVersion, VersionedTreeUpgradeActor},
-    RequestWal, RestoreComplete,
+    DatabaseOptions, RequestWal, RestoreComplete,
 };
 
 pub struct DbActor {
+    options: DatabaseOptions,
     wal: Option<Wal>,
+    compactor: Option<Compactor>,
+    cache: BlockCache,
     trees: HashMap<String, ActorRef<TreeActor>>,
+    /// Storage of the trees, kept once the database has been restored so that
+    /// trees created after that can be opened
+    storage: Option<RestoreStorage>,
 }
 
 impl DbActor {
-    pub fn new() -> Self {
+    pub fn new(options: DatabaseOptions, cache: BlockCache) -> Self {
         Self {
+            options,
+            cache,
             wal: None,
+            compactor: None,
             trees: HashMap::new(),
+            storage: None,
         }
     }
 
     fn wal(&self) -> Wal {
         (*self.wal.as_ref().unwrap()).clone()
     }
+
+    /// Reject every new write once an actor that the database can't work
+    /// without has stopped. Records can still be read.
+    fn stopped<A: Actor>(&self, name: &str, result: DeadActorResult<A>, ctx: &mut Ctx<Self>) {
+        let reason = match result {
+            Ok(_) => format!("the {} stopped", name),
+            Err(err) => format!("the {} failed: {:?}", name, err),
+        };
+        println!("Database is read-only because {}", reason);
+        if let Some(wal) = self.wal.clone() {
+            ctx.anonymous_task(async move { wal.read_only(reason).await });
+        }
+    }
+
+    fn restore_tables(&self, name: &str, storage: &RestoreStorage) -> RestoreTables {
+        RestoreTables {
+            fs: storage.fs.clone(),
+            manifest: storage.manifest.clone(),
+            cache: self.cache.clone(),
+            levels: storage.state.tables.get(name).cloned().unwrap_or_default(),
+            sequence: storage.state.sequences.get(name).cloned(),
+        }
+    }
 }
 
 impl Actor for DbActor {
@@ -43,8 +82,14 @@ impl Actor for DbActor {
     where
         Self: Actor,
     {
-        // let wal = new_wal_actor(ctx, Duration::from_millis(10));
-        // self.wal = Some(wal);
+        let wal = new_wal_actor(ctx, self.options.wal.clone());
+        self.wal = Some(wal);
+        let compactor = new_compaction_actor(
+            ctx,
+            self.options.compaction.clone(),
+            self.options.table.clone(),
+        );
+        self.compactor = Some(compactor);
     }
 }
 
@@ -52,32 +97,41 @@ impl Ask<NewTreeRoot> for DbActor {
     type Result = ActorRef<TreeActor>;
 
     fn handle(&mut self, message: NewTreeRoot, context: &mut Ctx<Self>) -> Self::Result {
-        let address = tree_actor(message.name.clone(), message.versions, self.wal(), context);
+        let address = tree_actor(
+            message.name.clone(),
+            message.versions,
+            self.wal(),
+            self.options.memtable_size,
+            self.options.table.clone(),
+            self.compactor.clone().unwrap(),
+            context,
+        );
         self.trees.insert(message.name, address.clone());
         address
     }
 }
 
-impl AsyncAsk<RestoreDbPath> for DbActor {
-    type Output = WalRestoredItems;
+impl AsyncAsk<RestoreWal> for DbActor {
+    type Output = anyhow::Result<WalRestoredItems>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle<'a>(&'a mut self, msg: RestoreDbPath, _: &mut Ctx<Self>) -> Self::Future<'a> {
-        // The restore message given to us here is a directory. In the future we'd
-        // want to do some advanced logic here such as like  get metadata files
-        // and read all of the WAL logs that are avaliable to us.
-
-        // For now we'll just hard code the one file ;)
-        assert!(msg.directory.is_dir());
+    fn handle<'a>(&'a mut self, msg: RestoreWal, _: &mut Ctx<Self>) -> Self::Future<'a> {
         let wal_address = self.wal();
-        let wal_path = msg.directory.join("wal");
+        Box::pin(async move { wal_address.restore(msg.fs, msg.manifest, msg.state).await })
+    }
+}
 
+impl AsyncAsk<RestoreStorage> for DbActor {
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: RestoreStorage, _: &mut Ctx<Self>) -> Self::Future<'a> {
         Box::pin(async move {
-            if !wal_path.exists() {
-                WalRestoredItems::new(vec![])
-            } else {
-                wal_address.restore(wal_path).await
+            for (name, tree) in self.trees.iter() {
+                tree.async_ask(self.restore_tables(name, &msg)).await??;
             }
+            self.storage = Some(msg);
+            Ok(())
         })
     }
 }
@@ -103,6 +157,25 @@ impl AsyncAsk<RestoreComplete> for DbActor {
     }
 }
 
+impl AsyncAsk<OpenTree> for DbActor {
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: OpenTree, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move {
+            // Trees created before the restore are opened by it
+            let (storage, tree) = match (self.storage.as_ref(), self.trees.get(&msg.name)) {
+                (Some(storage), Some(tree)) => (storage, tree),
+                _ => return Ok(()),
+            };
+            tree.async_ask(self.restore_tables(&msg.name, storage))
+                .await??;
+            tree.ask(RestoreComplete).await?;
+            Ok(())
+        })
+    }
+}
+
 impl AsyncAsk<Item> for DbActor {
     type Output = anyhow::Result<()>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
@@ -142,6 +215,31 @@ where
     }
 }
 
+impl<ID, Key, Value> Ask<UniqueIndexActor<ID, Key, Value>> for DbActor
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Result = UniqueIndexAddress<ID, Key, Value>;
+
+    fn handle(
+        &mut self,
+        idx: UniqueIndexActor<ID, Key, Value>,
+        ctx: &mut Ctx<Self>,
+    ) -> Self::Result {
+        idx.spawn_with_ctx(ctx)
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> Ask<SortedIndexActor<Key, Value>> for DbActor {
+    type Result = SortedIndexAddress<Key, Value>;
+
+    fn handle(&mut self, idx: SortedIndexActor<Key, Value>, ctx: &mut Ctx<Self>) -> Self::Result {
+        idx.spawn_with_ctx(ctx)
+    }
+}
+
 impl<
         ID: PrimaryKey,
         Record: Aggregate<Key, Value> + RecordValue,
@@ -186,11 +284,23 @@ impl Ask<FileSystem> for DbActor {
     }
 }
 
+impl Ask<Manifest> for DbActor {
+    type Result = ActorRef<Manifest>;
+
+    fn handle(&mut self, manifest: Manifest, ctx: &mut Ctx<Self>) -> Self::Result {
+        ctx.spawn(manifest)
+    }
+}
+
 /*******************************************************************************
  * Handle the death of child actors
  * - TreeActor
  * - WalActor
+ * - CompactionActor
+ * - Manifest
  * - IndexTreeActor
+ * - SortedIndexActor
+ * - UniqueIndexActor
  * - AggregateTreeActor
  ******************************************************************************/
 
@@ -206,12 +316,24 @@ impl Handler<DeadActorResult<WalActor>> for DbActor {
     }
 }
 
+impl Handler<DeadActorResult<CompactionActor>> for DbActor {
+    fn handle(&mut self, result: DeadActorResult<CompactionActor>, ctx: &mut Ctx<Self>) {
+        self.stopped("compaction actor", result, ctx);
+    }
+}
+
 impl Handler<DeadActorResult<FileSystem>> for DbActor {
     fn handle(&mut self, _: DeadActorResult<FileSystem>, _: &mut Ctx<Self>) {
         todo!()
     }
 }
 
+impl Handler<DeadActorResult<Manifest>> for DbActor {
+    fn handle(&mut self, result: DeadActorResult<Manifest>, ctx: &mut Ctx<Self>) {
+        self.stopped("manifest", result, ctx);
+    }
+}
+
 impl<ID, Key, Value> Handler<DeadActorResult<IndexTreeActor<ID, Key, Value>>> for DbActor
 where
     ID: PrimaryKey,
@@ -223,6 +345,40 @@ where
     }
 }
 
+impl<ID, Key, Value> Handler<DeadActorResult<UniqueIndexActor<ID, Key, Value>>> for DbActor
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    /// Writes to the source tree fail once the index can't be asked for claims
+    fn handle(
+        &mut self,
+        result: DeadActorResult<UniqueIndexActor<ID, Key, Value>>,
+        _: &mut Ctx<Self>,
+    ) {
+        match result {
+            Ok(dead) => println!("Unique index {} stopped", dead.actor.name()),
+            Err(err) => println!("Unique index failed: {:?}", err),
+        }
+    }
+}
+
+impl<Key, Value> Handler<DeadActorResult<SortedIndexActor<Key, Value>>> for DbActor
+where
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    /// Reads of the index and writes to the source tree fail once the index is
+    /// gone
+    fn handle(&mut self, result: DeadActorResult<SortedIndexActor<Key, Value>>, _: &mut Ctx<Self>) {
+        match result {
+            Ok(dead) => println!("Sorted index {} stopped", dead.actor.name()),
+            Err(err) => println!("Sorted index failed: {:?}", err),
+        }
+    }
+}
+
 impl<
         ID: PrimaryKey,
         Record: Aggregate<Key, Value> + RecordValue,
@@ -0,0 +1,437 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc};
+
+use tokactor::ActorRef;
+use tokio::sync::{oneshot, RwLock};
+
+use crate::{
+    actors::{
+        tree::{ApplyBatch, PrimaryKey, RecordValue, TreeActor},
+        wal::Durability,
+    },
+    codec, AutoIncrement, Tree,
+};
+
+use super::{actor::DbActor, RequestWal};
+
+/// Tells the indexes and aggregates of a tree about a write
+type Notify = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Reads the indexes and aggregates of a tree once the batch has been written,
+/// while the batch still holds the write permit of the tree
+type Notifier = Box<dyn FnOnce() -> Notify + Send>;
+
+/// Gives up the identities that a write claimed from unique indexes
+type Release = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Claims the identities of a write from the unique indexes of its tree
+type Claim = Pin<Box<dyn Future<Output = anyhow::Result<Release>> + Send>>;
+
+struct BatchWrite {
+    tree: String,
+    address: ActorRef<TreeActor>,
+    /// Write permits of the tree, see [`Tree::write_permit`]
+    writes: Arc<RwLock<()>>,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    claim: Option<Claim>,
+    notify: Notifier,
+}
+
+/// A record that was read by a transaction, the value it had and the sequence
+/// of the last write to it
+struct BatchRead {
+    tree: String,
+    address: ActorRef<TreeActor>,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    sequence: u64,
+}
+
+/// Writes to one or more trees that are committed together. Every write of the
+/// batch is stored in the same WAL frame, so after a crash either all of them
+/// are restored or none of them are. Indexes and aggregates are only told about
+/// the writes once the whole batch has been committed.
+pub struct WriteBatch {
+    database: ActorRef<DbActor>,
+    writes: Vec<BatchWrite>,
+    /// Records read by a transaction. The batch fails to commit if any of them
+    /// change before it is committed. Plain batches don't track their reads.
+    reads: Option<Vec<BatchRead>>,
+    durability: Option<Durability>,
+}
+
+impl WriteBatch {
+    pub(crate) fn new(database: ActorRef<DbActor>) -> Self {
+        Self {
+            database,
+            writes: Vec::new(),
+            reads: None,
+            durability: None,
+        }
+    }
+
+    /// A batch that remembers every record it reads, and only commits if none of
+    /// them have changed
+    pub(crate) fn tracked(database: ActorRef<DbActor>) -> Self {
+        Self {
+            reads: Some(Vec::new()),
+            ..Self::new(database)
+        }
+    }
+
+    /// Commit the batch with `durability` instead of the default durability of
+    /// the database
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Insert a value into a tree once the batch is committed. The key is
+    /// reserved right away, and is never handed out again even if the batch is
+    /// dropped.
+    pub async fn insert<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        value: Value,
+    ) -> anyhow::Result<Key>
+    where
+        Key: PrimaryKey + AutoIncrement,
+        Value: RecordValue,
+    {
+        tree.check(&value)?;
+        let key = tree.get_unique_key().await?;
+        let id = codec::to_vec(&key)?;
+        let json = serde_json::to_vec(&value)?;
+
+        let source = tree.duplicate();
+        let (arc_key, arc_value) = (Arc::new(key.clone()), Arc::new(value));
+        let claim = Self::claim(tree, &key, &arc_value);
+        let notify = move || -> Notify {
+            let created = source
+                .subscribers()
+                .into_iter()
+                .map(|subscriber| subscriber.created(arc_key.clone(), arc_value.clone()))
+                .collect::<Vec<_>>();
+            Box::pin(async move {
+                futures::future::try_join_all(created).await?;
+                Ok(())
+            })
+        };
+        self.push(tree, id, Some(json), Some(claim), Box::new(notify))?;
+        Ok(key)
+    }
+
+    /// Replace the value of a key in a tree once the batch is committed
+    pub async fn update<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        id: impl Into<Key>,
+        value: Value,
+    ) -> anyhow::Result<()>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        tree.check(&value)?;
+        let key = id.into();
+        let id = codec::to_vec(&key)?;
+        let old = self.stored(tree, &key, &id).await?.map(Arc::new);
+        let json = serde_json::to_vec(&value)?;
+
+        let source = tree.duplicate();
+        let arc_value = Arc::new(value);
+        let claim = Self::claim(tree, &key, &arc_value);
+        let arc_key = Arc::new(key);
+        let notify = move || -> Notify {
+            let subscribers = source.subscribers();
+            Box::pin(async move {
+                let updated = subscribers.iter().map(|subscriber| {
+                    subscriber.updated(arc_key.clone(), old.clone(), arc_value.clone())
+                });
+                futures::future::try_join_all(updated).await?;
+                Ok(())
+            })
+        };
+        self.push(tree, id, Some(json), Some(claim), Box::new(notify))
+    }
+
+    /// Delete a key from a tree once the batch is committed. Nothing is written
+    /// if the key doesn't exist.
+    pub async fn delete<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        id: impl Into<Key>,
+    ) -> anyhow::Result<()>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let key = id.into();
+        let id = codec::to_vec(&key)?;
+        let old = match self.stored(tree, &key, &id).await? {
+            Some(old) => Arc::new(old),
+            None => {
+                // A transaction can delete a record that it inserted itself
+                if self.reads.is_some() {
+                    self.writes
+                        .retain(|write| write.tree != tree.name() || write.key != id);
+                }
+                return Ok(());
+            }
+        };
+
+        let source = tree.duplicate();
+        let arc_key = Arc::new(key);
+        let notify = move || -> Notify {
+            let deleted = source
+                .subscribers()
+                .into_iter()
+                .map(|subscriber| subscriber.deleted(arc_key.clone(), old.clone()))
+                .collect::<Vec<_>>();
+            Box::pin(async move {
+                futures::future::try_join_all(deleted).await?;
+                Ok(())
+            })
+        };
+        self.push(tree, id, None, None, Box::new(notify))
+    }
+
+    /// Read a record as the batch sees it. Values written by the batch are
+    /// returned before they are committed.
+    pub(crate) async fn get<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        key: &Key,
+    ) -> anyhow::Result<Option<Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let id = codec::to_vec(key)?;
+        let written = self
+            .writes
+            .iter()
+            .find(|write| write.tree == tree.name() && write.key == id);
+        match written {
+            Some(write) => match &write.value {
+                Some(value) => Ok(Some(serde_json::from_slice(value)?)),
+                None => Ok(None),
+            },
+            None => self.stored(tree, key, &id).await,
+        }
+    }
+
+    /// Read the value of a record that is stored in the tree, ignoring anything
+    /// written by the batch. Transactions remember the value the first time it
+    /// is read, so every read sees the same value.
+    async fn stored<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        key: &Key,
+        id: &[u8],
+    ) -> anyhow::Result<Option<Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let value = match self.reads.as_mut() {
+            None => tree.get_bytes(key).await?.0,
+            Some(reads) => {
+                let read = reads
+                    .iter()
+                    .find(|read| read.tree == tree.name() && read.key == id);
+                match read {
+                    Some(read) => read.value.clone(),
+                    None => {
+                        let (value, sequence) = tree.get_bytes(key).await?;
+                        reads.push(BatchRead {
+                            tree: tree.name().to_string(),
+                            address: tree.address(),
+                            key: id.to_vec(),
+                            value: value.clone(),
+                            sequence,
+                        });
+                        value
+                    }
+                }
+            }
+        };
+        match value {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn push<Key, Value>(
+        &mut self,
+        tree: &Tree<Key, Value>,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        claim: Option<Claim>,
+        notify: Notifier,
+    ) -> anyhow::Result<()>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let existing = self
+            .writes
+            .iter()
+            .position(|write| write.tree == tree.name() && write.key == key);
+        if let Some(index) = existing {
+            // A transaction knows the stored value of every key it writes, so a
+            // later write of the same key can replace the earlier one. A plain
+            // batch would tell the indexes and aggregates the wrong old value.
+            if self.reads.is_none() {
+                anyhow::bail!(
+                    "A key of {} is written more than once in the batch",
+                    tree.name()
+                );
+            }
+            self.writes.remove(index);
+        }
+        self.writes.push(BatchWrite {
+            tree: tree.name().to_string(),
+            address: tree.address(),
+            writes: tree.writes(),
+            key,
+            value,
+            claim,
+            notify,
+        });
+        Ok(())
+    }
+
+    /// Claims are only made when the batch is committed, so that a batch that is
+    /// dropped never holds on to them
+    fn claim<Key, Value>(tree: &Tree<Key, Value>, key: &Key, value: &Arc<Value>) -> Claim
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let claim = tree.claim(Some(key), value);
+        Box::pin(async move {
+            let claims = claim.await?;
+            Ok(Box::pin(claims.release()) as Release)
+        })
+    }
+
+    /// Write the batch to the WAL and then apply it to every tree. Each tree
+    /// stops handling other messages until the batch has been applied to it.
+    /// Returns a [`crate::TransactionConflict`] if a record read by a transaction
+    /// has changed, in which case nothing is written.
+    pub async fn commit(self) -> anyhow::Result<()> {
+        let WriteBatch {
+            database,
+            mut writes,
+            reads,
+            durability,
+        } = self;
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        // Identities are claimed before any tree is locked, and given up again
+        // if the batch isn't written
+        let mut releases = Vec::new();
+        for claim in writes.iter_mut().filter_map(|write| write.claim.take()) {
+            match claim.await {
+                Ok(release) => releases.push(release),
+                Err(err) => {
+                    futures::future::join_all(releases).await;
+                    return Err(err);
+                }
+            }
+        }
+
+        // Each tree hands out one permit to the batch, in order of their names
+        let mut locks = BTreeMap::new();
+        for write in writes.iter() {
+            locks
+                .entry(write.tree.clone())
+                .or_insert_with(|| write.writes.clone());
+        }
+        let mut permits = Vec::with_capacity(locks.len());
+        for lock in locks.into_values() {
+            permits.push(lock.read_owned().await);
+        }
+
+        let mut notifies = Vec::with_capacity(writes.len());
+        let result = Self::write(database, writes, reads, durability, &mut notifies).await;
+        if result.is_err() {
+            drop(permits);
+            futures::future::join_all(releases).await;
+            return result;
+        }
+
+        let notifies = notifies
+            .into_iter()
+            .map(|notify| notify())
+            .collect::<Vec<_>>();
+        drop(permits);
+        futures::future::try_join_all(notifies).await?;
+        Ok(())
+    }
+
+    async fn write(
+        database: ActorRef<DbActor>,
+        writes: Vec<BatchWrite>,
+        reads: Option<Vec<BatchRead>>,
+        durability: Option<Durability>,
+        notifies: &mut Vec<Notifier>,
+    ) -> anyhow::Result<()> {
+        let wal = database.ask(RequestWal()).await?;
+
+        // Trees are always locked in order of their name, so two batches that
+        // use the same trees can't end up waiting on each other
+        let mut trees = BTreeMap::new();
+        for read in reads.unwrap_or_default() {
+            let (_, _, tree_reads) = trees
+                .entry(read.tree)
+                .or_insert_with(|| (read.address, Vec::new(), Vec::new()));
+            tree_reads.push((read.key, read.sequence));
+        }
+        for write in writes {
+            let (_, tree_writes, _) = trees
+                .entry(write.tree)
+                .or_insert_with(|| (write.address, Vec::new(), Vec::new()));
+            tree_writes.push((write.key, write.value));
+            notifies.push(write.notify);
+        }
+
+        let mut items = Vec::new();
+        let mut commits = Vec::with_capacity(trees.len());
+        let mut applies = Vec::with_capacity(trees.len());
+        for (name, (address, tree_writes, tree_reads)) in trees {
+            let (prepared_tx, prepared_rx) = oneshot::channel();
+            let (committed_tx, committed_rx) = oneshot::channel();
+            let msg = ApplyBatch::new(tree_writes, tree_reads, prepared_tx, committed_rx);
+            applies.push(tokio::spawn(async move { address.async_ask(msg).await }));
+            commits.push(committed_tx);
+            // Returning early drops the senders, which releases the trees
+            match prepared_rx.await {
+                Ok(tree_items) => items.extend(tree_items?),
+                Err(_) => anyhow::bail!("Tree {} stopped before the batch was committed", name),
+            }
+        }
+
+        let result = wal.write_batch(items, durability).await;
+        for committed in commits {
+            let _ = committed.send(result.is_ok());
+        }
+        result?;
+        for apply in applies {
+            apply.await???;
+        }
+        Ok(())
+    }
+}
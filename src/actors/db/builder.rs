@@ -3,18 +3,20 @@ use std::marker::PhantomData;
 use tokactor::{util::builder::ActorAskRef, ActorRef};
 
 use crate::{
-    actors::tree::{PrimaryKey, RecordValue},
-    Tree,
+    actors::tree::{Constraints, PrimaryKey, RecordValue},
+    Constraint, Tree,
 };
 
 use super::{
     actor::DbActor,
     version::{UpgradeVersion, UpgradedVersion, VersionedTree, VersionedTreeUpgradeActor},
-    NewTreeRoot,
+    NewTreeRoot, OpenTree,
 };
 
 #[derive(Debug, Clone)]
 pub struct TreeVersion {
+    // TODO(Alec): Compare against the version saved in the database metadata
+    #[allow(dead_code)]
     version: VersionedTree,
     upgrader: Option<ActorAskRef<UpgradeVersion, UpgradedVersion>>,
 }
@@ -47,6 +49,7 @@ pub struct TreeBuilder<Key: PrimaryKey, Value: RecordValue> {
     name: String,
     versions: Vec<TreeVersion>,
     database: ActorRef<DbActor>,
+    constraints: Vec<Box<dyn Constraint<Value> + Send + Sync>>,
     _key: PhantomData<Key>,
     _value: PhantomData<Value>,
 }
@@ -58,11 +61,24 @@ impl<Key: PrimaryKey, Value: RecordValue> TreeBuilder<Key, Value> {
             name,
             versions: vec![TreeVersion::new(version)],
             database,
+            constraints: Vec::new(),
             _key: PhantomData,
             _value: PhantomData,
         })
     }
 
+    /// Check every value written to the tree against `constraint` before it is
+    /// written to the WAL. Writes that are rejected fail with a
+    /// [`crate::ConstraintViolation`]. Constraints are dropped by
+    /// [`TreeBuilder::migrate`], so add them after the last migration.
+    pub fn constraint(
+        mut self,
+        constraint: impl Constraint<Value> + Send + Sync + 'static,
+    ) -> Self {
+        self.constraints.push(Box::new(constraint));
+        self
+    }
+
     /// Migrate from the current version of the `Key` and `Value` and replace them
     /// with the values of `NewKey` and `NewValue`. This upgrade happens when we
     /// load in data that has an older version that what is currently recorded in
@@ -92,6 +108,7 @@ impl<Key: PrimaryKey, Value: RecordValue> TreeBuilder<Key, Value> {
             name: self.name.clone(),
             versions: self.versions,
             database: self.database,
+            constraints: Vec::new(),
             _key: PhantomData,
             _value: PhantomData,
         };
@@ -103,10 +120,14 @@ impl<Key: PrimaryKey, Value: RecordValue> TreeBuilder<Key, Value> {
     pub async fn unwrap(self) -> anyhow::Result<Tree<Key, Value>> {
         let address = self
             .database
-            .ask(NewTreeRoot::new(self.name, self.versions))
+            .ask(NewTreeRoot::new(self.name.clone(), self.versions))
             .await?;
+        self.database
+            .async_ask(OpenTree::new(self.name.clone()))
+            .await??;
 
-        let tree = Tree::new(address);
+        let constraints = Constraints::new(self.constraints);
+        let tree = Tree::new(self.name, address).with_constraints(constraints);
 
         Ok(tree)
     }
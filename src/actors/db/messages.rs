@@ -1,4 +1,9 @@
-use std::path::PathBuf;
+use tokactor::ActorRef;
+
+use crate::actors::{
+    fs::FileSystemFacade,
+    manifest::{Manifest, ManifestState},
+};
 
 use super::builder::TreeVersion;
 
@@ -17,14 +22,52 @@ impl NewTreeRoot {
     }
 }
 
+/// Give a tree that was created after the database was restored its storage,
+/// so that its writes are saved like the writes of every other tree
 #[derive(Debug)]
-pub struct RestoreDbPath {
-    pub directory: PathBuf,
+pub struct OpenTree {
+    pub name: String,
+}
+
+impl OpenTree {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+#[derive(Debug)]
+pub struct RestoreWal {
+    pub fs: FileSystemFacade,
+    pub manifest: ActorRef<Manifest>,
+    pub state: ManifestState,
+}
+
+impl RestoreWal {
+    pub fn new(fs: FileSystemFacade, manifest: ActorRef<Manifest>, state: ManifestState) -> Self {
+        Self {
+            fs,
+            manifest,
+            state,
+        }
+    }
+}
+
+/// Open the table files of every tree that are listed in the manifest
+#[derive(Debug, Clone)]
+pub struct RestoreStorage {
+    /// Directory that stores all of the table files
+    pub fs: FileSystemFacade,
+    pub manifest: ActorRef<Manifest>,
+    pub state: ManifestState,
 }
 
-impl RestoreDbPath {
-    pub fn new(directory: PathBuf) -> Self {
-        Self { directory }
+impl RestoreStorage {
+    pub fn new(fs: FileSystemFacade, manifest: ActorRef<Manifest>, state: ManifestState) -> Self {
+        Self {
+            fs,
+            manifest,
+            state,
+        }
     }
 }
 
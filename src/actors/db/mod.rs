@@ -1,43 +1,126 @@
 mod actor;
+mod batch;
 mod builder;
 mod messages;
+mod options;
+mod transaction;
 mod version;
 
-use std::path::Path;
+use std::{
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
+use futures::{future::BoxFuture, FutureExt};
 use tokactor::{Actor, ActorRef};
+use tokio::sync::watch;
 
 use actor::DbActor;
+pub use batch::WriteBatch;
 pub use builder::TreeVersion;
 pub use messages::*;
-pub use version::VersionedTree;
+pub use options::DatabaseOptions;
+pub use transaction::Transaction;
 
-use crate::Aggregate;
+use crate::{Aggregate, SecondaryIndex, TransactionConflict};
 
 use self::builder::TreeBuilder;
 
 use super::{
     fs::{FileSystem, FileSystemFacade},
-    subtree::{AggregateTree, AggregateTreeActor, IndexTreeActor, SubTree, UtilTreeAddress},
+    manifest::Manifest,
+    subtree::{
+        AggregateTree, AggregateTreeActor, BackfillProgress, BackfillReporter, IdentitiesFn,
+        IndexTreeActor, Many, Single, SortedIndex, SortedIndexActor, SubTree, UniqueIndex,
+        UniqueIndexActor, UtilTreeAddress,
+    },
+    table::{BlockCache, CacheStats},
     tree::{PrimaryKey, RecordValue, Tree},
+    wal::WalRestoredItems,
 };
 
 pub struct Database {
     inner: ActorRef<DbActor>,
     filesystem: FileSystemFacade,
+    cache: BlockCache,
+    transaction_retries: usize,
+    /// Backfills of sorted indexes that were created before the database was
+    /// restored, or `None` once it has been restored. Sorted indexes are only
+    /// kept in memory, so they are built from the restored records.
+    sorted_backfills: Mutex<Option<Vec<BoxFuture<'static, anyhow::Result<()>>>>>,
+    /// Progress of the last backfill that was started
+    backfill_progress: Arc<watch::Sender<Option<BackfillProgress>>>,
 }
 
 impl Database {
     pub async fn new(fs: FileSystem) -> anyhow::Result<Self> {
-        let database = DbActor::new().start();
+        Self::with_options(fs, DatabaseOptions::default()).await
+    }
+
+    pub async fn with_options(fs: FileSystem, options: DatabaseOptions) -> anyhow::Result<Self> {
+        let cache = BlockCache::new(options.block_cache_size);
+        let transaction_retries = options.transaction_retries;
+        let database = DbActor::new(options, cache.clone()).start();
         let filesystem = database.ask(fs).await?;
         let facade = FileSystemFacade::new(filesystem);
         Ok(Self {
             inner: database,
             filesystem: facade,
+            cache,
+            transaction_retries,
+            sorted_backfills: Mutex::new(Some(Vec::new())),
+            backfill_progress: Arc::new(watch::channel(None).0),
         })
     }
 
+    /// Watch the progress of the backfills that indexes and aggregates run when
+    /// they are created, to add the records that their tree already holds.
+    /// Holds `None` until the first backfill starts.
+    pub fn backfill_progress(&self) -> watch::Receiver<Option<BackfillProgress>> {
+        self.backfill_progress.subscribe()
+    }
+
+    fn backfill_reporter(&self, name: &str) -> BackfillReporter {
+        BackfillReporter::new(Arc::clone(&self.backfill_progress), name)
+    }
+
+    /// Hit and miss counters of the block cache that is shared by every tree
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Start a batch of writes that are committed to one or more trees together
+    pub fn batch(&self) -> WriteBatch {
+        WriteBatch::new(self.inner.clone())
+    }
+
+    /// Run `f` inside of a transaction and commit everything it wrote. If a
+    /// record that `f` read was changed by another writer before the commit,
+    /// nothing is written and `f` is run again. Returns the
+    /// [`crate::TransactionConflict`] once the transaction has been retried too
+    /// many times, or the error of `f` without writing anything.
+    pub async fn transaction<F, Fut, T>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(Transaction) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            let tx = Transaction::new(WriteBatch::tracked(self.inner.clone()));
+            let output = f(tx.clone()).await?;
+            match tx.commit().await {
+                Ok(()) => return Ok(output),
+                Err(err)
+                    if err.is::<TransactionConflict>() && retries < self.transaction_retries =>
+                {
+                    retries += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     pub fn create<Key, Value>(&self, name: impl ToString) -> anyhow::Result<TreeBuilder<Key, Value>>
     where
         Key: PrimaryKey,
@@ -46,6 +129,9 @@ impl Database {
         TreeBuilder::new(self.inner.clone(), name.to_string())
     }
 
+    /// Index records of a tree by an identity that they hold. Records that the
+    /// tree already holds are added before the index is returned, and writes
+    /// made in the meantime are neither lost nor added twice.
     pub async fn create_index<Key, Value, ID, F>(
         &self,
         name: impl ToString,
@@ -58,25 +144,146 @@ impl Database {
         ID: PrimaryKey,
         F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static,
     {
-        let tree = self.create::<ID, Vec<Key>>(name)?.unwrap().await?;
+        self.index(name, source_tree, Single(identity)).await
+    }
+
+    /// Index records by an identity that is computed from the value instead of
+    /// borrowed from it, like a lowercased email or a composite `(board,
+    /// completed)` key. Composite identities can be listed by their leading
+    /// components with [`SubTree::list_prefix`].
+    pub async fn create_computed_index<Key, Value, ID, F>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        identity: F,
+    ) -> anyhow::Result<SubTree<ID, Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+        F: Fn(&Value) -> Option<ID> + Send + Sync + 'static,
+    {
+        self.index(name, source_tree, Many(identity)).await
+    }
+
+    /// Index records that belong to more than one bucket, like tickets that
+    /// have many tags. The identities of a record are diffed when it's updated,
+    /// so it's only moved in and out of the buckets that changed.
+    pub async fn create_multi_index<Key, Value, ID, F, I>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        identities: F,
+    ) -> anyhow::Result<SubTree<ID, Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+        F: Fn(&Value) -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = ID>,
+    {
+        self.index(name, source_tree, Many(identities)).await
+    }
+
+    async fn index<Key, Value, ID>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        identity: impl IdentitiesFn<ID, Value> + 'static,
+    ) -> anyhow::Result<SubTree<ID, Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+    {
+        let name = name.to_string();
+        let tree = self.create::<ID, Vec<Key>>(&name)?.unwrap().await?;
         let source_tree_clone = source_tree.duplicate();
         let tree = IndexTreeActor::new(tree, source_tree_clone, identity);
         let UtilTreeAddress {
             subscriber,
+            backfill,
             tree,
             restorer,
         } = self.inner.ask(tree).await?;
 
         source_tree.register_restorer(restorer).await;
-        source_tree.register_subscriber(subscriber);
+        let progress = self.backfill_reporter(&name);
+        backfill.backfill(source_tree, subscriber, progress).await?;
         Ok(tree)
     }
 
+    /// Find records of a tree by an identity that at most one record can have.
+    /// A write that would give a second record the same identity fails with a
+    /// [`crate::UniqueViolation`] before it is written. Records that the tree
+    /// already holds are added before the index is returned, and creating the
+    /// index fails with a [`crate::UniqueViolation`] if two of them have the
+    /// same identity.
+    pub async fn create_unique_index<Key, Value, ID, F>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        identity: F,
+    ) -> anyhow::Result<UniqueIndex<ID, Key, Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+        F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static,
+    {
+        let name = name.to_string();
+        let tree = self.create::<ID, Option<Key>>(&name)?.unwrap().await?;
+        let progress = self.backfill_reporter(&name);
+        let actor = UniqueIndexActor::new(name, tree, source_tree.duplicate(), identity);
+        let (subscriber, backfill, guard, index) = self.inner.ask(actor).await?;
+        backfill.backfill(source_tree, subscriber, progress).await?;
+        // Claims are checked against the owners of identities, so writes can
+        // only claim them once the index knows every owner
+        source_tree.register_guard(guard).await;
+        Ok(index)
+    }
+
+    /// Keep the records of a tree sorted by the comparator of `index`. Unlike
+    /// other indexes the order is only kept in memory. It is built from the
+    /// records of the tree before the index is returned, or by
+    /// [`Database::restore`] if the database hasn't been restored yet.
+    pub async fn create_sorted_index<Key, Value, I>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        index: I,
+    ) -> anyhow::Result<SortedIndex<Key, Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+        I: SecondaryIndex<Value> + Send + Sync + 'static,
+    {
+        let name = name.to_string();
+        let progress = self.backfill_reporter(&name);
+        let actor = SortedIndexActor::new(name, source_tree.duplicate(), index);
+        let (subscriber, backfill, index) = self.inner.ask(actor).await?;
+        let source_tree = source_tree.duplicate();
+        let build =
+            async move { backfill.backfill(&source_tree, subscriber, progress).await }.boxed();
+        let build = match self.sorted_backfills.lock().unwrap().as_mut() {
+            Some(backfills) => {
+                backfills.push(build);
+                return Ok(index);
+            }
+            None => build,
+        };
+        build.await?;
+        Ok(index)
+    }
+
+    /// Aggregate records of a tree by an identity that they hold. Like indexes,
+    /// the aggregate has seen every record the tree already holds before it is
+    /// returned.
     pub async fn create_aggregate<Record, Key, Value, ID, F>(
         &self,
         name: impl ToString,
         source_tree: &Tree<Key, Value>,
-        _: Record,
+        record: Record,
         identity: F,
     ) -> anyhow::Result<AggregateTree<ID, Record>>
     where
@@ -86,41 +293,119 @@ impl Database {
         ID: PrimaryKey,
         F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static,
     {
+        self.aggregate(name, source_tree, record, Single(identity))
+            .await
+    }
+
+    /// Aggregate records by an identity that is computed from the value.
+    /// Composite identities can be read by their leading components with
+    /// [`AggregateTree::get_prefix`].
+    pub async fn create_computed_aggregate<Record, Key, Value, ID, F>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        record: Record,
+        identity: F,
+    ) -> anyhow::Result<AggregateTree<ID, Record>>
+    where
+        Record: Aggregate<Key, Value> + RecordValue + Default,
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+        F: Fn(&Value) -> Option<ID> + Send + Sync + 'static,
+    {
+        self.aggregate(name, source_tree, record, Many(identity))
+            .await
+    }
+
+    /// Aggregate records that belong to more than one bucket. Every bucket of
+    /// a record observes it, and a bucket that a record is updated in or out of
+    /// observes it as created or deleted.
+    pub async fn create_multi_aggregate<Record, Key, Value, ID, F, I>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        record: Record,
+        identities: F,
+    ) -> anyhow::Result<AggregateTree<ID, Record>>
+    where
+        Record: Aggregate<Key, Value> + RecordValue + Default,
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+        F: Fn(&Value) -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = ID>,
+    {
+        self.aggregate(name, source_tree, record, Many(identities))
+            .await
+    }
+
+    async fn aggregate<Record, Key, Value, ID>(
+        &self,
+        name: impl ToString,
+        source_tree: &Tree<Key, Value>,
+        _: Record,
+        identity: impl IdentitiesFn<ID, Value> + 'static,
+    ) -> anyhow::Result<AggregateTree<ID, Record>>
+    where
+        Record: Aggregate<Key, Value> + RecordValue + Default,
+        Key: PrimaryKey,
+        Value: RecordValue,
+        ID: PrimaryKey,
+    {
+        let name = name.to_string();
         let tree = self
-            .create::<ID, (Record, Vec<Key>)>(name)?
+            .create::<ID, (Record, Vec<Key>)>(&name)?
             .unwrap()
             .await?;
         let source_tree_clone = source_tree.duplicate();
         let tree = AggregateTreeActor::new(tree, source_tree_clone, identity);
         let UtilTreeAddress {
             subscriber,
+            backfill,
             tree,
             restorer,
         } = self.inner.ask(tree).await?;
 
         source_tree.register_restorer(restorer).await;
-        source_tree.register_subscriber(subscriber);
+        let progress = self.backfill_reporter(&name);
+        backfill.backfill(source_tree, subscriber, progress).await?;
         Ok(tree)
     }
 
+    /// Restore the database from the filesystem. All trees, indexes and
+    /// aggregates should be created before calling restore so that every item
+    /// stored in the WAL can be routed to the tree that it belongs to. Writes
+    /// are only saved to disk once the restore has completed.
     pub async fn restore(&self) -> anyhow::Result<()> {
         self.filesystem.open_base_dir().await?;
         // We need to validate the system is setup correctly
         self.filesystem.validate_or_create_dir("manifest").await?;
         self.filesystem.validate_or_create_dir("storage").await?;
+        self.filesystem.validate_or_create_dir("wal").await?;
+
+        let manifest = Manifest::recover(self.filesystem.rebase("manifest")).await?;
+        let state = manifest.state().clone();
+        let manifest = self.inner.ask(manifest).await?;
 
-        let manifest_fs = self.filesystem.rebase("manifest");
+        let storage = self.filesystem.rebase("storage");
+        let restore = RestoreStorage::new(storage, manifest.clone(), state.clone());
+        self.inner.async_ask(restore).await??;
 
-        // let system = ManifestSystem::restore(self.filesystem.clone())?;
-        // let wal = Wal::restore(system.clone(), self.filesystem.clone())?;
+        let restore = RestoreWal::new(self.filesystem.rebase("wal"), manifest, state);
+        let WalRestoredItems { items, discarded } = self.inner.async_ask(restore).await??;
+        if discarded > 0 {
+            println!("Recovered WAL after discarding {discarded} bytes of torn writes");
+        }
+        for item in items {
+            self.inner.async_ask(item).await??;
+        }
+        self.inner.async_ask(RestoreComplete).await??;
 
-        // let WalRestoredItems { items } = self.inner.async_ask(RestoreDbPath::new(path)).await?;
-        // for item in items {
-        //     println!("Restoring    ->    {}", item);
-        //     let result = self.inner.async_ask(item).await?;
-        //     result?;
-        // }
-        // self.inner.async_ask(RestoreComplete).await?
+        let backfills = self.sorted_backfills.lock().unwrap().take();
+        for backfill in backfills.unwrap_or_default() {
+            backfill.await?;
+        }
         Ok(())
     }
 
@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use crate::actors::{
+    compaction::CompactionOptions,
+    table::TableOptions,
+    wal::{Durability, WalOptions},
+};
+
+/// Settings used to tune how the database stores its data
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    pub(crate) wal: WalOptions,
+    pub(crate) memtable_size: usize,
+    pub(crate) compaction: CompactionOptions,
+    pub(crate) table: TableOptions,
+    pub(crate) block_cache_size: usize,
+    pub(crate) transaction_retries: usize,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            wal: WalOptions::default(),
+            memtable_size: 4 * 1024 * 1024,
+            compaction: CompactionOptions::default(),
+            table: TableOptions::default(),
+            block_cache_size: 8 * 1024 * 1024,
+            transaction_retries: 10,
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bytes of keys and values a tree keeps in memory before they are
+    /// written to a table file in storage
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Number of bits of the bloom filter of a table file that are used for
+    /// every key. More bits rule out more lookups of keys that aren't in the
+    /// table, at the cost of memory. 10 bits per key rule out about 99% of
+    /// them, and 0 turns bloom filters off.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.table.bloom_bits_per_key = bits;
+        self
+    }
+
+    /// Number of bytes of table file blocks that are kept in memory after they
+    /// are read. The cache is shared by every tree of the database, and a size
+    /// of 0 turns it off.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// Number of times a transaction is run again after it conflicts with
+    /// another writer, before the conflict is returned
+    pub fn transaction_retries(mut self, retries: usize) -> Self {
+        self.transaction_retries = retries;
+        self
+    }
+
+    /// Durability used by every write that doesn't ask for its own. See
+    /// [`crate::Tree::with_durability`] to change it for a single write.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.wal.durability = durability;
+        self
+    }
+
+    /// Longest amount of time writes are buffered before they are committed to
+    /// the WAL together
+    pub fn flush_buffer_sync(mut self, duration: Duration) -> Self {
+        self.wal.flush_buffer_sync = duration;
+        self
+    }
+
+    /// Commit buffered writes to the WAL as soon as they take up this many
+    /// bytes, even if `flush_buffer_sync` hasn't passed yet
+    pub fn group_commit_bytes(mut self, bytes: usize) -> Self {
+        self.wal.group_commit_bytes = bytes;
+        self
+    }
+
+    /// Commit buffered writes to the WAL as soon as there are this many of them,
+    /// even if `flush_buffer_sync` hasn't passed yet
+    pub fn group_commit_records(mut self, records: usize) -> Self {
+        self.wal.group_commit_records = records;
+        self
+    }
+
+    /// Number of bytes a WAL segment can grow to before a new segment is
+    /// started. Segments are only deleted once every write inside of them has
+    /// been persisted, so smaller segments free disk space sooner.
+    pub fn wal_segment_size(mut self, bytes: u64) -> Self {
+        self.wal.segment_size = bytes;
+        self
+    }
+
+    /// Number of times in a row the WAL can fail to write to disk before the
+    /// database becomes read-only. Writes made while the database is read-only
+    /// return an error instead of being lost.
+    pub fn max_wal_write_failures(mut self, failures: usize) -> Self {
+        self.wal.max_write_failures = failures;
+        self
+    }
+
+    /// Number of table files that can be flushed from the memtable before they
+    /// are compacted together. Every table file in level 0 may need to be read
+    /// to find a key, so fewer files make reads faster at the cost of more
+    /// compactions.
+    pub fn l0_compaction_trigger(mut self, tables: usize) -> Self {
+        self.compaction.l0_compaction_trigger = tables;
+        self
+    }
+
+    /// Number of bytes level 1 can hold before it is compacted into level 2.
+    /// Every level after it can hold 10 times more then the level before it.
+    pub fn level_size_base(mut self, bytes: u64) -> Self {
+        self.compaction.level_size_base = bytes;
+        self
+    }
+
+    /// Number of bytes a table file written by a compaction can grow to before
+    /// a new one is started
+    pub fn table_file_size(mut self, bytes: u64) -> Self {
+        self.compaction.table_file_size = bytes;
+        self
+    }
+
+    /// Number of bytes per second compactions can write, so that they don't
+    /// starve reads and writes of disk bandwidth. A rate of 0 is unlimited.
+    pub fn compaction_rate(mut self, bytes_per_second: u64) -> Self {
+        self.compaction.rate = bytes_per_second;
+        self
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::{
+    actors::tree::{PrimaryKey, RecordValue},
+    AutoIncrement, Tree,
+};
+
+use super::WriteBatch;
+
+/// Reads and writes to one or more trees that are committed together. Every
+/// record that is read, or written, is remembered along with the value it had
+/// and the last write the tree made to it. Writes are buffered until the
+/// transaction commits, and the commit fails with a
+/// [`crate::TransactionConflict`] if any of those records were written by
+/// another writer in the meantime, even if they were written back to the value
+/// the transaction read.
+///
+/// Created by [`crate::Database::transaction`], which commits the transaction
+/// and runs it again when there is a conflict.
+#[derive(Clone)]
+pub struct Transaction {
+    /// Taken once the transaction is committed
+    batch: Arc<Mutex<Option<WriteBatch>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(batch: WriteBatch) -> Self {
+        Self {
+            batch: Arc::new(Mutex::new(Some(batch))),
+        }
+    }
+
+    /// Get a record, including the changes this transaction has made to it
+    pub async fn get<Key, Value>(
+        &self,
+        tree: &Tree<Key, Value>,
+        key: impl Into<Key>,
+    ) -> anyhow::Result<Option<Value>>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let mut batch = self.batch.lock().await;
+        Self::active(&mut batch)?.get(tree, &key.into()).await
+    }
+
+    /// Insert a value into a tree once the transaction commits. The key is
+    /// reserved right away.
+    pub async fn insert<Key, Value>(
+        &self,
+        tree: &Tree<Key, Value>,
+        value: Value,
+    ) -> anyhow::Result<Key>
+    where
+        Key: PrimaryKey + AutoIncrement,
+        Value: RecordValue,
+    {
+        let mut batch = self.batch.lock().await;
+        Self::active(&mut batch)?.insert(tree, value).await
+    }
+
+    /// Replace the value of a key once the transaction commits
+    pub async fn update<Key, Value>(
+        &self,
+        tree: &Tree<Key, Value>,
+        key: impl Into<Key>,
+        value: Value,
+    ) -> anyhow::Result<()>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let mut batch = self.batch.lock().await;
+        Self::active(&mut batch)?.update(tree, key, value).await
+    }
+
+    /// Delete a key once the transaction commits
+    pub async fn delete<Key, Value>(
+        &self,
+        tree: &Tree<Key, Value>,
+        key: impl Into<Key>,
+    ) -> anyhow::Result<()>
+    where
+        Key: PrimaryKey,
+        Value: RecordValue,
+    {
+        let mut batch = self.batch.lock().await;
+        Self::active(&mut batch)?.delete(tree, key).await
+    }
+
+    pub(crate) async fn commit(&self) -> anyhow::Result<()> {
+        let batch = self.batch.lock().await.take();
+        match batch {
+            Some(batch) => batch.commit().await,
+            None => anyhow::bail!("Transaction has already been committed"),
+        }
+    }
+
+    fn active(batch: &mut Option<WriteBatch>) -> anyhow::Result<&mut WriteBatch> {
+        match batch.as_mut() {
+            Some(batch) => Ok(batch),
+            None => anyhow::bail!("Transaction has already been committed"),
+        }
+    }
+}
@@ -6,7 +6,10 @@ use tokactor::{
     Actor, Ask, Ctx, DeadActorResult, Handler,
 };
 
-use crate::actors::tree::{PrimaryKey, RecordValue};
+use crate::{
+    actors::tree::{PrimaryKey, RecordValue},
+    codec,
+};
 
 /// Store a particular version of a tree. This value is saved to the database
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -152,13 +155,13 @@ where
 
     fn handle(&mut self, upgrade: UpgradeVersion, _: &mut Ctx<Self>) -> Self::Result {
         let past_value: Pv = serde_json::from_slice(&upgrade.past_value).unwrap();
-        let past_key: Pk = bincode::deserialize(&upgrade.past_key).unwrap();
+        let past_key: Pk = codec::from_slice(&upgrade.past_key).unwrap();
 
         let current_value = Cv::from(past_value);
         let current_key = Ck::from(past_key);
 
         let current_value_vec = serde_json::to_vec(&current_value).unwrap();
-        let current_key_vec = bincode::serialize(&current_key).unwrap();
+        let current_key_vec = codec::to_vec(&current_key).unwrap();
 
         UpgradedVersion::new(current_key_vec, current_value_vec)
     }
@@ -1,15 +1,15 @@
-use std::{io::Read, path::PathBuf};
+use std::path::PathBuf;
 
 use tokactor::ActorRef;
 
 use crate::FileSystem;
 
 use super::{
-    messages::{OpenBaseDir, OpenFileOptions, ValidateOrCreateDir},
+    messages::{OpenBaseDir, OpenFileOptions, RemoveFile, ValidateOrCreateDir},
     DbFile,
 };
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct FileSystemFacade {
     rebase: Option<PathBuf>,
     inner: ActorRef<FileSystem>,
@@ -36,8 +36,7 @@ impl FileSystemFacade {
     }
 
     pub async fn read_file(&self, path: impl Into<PathBuf>) -> anyhow::Result<DbFile> {
-        let path = path.into();
-        let path = Self::do_rebase(self.rebase.as_ref(), path);
+        // Opening the file rebases the path for us
         self.open(OpenFileOptions::new(path).read()).await
     }
 
@@ -46,6 +45,12 @@ impl FileSystemFacade {
         self.inner.ask(options).await?
     }
 
+    pub async fn remove_file(&self, path: impl Into<PathBuf>) -> anyhow::Result<()> {
+        let path = path.into();
+        let path = Self::do_rebase(self.rebase.as_ref(), path);
+        self.inner.ask(RemoveFile(path)).await?
+    }
+
     pub fn rebase(&self, path: impl Into<PathBuf>) -> FileSystemFacade {
         let path = path.into();
         let rebase = Self::do_rebase(self.rebase.as_ref(), path);
@@ -55,7 +60,10 @@ impl FileSystemFacade {
         }
     }
 
+    #[cfg(test)]
     pub(crate) async fn read_full_file(&self, path: impl Into<PathBuf>) -> anyhow::Result<String> {
+        use std::io::Read;
+
         let mut str = String::new();
         let mut file = self.read_file(path).await?;
         file.read_to_string(&mut str)?;
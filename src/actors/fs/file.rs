@@ -22,6 +22,10 @@ impl FNode {
         self.inner.write().unwrap().truncate();
     }
 
+    pub fn set_len(&self, len: usize) {
+        self.inner.write().unwrap().set_len(len);
+    }
+
     pub fn end_of_file_pointer(&self) -> usize {
         self.inner.write().unwrap().contents.len()
     }
@@ -80,6 +84,12 @@ impl Disk {
         }
     }
 
+    fn set_len(&mut self, len: usize) {
+        let mut p = 0;
+        let _ = self.as_writer(&mut p).flush();
+        self.contents.resize(len, 0);
+    }
+
     fn truncate(&mut self) {
         self.contents.clear();
         self.buffer = [0_u8; MAX_BUFFER_SIZE];
@@ -46,6 +46,20 @@ impl InMemoryFs {
         Ok(())
     }
 
+    pub fn remove_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        match self.file_system.get(path.as_ref()) {
+            Some(Ident::File(_)) => {
+                self.file_system.remove(path.as_ref());
+                Ok(())
+            }
+            Some(Ident::Dir()) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Path is a directory and not a file",
+            )),
+            None => Err(io::Error::new(ErrorKind::NotFound, "File does not exist")),
+        }
+    }
+
     pub fn open_file(&mut self, options: OpenFileOptions) -> io::Result<DbFile> {
         if !options.write && options.truncate {
             Err(io::Error::new(
@@ -6,6 +6,9 @@ pub struct OpenBaseDir;
 #[derive(Debug)]
 pub struct ValidateOrCreateDir(pub PathBuf);
 
+#[derive(Debug)]
+pub struct RemoveFile(pub PathBuf);
+
 #[derive(Debug, Clone)]
 pub struct OpenFileOptions {
     pub read: bool,
@@ -4,7 +4,7 @@ use tokactor::{Actor, Ask, Ctx, Scheduler};
 
 use self::{
     memory::InMemoryFs,
-    messages::{OpenBaseDir, ValidateOrCreateDir},
+    messages::{OpenBaseDir, RemoveFile, ValidateOrCreateDir},
     system::FsSystem,
 };
 
@@ -75,6 +75,14 @@ impl FileSystem {
         Ok(())
     }
 
+    fn remove_file(&mut self, path: impl Into<PathBuf>) -> std::io::Result<()> {
+        let absolute_path = self.base_path.join(path.into());
+        match &mut self.filesystem {
+            FileSystemImpl::Memory(memory) => memory.remove_file(absolute_path),
+            FileSystemImpl::System(system) => system.remove_file(absolute_path),
+        }
+    }
+
     fn open_file(&mut self, mut options: OpenFileOptions) -> anyhow::Result<DbFile> {
         options.path = self.base_path.join(options.path);
         let output = match &mut self.filesystem {
@@ -114,6 +122,19 @@ impl Ask<ValidateOrCreateDir> for FileSystem {
     }
 }
 
+impl Ask<RemoveFile> for FileSystem {
+    type Result = anyhow::Result<()>;
+
+    fn handle(&mut self, RemoveFile(path): RemoveFile, _: &mut Ctx<Self>) -> Self::Result {
+        self.remove_file(path)?;
+        Ok(())
+    }
+
+    fn scheduler() -> Scheduler {
+        Scheduler::Blocking
+    }
+}
+
 impl Ask<OpenFileOptions> for FileSystem {
     type Result = anyhow::Result<DbFile>;
 
@@ -390,6 +411,28 @@ mod tests {
         assert_eq!(buf_read, buf_write);
     }
 
+    #[test]
+    fn succeed_remove_file_in_memory() {
+        let mem = FileSystem::in_memory(());
+        succeed_remove_file(mem);
+    }
+
+    #[test]
+    fn succeed_remove_file_in_system() {
+        let mem = FileSystem::system(temp_dir().join("ex13"));
+        succeed_remove_file(mem);
+    }
+
+    fn succeed_remove_file(mut mem: FileSystem) {
+        assert!(mem.open_base_dir().is_ok());
+        assert!(mem
+            .open_file(OpenFileOptions::new("file").create().write())
+            .is_ok());
+        assert!(mem.remove_file("file").is_ok());
+        assert!(mem.open_file(OpenFileOptions::new("file").read()).is_err());
+        assert!(mem.remove_file("file").is_err());
+    }
+
     #[test]
     fn succeed_create_dir_if_not_exist_in_memory() {
         let mem = FileSystem::in_memory(());
@@ -1,6 +1,6 @@
 use std::{
     fs,
-    io::{ErrorKind, Read, Write},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
 };
 
 use tokactor::Actor;
@@ -33,6 +33,52 @@ impl DbFile {
     pub fn system(file: fs::File) -> Self {
         Self::System(file)
     }
+
+    /// Truncate or extend the file so that it is `len` bytes long. The file
+    /// pointer is moved to the end of the file so that new writes are appended.
+    pub fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            DbFile::Memory {
+                file,
+                pointer,
+                read: _,
+                write,
+            } => {
+                if !*write {
+                    return Err(std::io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Write permissions not given when openning file",
+                    ));
+                }
+                file.set_len(len as usize);
+                *pointer = len as usize;
+                Ok(())
+            }
+            DbFile::System(file) => {
+                file.set_len(len)?;
+                file.seek(SeekFrom::Start(len))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Make sure all of the data written to the file has reached the disk.
+    /// Metadata, such as the modification time, might not be synced.
+    pub fn sync_data(&mut self) -> std::io::Result<()> {
+        match self {
+            // Memory files are on "disk" once they have been flushed
+            DbFile::Memory { .. } => self.flush(),
+            DbFile::System(file) => file.sync_data(),
+        }
+    }
+
+    /// Make sure all of the data and metadata of the file has reached the disk
+    pub fn sync_all(&mut self) -> std::io::Result<()> {
+        match self {
+            DbFile::Memory { .. } => self.flush(),
+            DbFile::System(file) => file.sync_all(),
+        }
+    }
 }
 
 impl Write for DbFile {
@@ -99,7 +145,7 @@ impl Read for DbFile {
                 }
                 let lock = file.inner.read().unwrap();
                 let reader = lock.as_reader()?;
-                let remaining = &reader[*pointer..];
+                let remaining = reader.get(*pointer..).unwrap_or(&[]);
                 let read = if remaining.is_empty() {
                     0
                 } else if remaining.len() > buf.len() {
@@ -116,3 +162,27 @@ impl Read for DbFile {
         }
     }
 }
+
+impl Seek for DbFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            DbFile::Memory { file, pointer, .. } => {
+                let len = file.end_of_file_pointer() as i64;
+                let next = match pos {
+                    SeekFrom::Start(offset) => offset as i64,
+                    SeekFrom::End(offset) => len + offset,
+                    SeekFrom::Current(offset) => *pointer as i64 + offset,
+                };
+                if next < 0 {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "Can't seek before the start of the file",
+                    ));
+                }
+                *pointer = next as usize;
+                Ok(next as u64)
+            }
+            DbFile::System(file) => file.seek(pos),
+        }
+    }
+}
@@ -23,10 +23,7 @@ impl FsSystem {
 
     pub fn create_dir(&self, path: PathBuf) -> io::Result<()> {
         if path.is_file() {
-            Err(std::io::Error::new(
-                io::ErrorKind::Other,
-                "Directory is already a file",
-            ))
+            Err(std::io::Error::other("Directory is already a file"))
         } else {
             let output = std::fs::create_dir(path);
             println!("{:?}", output);
@@ -34,6 +31,10 @@ impl FsSystem {
         }
     }
 
+    pub fn remove_file(&self, path: PathBuf) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
     pub fn open_file(&self, options: OpenFileOptions) -> io::Result<DbFile> {
         let mut opt = std::fs::OpenOptions::new();
         opt.read(options.read);
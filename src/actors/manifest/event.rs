@@ -0,0 +1,95 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::actors::wal::WalPosition;
+
+/// Changes to the layout of the database that are recorded in the manifest log.
+/// Replaying every event in order rebuilds the [`ManifestState`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ManifestEvent {
+    /// A new WAL segment was created and writes are now appended to it
+    WalSegmentCreated { id: u64 },
+    /// A WAL segment was deleted because all of its writes were persisted
+    WalSegmentDeleted { id: u64 },
+    /// Every write to `table` that ends at or before `position` in the WAL has
+    /// been persisted outside of the WAL
+    Checkpoint {
+        table: String,
+        position: WalPosition,
+    },
+    /// The memtable of `tree` was written to the table file `id` in level 0
+    TableCreated { tree: String, id: u64 },
+    /// The `inputs` tables of `tree` were merged into the `outputs` tables,
+    /// which are added to the end of `level`
+    TablesCompacted {
+        tree: String,
+        level: usize,
+        inputs: Vec<u64>,
+        outputs: Vec<u64>,
+    },
+    /// Every auto-increment key of `tree` up to and including `end` may have
+    /// been handed out, and must never be handed out again
+    KeysReserved { tree: String, end: Vec<u8> },
+}
+
+/// The layout of the database after replaying all of the manifest events
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ManifestState {
+    pub wal_segments: BTreeSet<u64>,
+    pub checkpoints: HashMap<String, WalPosition>,
+    /// Table files of every tree split into levels. Level 0 is ordered from
+    /// oldest to newest, every other level is ordered by key.
+    pub tables: HashMap<String, Vec<Vec<u64>>>,
+    /// Smallest id that hasn't been used by a table file yet
+    pub next_table_id: u64,
+    /// Last auto-increment key reserved by every tree, encoded with the codec
+    pub sequences: HashMap<String, Vec<u8>>,
+}
+
+impl ManifestState {
+    pub fn apply(&mut self, event: &ManifestEvent) {
+        match event {
+            ManifestEvent::WalSegmentCreated { id } => {
+                self.wal_segments.insert(*id);
+            }
+            ManifestEvent::WalSegmentDeleted { id } => {
+                self.wal_segments.remove(id);
+            }
+            ManifestEvent::Checkpoint { table, position } => {
+                let checkpoint = self.checkpoints.entry(table.clone()).or_default();
+                *checkpoint = (*position).max(*checkpoint);
+            }
+            ManifestEvent::TableCreated { tree, id } => {
+                self.level(tree, 0).push(*id);
+                self.next_table_id = self.next_table_id.max(id + 1);
+            }
+            ManifestEvent::TablesCompacted {
+                tree,
+                level,
+                inputs,
+                outputs,
+            } => {
+                for tables in self.tables.entry(tree.clone()).or_default() {
+                    tables.retain(|id| !inputs.contains(id));
+                }
+                self.level(tree, *level).extend(outputs);
+                if let Some(max) = outputs.iter().max() {
+                    self.next_table_id = self.next_table_id.max(max + 1);
+                }
+            }
+            ManifestEvent::KeysReserved { tree, end } => {
+                let sequence = self.sequences.entry(tree.clone()).or_default();
+                if end > sequence {
+                    *sequence = end.clone();
+                }
+            }
+        }
+    }
+
+    fn level(&mut self, tree: &str, level: usize) -> &mut Vec<u64> {
+        let levels = self.tables.entry(tree.to_string()).or_default();
+        if levels.len() <= level {
+            levels.resize(level + 1, Vec::new());
+        }
+        &mut levels[level]
+    }
+}
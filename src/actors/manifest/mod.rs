@@ -1,3 +1,5 @@
+mod event;
+
 use std::{
     cmp::Ordering,
     fmt,
@@ -5,7 +7,16 @@ use std::{
     path::PathBuf,
 };
 
-use super::fs::{DbFile, FileSystemFacade, OpenFileOptions};
+use tokactor::{Actor, Ask, Ctx, Scheduler};
+
+use super::{
+    fs::{DbFile, FileSystemFacade, OpenFileOptions},
+    wal::frame::{self, Magic},
+};
+
+pub use event::{ManifestEvent, ManifestState};
+
+const MAGIC: Magic = *b"TKDB-MAN";
 
 fn validate_manifest_file_name(name: &str) -> anyhow::Result<u64> {
     if !name.starts_with("MANIFEST-") {
@@ -101,6 +112,7 @@ impl ManifestFileMap {
     /// following is true:
     /// 1. No more then 1 log exists in the system
     /// 2. If 1 log exists, it is empty
+    ///
     /// Returns ok, if no events have been logged in the system
     pub async fn no_log_events_exist(&self, fs: &FileSystemFacade) -> anyhow::Result<()> {
         if self.logs.len() > 1 {
@@ -181,11 +193,7 @@ impl Ord for Current {
 
 impl PartialOrd for Current {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.pointer.partial_cmp(&other.pointer) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.sequence_num.partial_cmp(&other.sequence_num)
+        Some(self.cmp(other))
     }
 }
 
@@ -195,10 +203,24 @@ impl fmt::Display for Current {
     }
 }
 
+/// The manifest records every change to the layout of the database, such as
+/// which WAL segments exist, to an event log. Replaying the log on recovery
+/// rebuilds the layout the database had before it was shut down.
+#[derive(Debug)]
 pub struct Manifest {
     log: DbFile,
+    has_header: bool,
+    state: ManifestState,
+    /// Table ids that have been handed out, but might not be recorded yet
+    reserved_table_ids: u64,
 }
 
+/// Reserve an id for a new table file
+#[derive(Debug)]
+pub struct ReserveTableId;
+
+impl Actor for Manifest {}
+
 impl Manifest {
     pub async fn recover(fs: FileSystemFacade) -> anyhow::Result<Self> {
         let mut manifest = ManifestFileMap::recover(&fs, "MANIFEST", "CURRENT").await?;
@@ -212,15 +234,73 @@ impl Manifest {
 
         manifest.points_at_latest(&current)?;
 
-        // TODO: We need a class to encapsulate writing to an event log
-        let log = fs
-            .open(OpenFileOptions::new(current.pointer).write())
+        // TODO: Once the log grows too large we should write a snapshot of the
+        //       state to a new log file and point current at it.
+        let mut log = fs
+            .open(OpenFileOptions::new(current.pointer).read().write())
             .await?;
 
-        // TODO: Read all log files and capture all of the events
-        //       validate that all events are in correct order
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let frames = frame::decode(&MAGIC, &bytes)?;
+
+        let mut state = ManifestState::default();
+        for frame in &frames.frames {
+            let event: ManifestEvent = bincode::deserialize(frame.payload)?;
+            state.apply(&event);
+        }
+        if frames.discarded(&bytes) > 0 {
+            log.set_len(frames.valid_len as u64)?;
+        }
+
+        Ok(Self {
+            log,
+            has_header: frames.valid_len > 0,
+            reserved_table_ids: state.next_table_id,
+            state,
+        })
+    }
+
+    pub fn state(&self) -> &ManifestState {
+        &self.state
+    }
+
+    /// Append an event to the manifest log. The event is only applied to the
+    /// state of the manifest after it has been written.
+    pub fn record(&mut self, event: ManifestEvent) -> anyhow::Result<()> {
+        let mut bytes = Vec::new();
+        if !self.has_header {
+            bytes.extend_from_slice(&frame::header(&MAGIC));
+        }
+        frame::encode(&bincode::serialize(&event)?, &mut bytes);
+        self.log.write_all(&bytes)?;
+        // The layout of the database must survive a power loss
+        self.log.sync_data()?;
+        self.has_header = true;
+        self.state.apply(&event);
+        Ok(())
+    }
+}
+
+impl Ask<ManifestEvent> for Manifest {
+    type Result = anyhow::Result<()>;
+
+    fn handle(&mut self, event: ManifestEvent, _: &mut Ctx<Self>) -> Self::Result {
+        self.record(event)
+    }
+
+    fn scheduler() -> Scheduler {
+        Scheduler::Blocking
+    }
+}
+
+impl Ask<ReserveTableId> for Manifest {
+    type Result = u64;
 
-        Ok(Self { log })
+    fn handle(&mut self, _: ReserveTableId, _: &mut Ctx<Self>) -> Self::Result {
+        let id = self.reserved_table_ids.max(self.state.next_table_id);
+        self.reserved_table_ids = id + 1;
+        id
     }
 }
 
@@ -230,8 +310,9 @@ mod tests {
 
     use crate::{
         actors::{
-            fs::{FileSystemFacade, OpenFileOptions},
-            manifest::{Current, Manifest, ManifestFileMap},
+            fs::FileSystemFacade,
+            manifest::{Current, Manifest, ManifestEvent, ManifestFileMap},
+            wal::WalPosition,
         },
         FileSystem,
     };
@@ -405,4 +486,82 @@ mod tests {
         assert_eq!(fs.read_full_file("MANIFEST-0").await.unwrap(), map[2].1);
         assert_eq!(fs.read_full_file("MANIFEST-1").await.unwrap(), map[3].1);
     }
+
+    #[tokio::test]
+    async fn recover_manifest_events() {
+        let fs = init_fs(&[] as &[(&str, &str)]).await;
+        let mut manifest = Manifest::recover(fs.clone()).await.unwrap();
+        let position = WalPosition::new(1, 42);
+        let events = [
+            ManifestEvent::WalSegmentCreated { id: 0 },
+            ManifestEvent::WalSegmentCreated { id: 1 },
+            ManifestEvent::Checkpoint {
+                table: "table".to_string(),
+                position,
+            },
+            ManifestEvent::WalSegmentDeleted { id: 0 },
+        ];
+        for event in events {
+            manifest.record(event).unwrap();
+        }
+        drop(manifest);
+
+        let manifest = Manifest::recover(fs).await.unwrap();
+        let state = manifest.state();
+        assert_eq!(state.wal_segments.iter().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(state.checkpoints.get("table"), Some(&position));
+    }
+
+    #[tokio::test]
+    async fn recover_compacted_tables() {
+        let fs = init_fs(&[] as &[(&str, &str)]).await;
+        let mut manifest = Manifest::recover(fs.clone()).await.unwrap();
+        let tree = || "tree".to_string();
+        let events = [
+            ManifestEvent::TableCreated {
+                tree: tree(),
+                id: 0,
+            },
+            ManifestEvent::TableCreated {
+                tree: tree(),
+                id: 1,
+            },
+            ManifestEvent::TablesCompacted {
+                tree: tree(),
+                level: 1,
+                inputs: vec![1, 0],
+                outputs: vec![2, 3],
+            },
+            ManifestEvent::TableCreated {
+                tree: tree(),
+                id: 4,
+            },
+        ];
+        for event in events {
+            manifest.record(event).unwrap();
+        }
+        drop(manifest);
+
+        let manifest = Manifest::recover(fs).await.unwrap();
+        let state = manifest.state();
+        assert_eq!(state.tables.get("tree"), Some(&vec![vec![4], vec![2, 3]]));
+        assert_eq!(state.next_table_id, 5);
+    }
+
+    #[tokio::test]
+    async fn recover_reserved_keys() {
+        let fs = init_fs(&[] as &[(&str, &str)]).await;
+        let mut manifest = Manifest::recover(fs.clone()).await.unwrap();
+        let reserve = |end: u8| ManifestEvent::KeysReserved {
+            tree: "tree".to_string(),
+            end: vec![end],
+        };
+        for event in [reserve(1), reserve(3), reserve(2)] {
+            manifest.record(event).unwrap();
+        }
+        drop(manifest);
+
+        let manifest = Manifest::recover(fs).await.unwrap();
+        assert_eq!(manifest.state().sequences.get("tree"), Some(&vec![3]));
+    }
 }
@@ -1,6 +1,8 @@
+mod compaction;
 pub mod db;
 pub mod fs;
 mod manifest;
 pub mod subtree;
+pub mod table;
 pub mod tree;
 pub mod wal;
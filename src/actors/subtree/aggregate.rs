@@ -8,10 +8,14 @@ use crate::{
 };
 
 use super::{
-    messages::{ChangeItem, RestoreItem},
-    AggregateTree, IdentityFn, SubTreeRestorer, SubTreeSubscriber, UtilTreeAddress,
+    messages::{Backfill, ChangeItem, Prefix, RestoreItem},
+    AggregateTree, IdentitiesFn, SubTreeBackfill, SubTreeRestorer, SubTreeSubscriber,
+    UtilTreeAddress,
 };
 
+/// Aggregates of every identity that was read, in order of their identity
+pub type AggregateRecords<ID, Record> = anyhow::Result<Vec<(ID, Record)>>;
+
 pub struct AggregateTreeActor<
     ID: PrimaryKey,
     Record: Aggregate<Key, Value> + RecordValue,
@@ -19,8 +23,8 @@ pub struct AggregateTreeActor<
     Value: RecordValue,
 > {
     tree: Tree<ID, (Record, Vec<Key>)>,
-    _source_tree: Tree<Key, Value>,
-    identity: Box<dyn IdentityFn<ID, Value>>,
+    source_tree: Tree<Key, Value>,
+    identity: Box<dyn IdentitiesFn<ID, Value>>,
 }
 
 impl<ID, Record, Key, Value> std::fmt::Debug for AggregateTreeActor<ID, Record, Key, Value>
@@ -33,7 +37,7 @@ where
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AggregateTreeActor")
             .field("tree", &self.tree)
-            .field("_source_tree", &self._source_tree)
+            .field("source_tree", &self.source_tree)
             .finish()
     }
 }
@@ -61,65 +65,71 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
-    pub fn new<F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static>(
+    pub(crate) fn new(
         tree: Tree<ID, (Record, Vec<Key>)>,
         source_tree: Tree<Key, Value>,
-        identity: F,
+        identity: impl IdentitiesFn<ID, Value> + 'static,
     ) -> Self {
         Self {
             tree,
-            _source_tree: source_tree,
+            source_tree,
             identity: Box::new(identity),
         }
     }
 
-    async fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) {
-        match change.update {
-            Update::Set { old, new } => {
-                match old {
-                    Some(old) => {
-                        // updated value
-                        let old_id = self.identity.identify(&old);
-                        let new_id = self.identity.identify(&new);
-                        match (old_id, new_id) {
-                            (Some(old_id), Some(new_id)) => {
-                                if old_id == new_id {
-                                    self.update(new_id, &*change.key, &*old, &*new).await;
-                                } else {
-                                    self.delete(old_id, &*change.key, &*old).await;
-                                    self.create(new_id, &change.key, &*new).await;
-                                }
-                            }
-                            (Some(old_id), None) => {
-                                self.delete(old_id, &*change.key, &*old).await;
-                            }
-                            (None, Some(new_id)) => {
-                                // This is the same event as create. Because of an
-                                // update, we can now add the value to this bucket
-                                self.create(new_id, &change.key, &*new).await;
-                            }
-                            (None, None) => {
-                                unreachable!("The identity function changed. If this triggers, our aggregate sub tree now has bad data");
-                            }
-                        }
-                    }
-                    None => {
-                        // new value
-                        if let Some(id) = self.identity.identify(&new) {
-                            self.create(id, &change.key, &*new).await;
-                        }
-                    }
-                }
+    async fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) -> anyhow::Result<()> {
+        let (old, new) = match &change.update {
+            Update::Set { old, new } => (old.as_deref(), Some(&**new)),
+            Update::Del { old } => (Some(&**old), None),
+        };
+        let diff = self.identity.diff(old, new);
+        if let Some(old) = old {
+            for id in &diff.removed {
+                self.delete(id, &change.key, old).await?;
+            }
+        }
+        if let (Some(old), Some(new)) = (old, new) {
+            for id in &diff.kept {
+                self.update(id, &change.key, old, new).await?;
+            }
+        }
+        if let Some(new) = new {
+            // A value that is updated into a bucket is the same event as a
+            // value that is created in it
+            for id in &diff.added {
+                self.create(id, &change.key, new).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the source tree and aggregate every record that it already
+    /// holds, a page at a time. Changes that arrive in the meantime wait until
+    /// the backfill is done.
+    async fn backfill(&mut self, backfill: Backfill<Key, Value>) -> anyhow::Result<()> {
+        let Backfill {
+            subscriber,
+            mut progress,
+        } = backfill;
+        self.source_tree.subscribe(subscriber.clone()).await;
+        loop {
+            let records = self.source_tree.backfill_page(&subscriber).await?;
+            if records.is_empty() {
+                break;
             }
-            Update::Del { old } => {
-                if let Some(old_id) = self.identity.identify(&old) {
-                    self.delete(old_id, &*change.key, &*old).await
+            let added = records.len();
+            for (key, value) in records {
+                for id in self.identity.identities(&value) {
+                    self.create(&id, &key, &value).await?;
                 }
             }
+            progress.added(added);
         }
+        progress.done();
+        Ok(())
     }
 
-    async fn create(&mut self, id: &ID, key: &Key, value: &Value) {
+    async fn create(&mut self, id: &ID, key: &Key, value: &Value) -> anyhow::Result<()> {
         let change = Change {
             key,
             update: Update::Set {
@@ -128,10 +138,10 @@ where
             },
         };
         self.handle_change(Operation::Create, id.clone(), change)
-            .await;
+            .await
     }
 
-    async fn update(&mut self, id: &ID, key: &Key, old: &Value, new: &Value) {
+    async fn update(&mut self, id: &ID, key: &Key, old: &Value, new: &Value) -> anyhow::Result<()> {
         let change = Change {
             key,
             update: Update::Set {
@@ -140,24 +150,28 @@ where
             },
         };
         self.handle_change(Operation::Update, id.clone(), change)
-            .await;
+            .await
     }
 
-    async fn delete(&mut self, id: &ID, key: &Key, old: &Value) {
+    async fn delete(&mut self, id: &ID, key: &Key, old: &Value) -> anyhow::Result<()> {
         let change = Change {
             key,
             update: Update::Del { old },
         };
         self.handle_change(Operation::Delete, id.clone(), change)
-            .await;
+            .await
     }
 
-    async fn handle_change(&self, op: Operation, id: ID, change: Change<&Key, &Value>) {
+    async fn handle_change(
+        &self,
+        op: Operation,
+        id: ID,
+        change: Change<&Key, &Value>,
+    ) -> anyhow::Result<()> {
         let (mut record, mut list) = self
             .tree
             .get(id.clone())
-            .await
-            .unwrap()
+            .await?
             .unwrap_or((Record::default(), Vec::new()));
         match op {
             Operation::Create => {
@@ -179,7 +193,7 @@ where
                 // TODO(Alec): Should there be a panic here? Shouldn't the record always exist?
             }
         }
-        self.tree.update(id, (record, list)).await.unwrap();
+        self.tree.update(id, (record, list)).await
     }
 
     async fn get(&self, id: ID) -> Option<Record> {
@@ -190,21 +204,33 @@ where
         }
     }
 
+    async fn get_prefix(&self, prefix: Vec<u8>) -> AggregateRecords<ID, Record> {
+        let records = self.tree.prefix_bytes(prefix).await?;
+        Ok(records
+            .into_iter()
+            .map(|(id, (record, _))| (id, record))
+            .collect())
+    }
+
     pub fn spawn_with_ctx<P: Actor + Handler<DeadActorResult<Self>>>(
         self,
         ctx: &Ctx<P>,
     ) -> UtilTreeAddress<AggregateTree<ID, Record>, Key, Value> {
-        let (restore_tx, subscribe_tx, get_tx) = CtxBuilder::new(self)
+        let (restore_tx, subscribe_tx, backfill_tx, get_tx, prefix_tx) = CtxBuilder::new(self)
             .ask_asyncer::<RestoreItem>()
             .ask_asyncer::<ChangeItem<Key, Value>>()
+            .ask_asyncer::<Backfill<Key, Value>>()
             .ask_asyncer::<ID>()
+            .ask_asyncer::<Prefix>()
             .spawn(ctx);
         let restorer = SubTreeRestorer::new(restore_tx);
         let subscriber = SubTreeSubscriber::new(subscribe_tx);
-        let tree = AggregateTree::new(get_tx);
+        let backfill = SubTreeBackfill::new(backfill_tx);
+        let tree = AggregateTree::new(get_tx, prefix_tx);
         UtilTreeAddress {
             restorer,
             subscriber,
+            backfill,
             tree,
         }
     }
@@ -221,16 +247,29 @@ where
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
     fn handle<'a>(&'a mut self, restore: RestoreItem, _: &mut Ctx<Self>) -> Self::Future<'a> {
-        if let Ok((key, Some(value))) = restore.deserialize::<Key, Value>() {
-            return Box::pin(async move {
-                if let Some(id) = self.identity.identify(&value) {
-                    println!("Adding item {:?} to list {:?} with {:?}", id, key, value);
-                    self.create(id, &key, &value).await;
+        match restore.deserialize::<Key, Value>() {
+            Ok((key, Some(value))) => Box::pin(async move {
+                for id in self.identity.identities(&value) {
+                    tracing::trace!("Restoring {:?} into {:?}", key, id);
+                    if let Err(err) = self.create(&id, &key, &value).await {
+                        println!("Failed to restore {:?} into aggregate: {}", key, err);
+                    }
                 }
-            });
+            }),
+            // A tombstone doesn't hold the value that was deleted, so the
+            // aggregate can't tell which identities to remove the key from. It
+            // doesn't have to: the delete was applied to the aggregate's own
+            // tree before it returned, and that write is restored along with
+            // the tombstone.
+            Ok((key, None)) => {
+                tracing::trace!("Skipping deleted record {:?}", key);
+                Box::pin(async {})
+            }
+            Err(err) => {
+                println!("Failed to read a restored record of the aggregate: {}", err);
+                Box::pin(async {})
+            }
         }
-        println!("Skipping record");
-        Box::pin(async {})
     }
 }
 
@@ -242,7 +281,7 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
-    type Output = ();
+    type Output = anyhow::Result<()>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
     fn handle<'a>(
@@ -250,9 +289,7 @@ where
         chg: ChangeItem<Key, Value>,
         _: &mut Ctx<Self>,
     ) -> Self::Future<'a> {
-        Box::pin(async move {
-            self.change(chg.into_inner()).await;
-        })
+        Box::pin(async move { self.change(chg.into_inner()).await })
     }
 }
 
@@ -270,3 +307,38 @@ where
         Box::pin(async move { self.get(id).await })
     }
 }
+
+impl<ID, Record, Key, Value> AsyncAsk<Prefix> for AggregateTreeActor<ID, Record, Key, Value>
+where
+    ID: PrimaryKey,
+    Record: Aggregate<Key, Value> + RecordValue,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = AggregateRecords<ID, Record>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, prefix: Prefix, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move { self.get_prefix(prefix.prefix).await })
+    }
+}
+
+impl<ID, Record, Key, Value> AsyncAsk<Backfill<Key, Value>>
+    for AggregateTreeActor<ID, Record, Key, Value>
+where
+    ID: PrimaryKey,
+    Record: Aggregate<Key, Value> + RecordValue,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        backfill: Backfill<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        Box::pin(async move { self.backfill(backfill).await })
+    }
+}
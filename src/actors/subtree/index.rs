@@ -9,15 +9,15 @@ use crate::{
 };
 
 use super::{
-    messages::{ChangeItem, RestoreItem},
-    IdentityFn, RefactorMeResponse, Request, SubTree, SubTreeRestorer, SubTreeSubscriber,
-    UtilTreeAddress,
+    messages::{Backfill, ChangeItem, RestoreItem},
+    IdentitiesFn, RefactorMeResponse, Request, SubTree, SubTreeBackfill, SubTreeRestorer,
+    SubTreeSubscriber, UtilTreeAddress,
 };
 
 pub struct IndexTreeActor<ID: PrimaryKey, Key: PrimaryKey, Value: RecordValue> {
     tree: Tree<ID, Vec<Key>>,
     source_tree: Tree<Key, Value>,
-    identity: Box<dyn IdentityFn<ID, Value>>,
+    identity: Box<dyn IdentitiesFn<ID, Value>>,
 }
 
 impl<ID, Key, Value> Actor for IndexTreeActor<ID, Key, Value>
@@ -48,10 +48,10 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
-    pub fn new<F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static>(
+    pub(crate) fn new(
         tree: Tree<ID, Vec<Key>>,
         source_tree: Tree<Key, Value>,
-        identity: F,
+        identity: impl IdentitiesFn<ID, Value> + 'static,
     ) -> Self {
         Self {
             tree,
@@ -60,64 +60,63 @@ where
         }
     }
 
-    async fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) {
-        match change.update {
-            Update::Set { old, new } => {
-                match old {
-                    Some(old) => {
-                        // updated value
-                        let old_id = self.identity.identify(&old);
-                        let new_id = self.identity.identify(&new);
-                        match (old_id, new_id) {
-                            (Some(old_id), Some(new_id)) => {
-                                if old_id == new_id {
-                                    self.add_key_to_list(new_id, (*change.key).clone()).await;
-                                } else {
-                                    self.remove_key_from_list(old_id, &*change.key).await;
-                                    self.add_key_to_list(new_id, (*change.key).clone()).await;
-                                }
-                            }
-                            (Some(old_id), None) => {
-                                self.remove_key_from_list(old_id, &*change.key).await
-                            }
-                            (None, Some(new_id)) => {
-                                self.add_key_to_list(new_id, (*change.key).clone()).await;
-                            }
-                            (None, None) => {
-                                unreachable!("The identity function changed. If this triggers, our index sub tree now has bad data");
-                            }
-                        }
-                    }
-                    None => {
-                        // new value
-                        if let Some(id) = self.identity.identify(&new) {
-                            self.add_key_to_list(id, (*change.key).clone()).await;
-                        }
-                    }
-                }
+    async fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) -> anyhow::Result<()> {
+        let (old, new) = match &change.update {
+            Update::Set { old, new } => (old.as_deref(), Some(&**new)),
+            Update::Del { old } => (Some(&**old), None),
+        };
+        let diff = self.identity.diff(old, new);
+        for id in &diff.removed {
+            self.remove_key_from_list(id, &change.key).await?;
+        }
+        for id in diff.kept.iter().chain(&diff.added) {
+            self.add_key_to_list(id, (*change.key).clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the source tree and add every record that it already holds,
+    /// a page at a time. Changes that arrive in the meantime wait until the
+    /// backfill is done.
+    async fn backfill(&mut self, backfill: Backfill<Key, Value>) -> anyhow::Result<()> {
+        let Backfill {
+            subscriber,
+            mut progress,
+        } = backfill;
+        self.source_tree.subscribe(subscriber.clone()).await;
+        loop {
+            let records = self.source_tree.backfill_page(&subscriber).await?;
+            if records.is_empty() {
+                break;
             }
-            Update::Del { old } => {
-                if let Some(old_id) = self.identity.identify(&old) {
-                    self.remove_key_from_list(old_id, &*change.key).await
+            let added = records.len();
+            for (key, value) in records {
+                for id in self.identity.identities(&value) {
+                    self.add_key_to_list(&id, key.clone()).await?;
                 }
             }
+            progress.added(added);
         }
+        progress.done();
+        Ok(())
     }
 
-    async fn add_key_to_list(&mut self, id: &ID, key: Key) {
-        let mut list = self.tree.get(id.clone()).await.unwrap().unwrap_or(vec![]);
+    async fn add_key_to_list(&mut self, id: &ID, key: Key) -> anyhow::Result<()> {
+        let mut list = self.tree.get(id.clone()).await?.unwrap_or(vec![]);
         if !list.contains(&key) {
             list.push(key);
-            self.tree.update(id.clone(), list).await.unwrap();
+            self.tree.update(id.clone(), list).await?;
         }
+        Ok(())
     }
 
-    async fn remove_key_from_list(&mut self, id: &ID, key: &Key) {
-        let mut list = self.tree.get(id.clone()).await.unwrap().unwrap_or(vec![]);
+    async fn remove_key_from_list(&mut self, id: &ID, key: &Key) -> anyhow::Result<()> {
+        let mut list = self.tree.get(id.clone()).await?.unwrap_or(vec![]);
         if let Some(index) = list.iter().position(|id| id == key) {
             list.remove(index);
-            self.tree.update(id.clone(), list).await.unwrap();
+            self.tree.update(id.clone(), list).await?;
         }
+        Ok(())
     }
 
     async fn get(&self, id: ID) -> Vec<Value> {
@@ -135,6 +134,18 @@ where
         }
     }
 
+    async fn get_prefix(&self, prefix: Vec<u8>) -> Vec<Value> {
+        let mut storage = Vec::new();
+        for (_, keys) in self.tree.prefix_bytes(prefix).await.unwrap() {
+            for key in keys {
+                if let Some(value) = self.source_tree.get(key).await.unwrap() {
+                    storage.push(value);
+                }
+            }
+        }
+        storage
+    }
+
     async fn get_item_by_index(
         &self,
         id: ID,
@@ -164,17 +175,20 @@ where
         self,
         ctx: &Ctx<P>,
     ) -> UtilTreeAddress<SubTree<ID, Value>, Key, Value> {
-        let (restore_tx, subscribe_tx, get_tx) = CtxBuilder::new(self)
+        let (restore_tx, subscribe_tx, backfill_tx, get_tx) = CtxBuilder::new(self)
             .ask_asyncer::<RestoreItem>()
             .ask_asyncer::<ChangeItem<Key, Value>>()
+            .ask_asyncer::<Backfill<Key, Value>>()
             .ask_asyncer::<Request<ID, Value>>()
             .spawn(ctx);
         let restorer = SubTreeRestorer::new(restore_tx);
         let subscriber = SubTreeSubscriber::new(subscribe_tx);
+        let backfill = SubTreeBackfill::new(backfill_tx);
         let tree = SubTree::new(get_tx);
         UtilTreeAddress {
             restorer,
             subscriber,
+            backfill,
             tree,
         }
     }
@@ -191,16 +205,28 @@ where
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
     fn handle<'a>(&'a mut self, restore: RestoreItem, _: &mut Ctx<Self>) -> Self::Future<'a> {
-        if let Ok((key, Some(value))) = restore.deserialize::<Key, Value>() {
-            return Box::pin(async move {
-                if let Some(id) = self.identity.identify(&value) {
-                    println!("Adding item {:?} to list {:?} with {:?}", id, key, value);
-                    self.add_key_to_list(id, key).await;
+        match restore.deserialize::<Key, Value>() {
+            Ok((key, Some(value))) => Box::pin(async move {
+                for id in self.identity.identities(&value) {
+                    tracing::trace!("Restoring {:?} into {:?}", key, id);
+                    if let Err(err) = self.add_key_to_list(&id, key.clone()).await {
+                        println!("Failed to restore {:?} into index: {}", key, err);
+                    }
                 }
-            });
+            }),
+            // A tombstone doesn't hold the value that was deleted, so the index
+            // can't tell which identities to remove the key from. It doesn't
+            // have to: the delete was applied to the index's own tree before it
+            // returned, and that write is restored along with the tombstone.
+            Ok((key, None)) => {
+                tracing::trace!("Skipping deleted record {:?}", key);
+                Box::pin(async {})
+            }
+            Err(err) => {
+                println!("Failed to read a restored record of the index: {}", err);
+                Box::pin(async {})
+            }
         }
-        println!("Skipping record");
-        Box::pin(async {})
     }
 }
 
@@ -210,7 +236,7 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
-    type Output = ();
+    type Output = anyhow::Result<()>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
     fn handle<'a>(
@@ -239,6 +265,7 @@ where
         Box::pin(async move {
             match request {
                 Request::List(id) => RefactorMeResponse::List(self.get(id).await),
+                Request::Prefix(prefix) => RefactorMeResponse::List(self.get_prefix(prefix).await),
                 Request::Item((id, index, op)) => {
                     RefactorMeResponse::Item(self.get_item_by_index(id, index, op).await)
                 }
@@ -246,3 +273,21 @@ where
         })
     }
 }
+
+impl<ID, Key, Value> AsyncAsk<Backfill<Key, Value>> for IndexTreeActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        backfill: Backfill<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        Box::pin(async move { self.backfill(backfill).await })
+    }
+}
@@ -2,7 +2,12 @@ use std::{error::Error, sync::Arc};
 
 use serde::Deserialize;
 
-use crate::Change;
+use crate::{
+    actors::tree::{PrimaryKey, RecordValue},
+    codec, Change,
+};
+
+use super::{BackfillReporter, SubTreeSubscriber};
 
 #[derive(Debug, Clone)]
 pub struct RestoreItem {
@@ -20,7 +25,7 @@ impl RestoreItem {
         Key: Deserialize<'a>,
         Value: Deserialize<'a>,
     {
-        let key: Key = bincode::deserialize(&self.key)?;
+        let key: Key = codec::from_slice(&self.key)?;
         if let Some(value) = &*self.value {
             let value: Value = serde_json::from_slice(value)?;
             Ok((key, Some(value)))
@@ -52,3 +57,53 @@ impl<Key, Value> ChangeItem<Key, Value> {
         self.inner
     }
 }
+
+/// Claim the identity of a value that is about to be written under `key`, or
+/// under a key that the tree picks when `key` is `None`
+#[derive(Debug)]
+pub struct Claim<Key, Value> {
+    pub key: Option<Arc<Key>>,
+    pub value: Arc<Value>,
+}
+
+impl<Key, Value> Clone for Claim<Key, Value> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+/// Give up the claim of a value that was never written
+#[derive(Debug)]
+pub struct Release<Value> {
+    pub value: Arc<Value>,
+}
+
+impl<Value> Clone for Release<Value> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lookup<ID> {
+    pub id: ID,
+}
+
+/// Read the records of a sub tree whose identity starts with an encoded prefix
+#[derive(Debug, Clone)]
+pub struct Prefix {
+    pub prefix: Vec<u8>,
+}
+
+/// Subscribe a sub tree to its source tree and add every record that the
+/// source tree already holds, a page at a time
+#[derive(Debug)]
+pub struct Backfill<Key: PrimaryKey, Value: RecordValue> {
+    pub subscriber: SubTreeSubscriber<Key, Value>,
+    pub progress: BackfillReporter,
+}
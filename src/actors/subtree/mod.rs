@@ -1,17 +1,29 @@
 mod aggregate;
 mod index;
 mod messages;
+mod sorted;
+mod unique;
 
-use std::sync::Arc;
+use std::{
+    ops::RangeBounds,
+    sync::{Arc, Mutex},
+};
 
+use serde::Serialize;
 use tokactor::util::builder::ActorAsyncAskRef;
+use tokio::sync::watch;
 
-pub use aggregate::AggregateTreeActor;
+pub use aggregate::{AggregateRecords, AggregateTreeActor};
 pub use index::IndexTreeActor;
+pub use sorted::{SortedIndexActor, SortedIndexAddress};
+pub use unique::{UniqueIndexActor, UniqueIndexAddress};
 
-use crate::{Change, Update};
+use crate::{codec, Change, KeyPrefix, Tree, Update};
 
-use self::messages::{ChangeItem, RestoreItem};
+use self::{
+    messages::{Backfill, ChangeItem, Claim, Lookup, Prefix, Release, RestoreItem},
+    sorted::{SortedRecords, SortedRequest},
+};
 
 use super::tree::{PrimaryKey, RecordValue};
 
@@ -28,6 +40,65 @@ where
     }
 }
 
+/// Every bucket of an index or aggregate that a value belongs to. The
+/// identities are sorted and never repeat.
+pub(crate) trait IdentitiesFn<ID, Value>: Send + Sync {
+    fn identities(&self, value: &Value) -> Vec<ID>;
+
+    /// Identities of the old value that the new value no longer has, the ones
+    /// both of them have, and the ones only the new value has
+    fn diff(&self, old: Option<&Value>, new: Option<&Value>) -> Diff<ID>
+    where
+        ID: PartialEq,
+    {
+        let old = old.map(|old| self.identities(old)).unwrap_or_default();
+        let new = new.map(|new| self.identities(new)).unwrap_or_default();
+        let (kept, removed): (Vec<_>, Vec<_>) = old.into_iter().partition(|id| new.contains(id));
+        let added = new.into_iter().filter(|id| !kept.contains(id)).collect();
+        Diff {
+            removed,
+            kept,
+            added,
+        }
+    }
+}
+
+pub(crate) struct Diff<ID> {
+    pub removed: Vec<ID>,
+    pub kept: Vec<ID>,
+    pub added: Vec<ID>,
+}
+
+/// Identity of values that belong to at most one bucket
+pub(crate) struct Single<F>(pub F);
+
+impl<F, ID, Value> IdentitiesFn<ID, Value> for Single<F>
+where
+    F: Fn(&Value) -> Option<&ID> + Send + Sync,
+    ID: Clone,
+{
+    fn identities(&self, value: &Value) -> Vec<ID> {
+        (self.0)(value).cloned().into_iter().collect()
+    }
+}
+
+/// Identity of values that belong to every bucket it returns
+pub(crate) struct Many<F>(pub F);
+
+impl<F, I, ID, Value> IdentitiesFn<ID, Value> for Many<F>
+where
+    F: Fn(&Value) -> I + Send + Sync,
+    I: IntoIterator<Item = ID>,
+    ID: Ord,
+{
+    fn identities(&self, value: &Value) -> Vec<ID> {
+        let mut ids = (self.0)(value).into_iter().collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SubTreeRestorer {
     inner: ActorAsyncAskRef<RestoreItem, ()>,
@@ -46,16 +117,43 @@ impl SubTreeRestorer {
     }
 }
 
+/// Records of the source tree that the backfill of a sub tree has read
+#[derive(Debug, Clone)]
+pub(crate) enum Backfilled<Key> {
+    Nothing,
+    /// Every record up to and including the key
+    Until(Key),
+    Everything,
+}
+
+impl<Key: Ord> Backfilled<Key> {
+    fn covers(&self, key: &Key) -> bool {
+        match self {
+            Self::Nothing => false,
+            Self::Until(last) => key <= last,
+            Self::Everything => true,
+        }
+    }
+}
+
 /// A address to message a sub tree given a collections key and value.
 /// Send updates to a subcollection when the orignial collection changes.
 pub struct SubTreeSubscriber<Key: PrimaryKey, Value: RecordValue> {
-    inner: ActorAsyncAskRef<ChangeItem<Key, Value>, ()>,
+    /// Errors can't be cloned, so neither can the address that returns them
+    inner: Arc<ActorAsyncAskRef<ChangeItem<Key, Value>, anyhow::Result<()>>>,
+    /// Writes to records that the backfill hasn't read yet aren't sent, because
+    /// the backfill reads them later. Only moved forward while the writes of the
+    /// source tree are held exclusively.
+    backfilled: Arc<Mutex<Backfilled<Key>>>,
 }
 
 impl<Key: PrimaryKey, Value: RecordValue> std::fmt::Debug for SubTreeSubscriber<Key, Value> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SubTreeSubscriber")
-            .field("inner", &"ActorAsyncAskRef<ChangeItem<Key, Value>, ()>")
+            .field(
+                "inner",
+                &"ActorAsyncAskRef<ChangeItem<Key, Value>, anyhow::Result<()>>",
+            )
             .finish()
     }
 }
@@ -63,14 +161,38 @@ impl<Key: PrimaryKey, Value: RecordValue> std::fmt::Debug for SubTreeSubscriber<
 impl<Key: PrimaryKey, Value: RecordValue> Clone for SubTreeSubscriber<Key, Value> {
     fn clone(&self) -> Self {
         Self {
-            inner: self.inner.clone(),
+            inner: Arc::clone(&self.inner),
+            backfilled: Arc::clone(&self.backfilled),
         }
     }
 }
 
 impl<Key: PrimaryKey, Value: RecordValue> SubTreeSubscriber<Key, Value> {
-    pub fn new(inner: ActorAsyncAskRef<ChangeItem<Key, Value>, ()>) -> Self {
-        Self { inner }
+    pub fn new(inner: ActorAsyncAskRef<ChangeItem<Key, Value>, anyhow::Result<()>>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            backfilled: Arc::new(Mutex::new(Backfilled::Nothing)),
+        }
+    }
+
+    pub(crate) fn backfilled(&self) -> Backfilled<Key> {
+        self.backfilled.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_backfilled(&self, backfilled: Backfilled<Key>) {
+        *self.backfilled.lock().unwrap() = backfilled;
+    }
+
+    /// The subscriber as it was when a write was applied, which keeps how far
+    /// the backfill had read at the time
+    pub(crate) fn snapshot(&self) -> Self {
+        match self.backfilled() {
+            Backfilled::Everything => self.clone(),
+            backfilled => Self {
+                inner: Arc::clone(&self.inner),
+                backfilled: Arc::new(Mutex::new(backfilled)),
+            },
+        }
     }
 
     pub async fn created(self, key: Arc<Key>, new: Arc<Value>) -> anyhow::Result<()> {
@@ -94,7 +216,7 @@ impl<Key: PrimaryKey, Value: RecordValue> SubTreeSubscriber<Key, Value> {
         self.send(change).await
     }
 
-    pub async fn _delete(&self, key: Arc<Key>, old: Arc<Value>) -> anyhow::Result<()> {
+    pub async fn deleted(self, key: Arc<Key>, old: Arc<Value>) -> anyhow::Result<()> {
         let change = Change {
             key,
             update: Update::Del { old },
@@ -102,11 +224,20 @@ impl<Key: PrimaryKey, Value: RecordValue> SubTreeSubscriber<Key, Value> {
         self.send(change).await
     }
 
+    /// If both subscribers send changes to the same sub tree
+    pub(crate) fn is(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Fails with the error of the sub tree if it couldn't apply the change
     async fn send(&self, change: Change<Arc<Key>, Arc<Value>>) -> anyhow::Result<()> {
-        if let Err(err) = self.inner.ask_async(ChangeItem::new(change)).await {
-            panic!("Failed to send error {}", err);
+        if !self.backfilled.lock().unwrap().covers(&change.key) {
+            return Ok(());
+        }
+        match self.inner.ask_async(ChangeItem::new(change)).await {
+            Ok(result) => result,
+            Err(err) => anyhow::bail!("Failed to send change to sub tree: {}", err),
         }
-        Ok(())
     }
 }
 
@@ -117,6 +248,8 @@ pub enum RefactorMeResponse<Value> {
 
 pub enum Request<ID: PrimaryKey, Value: RecordValue> {
     List(ID),
+    /// Every value of the identities whose encoding starts with the prefix
+    Prefix(Vec<u8>),
     Item(
         #[allow(clippy::type_complexity)]
         (ID, usize, Box<dyn Fn(&mut Value) + Send + Sync + 'static>),
@@ -127,6 +260,7 @@ impl<ID: PrimaryKey, Value: RecordValue> std::fmt::Debug for Request<ID, Value>
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::List(arg0) => f.debug_tuple("List").field(arg0).finish(),
+            Self::Prefix(arg0) => f.debug_tuple("Prefix").field(arg0).finish(),
             Self::Item(arg0) => f.debug_tuple("Item").field(&(&arg0.0, &arg0.1)).finish(),
         }
     }
@@ -153,6 +287,25 @@ impl<ID: PrimaryKey, Value: RecordValue> SubTree<ID, Value> {
         }
     }
 
+    /// Every value of the identities that start with `prefix`, in order of
+    /// their identity
+    pub async fn list_prefix<P>(&self, prefix: &P) -> anyhow::Result<Vec<Value>>
+    where
+        ID: KeyPrefix<P>,
+        P: Serialize,
+    {
+        let prefix = codec::to_vec(prefix)?;
+        match self.inner.ask_async(Request::Prefix(prefix)).await {
+            Ok(RefactorMeResponse::List(list)) => Ok(list),
+            Ok(RefactorMeResponse::Item(_)) => {
+                panic!("Should never be responded. TODO: Please refactor me! Item returned expected List")
+            }
+            Err(err) => {
+                panic!("Error, failed to list sub tree {}", err);
+            }
+        }
+    }
+
     pub async fn mutate_by_index<F: Fn(&mut Value) + Send + Sync + 'static>(
         &self,
         id: ID,
@@ -177,11 +330,15 @@ impl<ID: PrimaryKey, Value: RecordValue> SubTree<ID, Value> {
 
 pub struct AggregateTree<ID: PrimaryKey, Value: RecordValue> {
     inner: ActorAsyncAskRef<ID, Option<Value>>,
+    prefix: ActorAsyncAskRef<Prefix, AggregateRecords<ID, Value>>,
 }
 
 impl<ID: PrimaryKey, Value: RecordValue> AggregateTree<ID, Value> {
-    pub fn new(inner: ActorAsyncAskRef<ID, Option<Value>>) -> Self {
-        Self { inner }
+    pub fn new(
+        inner: ActorAsyncAskRef<ID, Option<Value>>,
+        prefix: ActorAsyncAskRef<Prefix, AggregateRecords<ID, Value>>,
+    ) -> Self {
+        Self { inner, prefix }
     }
 
     pub async fn get(&self, key: ID) -> anyhow::Result<Option<Value>> {
@@ -192,10 +349,288 @@ impl<ID: PrimaryKey, Value: RecordValue> AggregateTree<ID, Value> {
             }
         }
     }
+
+    /// The aggregate of every identity that starts with `prefix`, in order of
+    /// their identity
+    pub async fn get_prefix<P>(&self, prefix: &P) -> AggregateRecords<ID, Value>
+    where
+        ID: KeyPrefix<P>,
+        P: Serialize,
+    {
+        let prefix = Prefix {
+            prefix: codec::to_vec(prefix)?,
+        };
+        self.prefix.ask_async(prefix).await?
+    }
+}
+
+/// Records of a tree in the order of a [`crate::SecondaryIndex`], created with
+/// [`crate::Database::create_sorted_index`]
+pub struct SortedIndex<Key: PrimaryKey, Value: RecordValue> {
+    inner: ActorAsyncAskRef<SortedRequest<Key, Value>, SortedRecords<Key, Value>>,
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> SortedIndex<Key, Value> {
+    pub fn new(
+        inner: ActorAsyncAskRef<SortedRequest<Key, Value>, SortedRecords<Key, Value>>,
+    ) -> Self {
+        Self { inner }
+    }
+
+    /// The record that sorts first
+    pub async fn first(&self) -> anyhow::Result<Option<(Key, Value)>> {
+        let records = self.read(SortedRequest::First).await?;
+        Ok(records.into_iter().next())
+    }
+
+    /// The record that sorts last
+    pub async fn last(&self) -> anyhow::Result<Option<(Key, Value)>> {
+        let records = self.read(SortedRequest::Last).await?;
+        Ok(records.into_iter().next())
+    }
+
+    /// Every record that sorts inside of the range. Bounds are compared with the
+    /// comparator of the index, so they only need to fill in the fields that it
+    /// looks at.
+    pub async fn range(&self, range: impl RangeBounds<Value>) -> anyhow::Result<Vec<(Key, Value)>>
+    where
+        Value: Clone,
+    {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.read(SortedRequest::Range(start, end)).await
+    }
+
+    /// Up to `limit` records that sort after the record `after`, or from the
+    /// start of the index when it is `None`. Pass the last record of a page to
+    /// get the next page, even if that record has changed since.
+    pub async fn page(
+        &self,
+        after: Option<(Key, Value)>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        self.read(SortedRequest::Page(after, limit)).await
+    }
+
+    async fn read(&self, request: SortedRequest<Key, Value>) -> anyhow::Result<Vec<(Key, Value)>> {
+        match self.inner.ask_async(request).await {
+            Ok(records) => records,
+            Err(err) => anyhow::bail!("Failed to read sorted index: {}", err),
+        }
+    }
+}
+
+/// Claims identities in a unique index for writes to its source tree, before
+/// they are written
+pub struct UniqueGuard<Key: PrimaryKey, Value: RecordValue> {
+    /// Errors can't be cloned, so neither can the address that returns them
+    claim: Arc<ActorAsyncAskRef<Claim<Key, Value>, anyhow::Result<()>>>,
+    release: ActorAsyncAskRef<Release<Value>, ()>,
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> std::fmt::Debug for UniqueGuard<Key, Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UniqueGuard").finish()
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> Clone for UniqueGuard<Key, Value> {
+    fn clone(&self) -> Self {
+        Self {
+            claim: Arc::clone(&self.claim),
+            release: self.release.clone(),
+        }
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> UniqueGuard<Key, Value> {
+    pub fn new(
+        claim: ActorAsyncAskRef<Claim<Key, Value>, anyhow::Result<()>>,
+        release: ActorAsyncAskRef<Release<Value>, ()>,
+    ) -> Self {
+        Self {
+            claim: Arc::new(claim),
+            release,
+        }
+    }
+
+    pub async fn claim(&self, key: Option<Arc<Key>>, value: Arc<Value>) -> anyhow::Result<()> {
+        match self.claim.ask_async(Claim { key, value }).await {
+            Ok(result) => result,
+            Err(err) => anyhow::bail!("Failed to claim unique identity: {}", err),
+        }
+    }
+
+    pub async fn release(&self, value: Arc<Value>) {
+        if let Err(err) = self.release.ask_async(Release { value }).await {
+            panic!("Failed to release unique identity {}", err);
+        }
+    }
+}
+
+/// Identities claimed by a write in every unique index of a tree. The claims
+/// are settled by the index once it is told about the write, and must be
+/// released if the write is never made.
+pub struct Claims<Key: PrimaryKey, Value: RecordValue> {
+    guards: Vec<UniqueGuard<Key, Value>>,
+    value: Arc<Value>,
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> Claims<Key, Value> {
+    /// Claim the identity of `value` in every index, or in none of them
+    pub async fn claim(
+        guards: Vec<UniqueGuard<Key, Value>>,
+        key: Option<Arc<Key>>,
+        value: Arc<Value>,
+    ) -> anyhow::Result<Self> {
+        let mut claims = Self {
+            guards: Vec::with_capacity(guards.len()),
+            value,
+        };
+        for guard in guards {
+            if let Err(err) = guard.claim(key.clone(), claims.value.clone()).await {
+                claims.release().await;
+                return Err(err);
+            }
+            claims.guards.push(guard);
+        }
+        Ok(claims)
+    }
+
+    pub async fn release(self) {
+        for guard in self.guards {
+            guard.release(self.value.clone()).await;
+        }
+    }
+
+    /// Release the claims if the write failed
+    pub async fn release_on_err<T>(self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        if result.is_err() {
+            self.release().await;
+        }
+        result
+    }
+
+    /// Release the claims unless the value was written
+    pub async fn release_unless_written(
+        self,
+        written: anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        if !matches!(written, Ok(true)) {
+            self.release().await;
+        }
+        written
+    }
+}
+
+/// Records of a tree found by an identity that belongs to at most one record,
+/// created with [`crate::Database::create_unique_index`]
+pub struct UniqueIndex<ID: PrimaryKey, Key: PrimaryKey, Value: RecordValue> {
+    inner: ActorAsyncAskRef<Lookup<ID>, anyhow::Result<Option<(Key, Value)>>>,
+}
+
+impl<ID: PrimaryKey, Key: PrimaryKey, Value: RecordValue> UniqueIndex<ID, Key, Value> {
+    pub fn new(inner: ActorAsyncAskRef<Lookup<ID>, anyhow::Result<Option<(Key, Value)>>>) -> Self {
+        Self { inner }
+    }
+
+    /// The record that owns the identity. Fails once the index has failed to
+    /// apply a write to its source tree.
+    pub async fn lookup(&self, id: ID) -> anyhow::Result<Option<(Key, Value)>> {
+        match self.inner.ask_async(Lookup { id }).await {
+            Ok(record) => record,
+            Err(err) => anyhow::bail!("Failed to look up unique index: {}", err),
+        }
+    }
 }
 
 pub struct UtilTreeAddress<Tree, Key: PrimaryKey, Value: RecordValue> {
     pub restorer: SubTreeRestorer,
     pub subscriber: SubTreeSubscriber<Key, Value>,
+    pub backfill: SubTreeBackfill<Key, Value>,
     pub tree: Tree,
 }
+
+/// Builds a sub tree from the records that its source tree already holds
+pub struct SubTreeBackfill<Key: PrimaryKey, Value: RecordValue> {
+    inner: ActorAsyncAskRef<Backfill<Key, Value>, anyhow::Result<()>>,
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> SubTreeBackfill<Key, Value> {
+    pub fn new(inner: ActorAsyncAskRef<Backfill<Key, Value>, anyhow::Result<()>>) -> Self {
+        Self { inner }
+    }
+
+    /// Subscribe the sub tree to its source tree, and add every record that the
+    /// source tree holds. Returns once all of them have been added.
+    pub async fn backfill(
+        &self,
+        source_tree: &Tree<Key, Value>,
+        subscriber: SubTreeSubscriber<Key, Value>,
+        progress: BackfillReporter,
+    ) -> anyhow::Result<()> {
+        let backfill = Backfill {
+            subscriber: subscriber.clone(),
+            progress,
+        };
+        let result = match self.inner.ask_async(backfill).await {
+            Ok(result) => result,
+            Err(err) => Err(err.into()),
+        };
+        if result.is_err() {
+            // The sub tree is never handed out, so stop telling it about writes
+            source_tree.unsubscribe(&subscriber).await;
+        }
+        result
+    }
+}
+
+/// Progress of the backfill of an index or aggregate, see
+/// [`crate::Database::backfill_progress`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackfillProgress {
+    /// Name of the index or aggregate
+    pub name: String,
+    /// Records of the source tree that have been added so far
+    pub records: usize,
+    /// Every record of the source tree has been added
+    pub done: bool,
+}
+
+/// Publishes the progress of a backfill to the watchers of the database
+#[derive(Debug)]
+pub struct BackfillReporter {
+    progress: Arc<watch::Sender<Option<BackfillProgress>>>,
+    name: String,
+    records: usize,
+}
+
+impl BackfillReporter {
+    pub(crate) fn new(
+        progress: Arc<watch::Sender<Option<BackfillProgress>>>,
+        name: impl ToString,
+    ) -> Self {
+        Self {
+            progress,
+            name: name.to_string(),
+            records: 0,
+        }
+    }
+
+    /// Report that more records have been added
+    pub(crate) fn added(&mut self, records: usize) {
+        self.records += records;
+        self.publish(false);
+    }
+
+    pub(crate) fn done(&self) {
+        self.publish(true);
+    }
+
+    fn publish(&self, done: bool) {
+        self.progress.send_replace(Some(BackfillProgress {
+            name: self.name.clone(),
+            records: self.records,
+            done,
+        }));
+    }
+}
@@ -0,0 +1,261 @@
+use std::{cmp::Ordering, collections::BTreeMap, ops::Bound, pin::Pin, sync::Arc};
+
+use futures::Future;
+use tokactor::{util::builder::CtxBuilder, Actor, AsyncAsk, Ctx, DeadActorResult, Handler};
+
+use crate::{
+    actors::tree::{PrimaryKey, RecordValue},
+    Change, SecondaryIndex, Tree, Update,
+};
+
+use super::{
+    messages::{Backfill, ChangeItem},
+    SortedIndex, SubTreeBackfill, SubTreeSubscriber,
+};
+
+/// Addresses of a sorted index: its subscriber on the source tree, the
+/// backfill that sorts the records it already holds, and the handle used to
+/// read the index
+pub type SortedIndexAddress<Key, Value> = (
+    SubTreeSubscriber<Key, Value>,
+    SubTreeBackfill<Key, Value>,
+    SortedIndex<Key, Value>,
+);
+
+/// Records of a sorted index in the order of its comparator
+pub type SortedRecords<Key, Value> = anyhow::Result<Vec<(Key, Value)>>;
+
+/// Read records of a sorted index in the order of its comparator
+#[derive(Debug)]
+pub enum SortedRequest<Key, Value> {
+    First,
+    Last,
+    Range(Bound<Value>, Bound<Value>),
+    /// Up to `limit` records that come after a record
+    Page(Option<(Key, Value)>, usize),
+}
+
+/// Keeps the keys of a tree sorted by the comparator of a [`SecondaryIndex`].
+/// The order only exists in memory, so the index is built from the records of
+/// the source tree by a backfill, and kept up to date as writes arrive after
+/// that.
+pub struct SortedIndexActor<Key: PrimaryKey, Value: RecordValue> {
+    name: String,
+    source_tree: Tree<Key, Value>,
+    index: Box<dyn SecondaryIndex<Value> + Send + Sync>,
+    /// Every record of the source tree, sorted by the comparator and then by key
+    entries: Vec<(Arc<Value>, Key)>,
+    values: BTreeMap<Key, Arc<Value>>,
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> Actor for SortedIndexActor<Key, Value> {}
+
+impl<Key: PrimaryKey, Value: RecordValue> std::fmt::Debug for SortedIndexActor<Key, Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SortedIndexActor")
+            .field("name", &self.name)
+            .field("source_tree", &self.source_tree)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> SortedIndexActor<Key, Value> {
+    pub fn new(
+        name: String,
+        source_tree: Tree<Key, Value>,
+        index: impl SecondaryIndex<Value> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            source_tree,
+            index: Box::new(index),
+            entries: Vec::new(),
+            values: BTreeMap::new(),
+        }
+    }
+
+    fn compare(&self, entry: &(Arc<Value>, Key), value: &Value, key: &Key) -> Ordering {
+        self.index
+            .compare(&entry.0, value)
+            .then_with(|| entry.1.cmp(key))
+    }
+
+    /// Subscribe to the source tree and sort every record that it already
+    /// holds, a page at a time. Changes that arrive in the meantime wait until
+    /// the backfill is done.
+    async fn backfill(&mut self, backfill: Backfill<Key, Value>) -> anyhow::Result<()> {
+        let Backfill {
+            subscriber,
+            mut progress,
+        } = backfill;
+        self.source_tree.subscribe(subscriber.clone()).await;
+        loop {
+            let records = self.source_tree.backfill_page(&subscriber).await?;
+            if records.is_empty() {
+                break;
+            }
+            let added = records.len();
+            for (key, value) in records {
+                let value = Arc::new(value);
+                self.values.insert(key.clone(), value.clone());
+                self.entries.push((value, key));
+            }
+            progress.added(added);
+        }
+        // Sorting once is cheaper than inserting every record in order
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by(|a, b| self.compare(a, &b.0, &b.1));
+        self.entries = entries;
+        progress.done();
+        Ok(())
+    }
+
+    fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) {
+        // The stored value of the key is removed instead of the old value of
+        // the change, so the index can't be left with a value it never stored
+        self.remove(&change.key);
+        if let Update::Set { new, .. } = change.update {
+            self.insert((*change.key).clone(), new);
+        }
+    }
+
+    fn insert(&mut self, key: Key, value: Arc<Value>) {
+        let index = self
+            .entries
+            .partition_point(|entry| self.compare(entry, &value, &key) == Ordering::Less);
+        self.entries.insert(index, (value.clone(), key.clone()));
+        self.values.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(value) = self.values.remove(key) {
+            let index = self
+                .entries
+                .partition_point(|entry| self.compare(entry, &value, key) == Ordering::Less);
+            if self.entries.get(index).is_some_and(|entry| &entry.1 == key) {
+                self.entries.remove(index);
+            }
+        }
+    }
+
+    fn lower_bound(&self, bound: &Bound<Value>) -> usize {
+        match bound {
+            Bound::Included(value) => self
+                .entries
+                .partition_point(|(v, _)| self.index.compare(v, value) == Ordering::Less),
+            Bound::Excluded(value) => self
+                .entries
+                .partition_point(|(v, _)| self.index.compare(v, value) != Ordering::Greater),
+            Bound::Unbounded => 0,
+        }
+    }
+
+    fn upper_bound(&self, bound: &Bound<Value>) -> usize {
+        match bound {
+            Bound::Included(value) => self
+                .entries
+                .partition_point(|(v, _)| self.index.compare(v, value) != Ordering::Greater),
+            Bound::Excluded(value) => self
+                .entries
+                .partition_point(|(v, _)| self.index.compare(v, value) == Ordering::Less),
+            Bound::Unbounded => self.entries.len(),
+        }
+    }
+
+    fn read(&self, request: SortedRequest<Key, Value>) -> anyhow::Result<Vec<(Key, Value)>> {
+        let entries = match request {
+            SortedRequest::First => &self.entries[..self.entries.len().min(1)],
+            SortedRequest::Last => &self.entries[self.entries.len().saturating_sub(1)..],
+            SortedRequest::Range(start, end) => {
+                let (start, end) = (self.lower_bound(&start), self.upper_bound(&end));
+                &self.entries[start..end.max(start)]
+            }
+            SortedRequest::Page(after, limit) => {
+                let start = match after {
+                    Some((key, value)) => self.entries.partition_point(|entry| {
+                        self.compare(entry, &value, &key) != Ordering::Greater
+                    }),
+                    None => 0,
+                };
+                let end = start.saturating_add(limit).min(self.entries.len());
+                &self.entries[start..end]
+            }
+        };
+        entries
+            .iter()
+            .map(|(value, key)| {
+                // Values are shared with the index, so hand back a copy
+                let value = serde_json::from_slice(&serde_json::to_vec(&**value)?)?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn spawn_with_ctx<P: Actor + Handler<DeadActorResult<Self>>>(
+        self,
+        ctx: &Ctx<P>,
+    ) -> SortedIndexAddress<Key, Value> {
+        let (subscribe_tx, backfill_tx, get_tx) = CtxBuilder::new(self)
+            .ask_asyncer::<ChangeItem<Key, Value>>()
+            .ask_asyncer::<Backfill<Key, Value>>()
+            .ask_asyncer::<SortedRequest<Key, Value>>()
+            .spawn(ctx);
+        (
+            SubTreeSubscriber::new(subscribe_tx),
+            SubTreeBackfill::new(backfill_tx),
+            SortedIndex::new(get_tx),
+        )
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> AsyncAsk<ChangeItem<Key, Value>>
+    for SortedIndexActor<Key, Value>
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        change: ChangeItem<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        self.change(change.into_inner());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> AsyncAsk<SortedRequest<Key, Value>>
+    for SortedIndexActor<Key, Value>
+{
+    type Output = SortedRecords<Key, Value>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        request: SortedRequest<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        let records = self.read(request);
+        Box::pin(async move { records })
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> AsyncAsk<Backfill<Key, Value>>
+    for SortedIndexActor<Key, Value>
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        backfill: Backfill<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        Box::pin(async move { self.backfill(backfill).await })
+    }
+}
@@ -0,0 +1,332 @@
+use std::{collections::BTreeMap, pin::Pin, sync::Arc};
+
+use futures::Future;
+use tokactor::{util::builder::CtxBuilder, Actor, AsyncAsk, Ctx, DeadActorResult, Handler};
+
+use crate::{
+    actors::tree::{PrimaryKey, RecordValue},
+    Change, Tree, UniqueViolation, Update,
+};
+
+use super::{
+    messages::{Backfill, ChangeItem, Claim, Lookup, Release},
+    IdentityFn, SubTreeBackfill, SubTreeSubscriber, UniqueGuard, UniqueIndex,
+};
+
+/// Addresses of a unique index: its subscriber on the source tree, the
+/// backfill that takes the identities of the records it already holds, the
+/// guard that writes claim identities from, and the handle used to look them up
+pub type UniqueIndexAddress<ID, Key, Value> = (
+    SubTreeSubscriber<Key, Value>,
+    SubTreeBackfill<Key, Value>,
+    UniqueGuard<Key, Value>,
+    UniqueIndex<ID, Key, Value>,
+);
+
+/// An index where every identity belongs to at most one key of the source
+/// tree. Writes claim the identity of their value before they are written, and
+/// the claim is rejected if another key already owns it.
+pub struct UniqueIndexActor<ID: PrimaryKey, Key: PrimaryKey, Value: RecordValue> {
+    name: String,
+    /// Key that owns each identity. Keys aren't required to implement
+    /// `Default`, so they are stored as an `Option` that is never `None`.
+    tree: Tree<ID, Option<Key>>,
+    source_tree: Tree<Key, Value>,
+    identity: Box<dyn IdentityFn<ID, Value>>,
+    /// Identities claimed by writes that haven't been applied to the index yet,
+    /// with the key that claimed them (`None` while an insert is picking its
+    /// key) and the number of writes that hold the claim
+    pending: BTreeMap<ID, (Option<Key>, usize)>,
+    /// Why the index stopped applying changes. Claims and lookups are rejected
+    /// from then on, because the index no longer knows who owns an identity.
+    failed: Option<String>,
+}
+
+impl<ID, Key, Value> Actor for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+}
+
+impl<ID, Key, Value> std::fmt::Debug for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UniqueIndexActor")
+            .field("name", &self.name)
+            .field("tree", &self.tree)
+            .field("pending", &self.pending)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl<ID, Key, Value> UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    pub fn new<F: Fn(&Value) -> Option<&ID> + Send + Sync + 'static>(
+        name: String,
+        tree: Tree<ID, Option<Key>>,
+        source_tree: Tree<Key, Value>,
+        identity: F,
+    ) -> Self {
+        Self {
+            name,
+            tree,
+            source_tree,
+            identity: Box::new(identity),
+            pending: BTreeMap::new(),
+            failed: None,
+        }
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        match &self.failed {
+            Some(reason) => anyhow::bail!("Unique index {} failed: {}", self.name, reason),
+            None => Ok(()),
+        }
+    }
+
+    async fn claim(&mut self, key: Option<Arc<Key>>, value: Arc<Value>) -> anyhow::Result<()> {
+        self.check()?;
+        let id = match self.identity.identify(&value) {
+            Some(id) => id.clone(),
+            None => return Ok(()),
+        };
+        let key = key.map(|key| (*key).clone());
+        if let Some((claimed_by, count)) = self.pending.get_mut(&id) {
+            // Only writes to the same record can share a claim
+            if claimed_by.is_some() && *claimed_by == key {
+                *count += 1;
+                return Ok(());
+            }
+            return Err(UniqueViolation::new(&self.name, &id).into());
+        }
+        match self.tree.get(id.clone()).await?.flatten() {
+            Some(owner) if Some(&owner) != key.as_ref() => {
+                Err(UniqueViolation::new(&self.name, &id).into())
+            }
+            _ => {
+                self.pending.insert(id, (key, 1));
+                Ok(())
+            }
+        }
+    }
+
+    /// Give up the claim of a write that was never made
+    fn release(&mut self, value: &Value) {
+        if let Some(id) = self.identity.identify(value) {
+            self.settle(id);
+        }
+    }
+
+    fn settle(&mut self, id: &ID) {
+        if let Some((_, count)) = self.pending.get_mut(id) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending.remove(id);
+            }
+        }
+    }
+
+    async fn change(&mut self, change: Change<Arc<Key>, Arc<Value>>) -> anyhow::Result<()> {
+        let (old, new) = match &change.update {
+            Update::Set { old, new } => (old.as_deref(), Some(&**new)),
+            Update::Del { old } => (Some(&**old), None),
+        };
+        let old_id = old.and_then(|old| self.identity.identify(old)).cloned();
+        let new_id = new.and_then(|new| self.identity.identify(new)).cloned();
+
+        if let Some(new_id) = &new_id {
+            self.settle(new_id);
+        }
+
+        if let Some(old_id) = old_id.filter(|old_id| Some(old_id) != new_id.as_ref()) {
+            // The old identity might already belong to another key
+            let owner = self.tree.get(old_id.clone()).await?.flatten();
+            if owner.as_ref() == Some(&*change.key) {
+                self.tree.delete(old_id).await?;
+            }
+        }
+        if let Some(new_id) = new_id {
+            // Claims keep duplicates out, except for writes made before the
+            // index was done with its backfill
+            self.take(new_id, (*change.key).clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the source tree and take the identity of every record that
+    /// it already holds, a page at a time. Fails with a [`UniqueViolation`] if
+    /// two of them have the same identity.
+    async fn backfill(&mut self, backfill: Backfill<Key, Value>) -> anyhow::Result<()> {
+        let Backfill {
+            subscriber,
+            mut progress,
+        } = backfill;
+        self.source_tree.subscribe(subscriber.clone()).await;
+        loop {
+            let records = self.source_tree.backfill_page(&subscriber).await?;
+            if records.is_empty() {
+                break;
+            }
+            let added = records.len();
+            for (key, value) in records {
+                if let Some(id) = self.identity.identify(&value).cloned() {
+                    self.take(id, key).await?;
+                }
+            }
+            progress.added(added);
+        }
+        progress.done();
+        Ok(())
+    }
+
+    /// Give an identity to a key. Fails with a [`UniqueViolation`] if another
+    /// record still has it.
+    async fn take(&mut self, id: ID, key: Key) -> anyhow::Result<()> {
+        match self.tree.get(id.clone()).await?.flatten() {
+            Some(owner) if owner == key => Ok(()),
+            Some(owner) if self.holds(&owner, &id).await? => {
+                Err(UniqueViolation::new(&self.name, &id).into())
+            }
+            _ => self.tree.put(id, Some(key)).await,
+        }
+    }
+
+    /// If the record still has the identity. The index keeps the identities of
+    /// records that changed while it wasn't open, until they are taken again.
+    async fn holds(&self, key: &Key, id: &ID) -> anyhow::Result<bool> {
+        let value = self.source_tree.get(key.clone()).await?;
+        Ok(value.is_some_and(|value| self.identity.identify(&value) == Some(id)))
+    }
+
+    async fn lookup(&self, id: ID) -> anyhow::Result<Option<(Key, Value)>> {
+        self.check()?;
+        let key = match self.tree.get(id).await?.flatten() {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let value = self.source_tree.get(key.clone()).await?;
+        Ok(value.map(|value| (key, value)))
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn spawn_with_ctx<P: Actor + Handler<DeadActorResult<Self>>>(
+        self,
+        ctx: &Ctx<P>,
+    ) -> UniqueIndexAddress<ID, Key, Value> {
+        let (subscribe_tx, backfill_tx, claim_tx, release_tx, lookup_tx) = CtxBuilder::new(self)
+            .ask_asyncer::<ChangeItem<Key, Value>>()
+            .ask_asyncer::<Backfill<Key, Value>>()
+            .ask_asyncer::<Claim<Key, Value>>()
+            .ask_asyncer::<Release<Value>>()
+            .ask_asyncer::<Lookup<ID>>()
+            .spawn(ctx);
+        let subscriber = SubTreeSubscriber::new(subscribe_tx);
+        let backfill = SubTreeBackfill::new(backfill_tx);
+        let guard = UniqueGuard::new(claim_tx, release_tx);
+        (subscriber, backfill, guard, UniqueIndex::new(lookup_tx))
+    }
+}
+
+impl<ID, Key, Value> AsyncAsk<ChangeItem<Key, Value>> for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        change: ChangeItem<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        Box::pin(async move {
+            // The writes that a failed index misses can't be claimed anymore
+            if self.failed.is_some() {
+                return Ok(());
+            }
+            let result = self.change(change.into_inner()).await;
+            if let Err(err) = &result {
+                println!("Failed to update unique index {}: {}", self.name, err);
+                self.failed = Some(err.to_string());
+            }
+            result
+        })
+    }
+}
+
+impl<ID, Key, Value> AsyncAsk<Claim<Key, Value>> for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, claim: Claim<Key, Value>, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move { self.claim(claim.key, claim.value).await })
+    }
+}
+
+impl<ID, Key, Value> AsyncAsk<Release<Value>> for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = ();
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, release: Release<Value>, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.release(&release.value);
+        Box::pin(async {})
+    }
+}
+
+impl<ID, Key, Value> AsyncAsk<Lookup<ID>> for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<Option<(Key, Value)>>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, lookup: Lookup<ID>, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move { self.lookup(lookup.id).await })
+    }
+}
+
+impl<ID, Key, Value> AsyncAsk<Backfill<Key, Value>> for UniqueIndexActor<ID, Key, Value>
+where
+    ID: PrimaryKey,
+    Key: PrimaryKey,
+    Value: RecordValue,
+{
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(
+        &'a mut self,
+        backfill: Backfill<Key, Value>,
+        _: &mut Ctx<Self>,
+    ) -> Self::Future<'a> {
+        Box::pin(async move { self.backfill(backfill).await })
+    }
+}
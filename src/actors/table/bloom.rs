@@ -0,0 +1,100 @@
+/// A bloom filter over the keys of a table. It can say for sure that a key is
+/// not inside of the table, so a lookup of a missing key doesn't need to read a
+/// block from the file.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    /// Number of bits that are set for every key
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Build a filter that uses `bits_per_key` bits for every key. Returns `None`
+    /// if `bits_per_key` is 0, which turns filters off.
+    pub fn build(keys: &[u64], bits_per_key: usize) -> Option<Self> {
+        if bits_per_key == 0 {
+            return None;
+        }
+        // ln(2) * bits per key gives the lowest false positive rate
+        let hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        // Tiny filters have a high false positive rate, so use at least 64 bits
+        let len = (keys.len() * bits_per_key).max(64).div_ceil(8);
+        let mut filter = Self {
+            bits: vec![0; len],
+            hashes,
+        };
+        for hash in keys {
+            for bit in filter.bits(*hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(filter)
+    }
+
+    /// Returns false if the key is not in the filter. Returns true if the key
+    /// might be in the filter.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bits that belong to a key, found using double hashing
+    fn bits(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let (mut h, delta) = (hash, hash.rotate_right(17) | 1);
+        (0..self.hashes).map(move |_| {
+            let bit = h % len;
+            h = h.wrapping_add(delta);
+            bit as usize
+        })
+    }
+}
+
+/// Hash of a key that is stored inside of filters, so it can never change
+/// between versions (unlike the hasher of the standard library). Uses 64 bit
+/// FNV-1a.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, BloomFilter};
+
+    fn keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| i.to_be_bytes().to_vec()).collect()
+    }
+
+    fn build(keys: &[Vec<u8>], bits_per_key: usize) -> Option<BloomFilter> {
+        let hashes = keys.iter().map(|key| hash(key)).collect::<Vec<_>>();
+        BloomFilter::build(&hashes, bits_per_key)
+    }
+
+    #[test]
+    fn contain_every_key_that_was_added() {
+        let keys = keys(0..1000);
+        let filter = build(&keys, 10).unwrap();
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+    }
+
+    #[test]
+    fn rule_out_most_missing_keys() {
+        let filter = build(&keys(0..1000), 10).unwrap();
+        let false_positives = keys(1000..11000)
+            .iter()
+            .filter(|key| filter.may_contain(key))
+            .count();
+        // 10 bits per key should give a false positive rate close to 1%
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn turn_off_filters_with_zero_bits_per_key() {
+        assert!(build(&keys(0..10), 0).is_none());
+    }
+}
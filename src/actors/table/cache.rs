@@ -0,0 +1,194 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use super::Entry;
+
+/// Table id and block number of a cached block
+type BlockKey = (u64, usize);
+
+/// Counters that show how well the block cache is working
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of blocks that were found in the cache
+    pub hits: u64,
+    /// Number of blocks that had to be read from a table file
+    pub misses: u64,
+    /// Number of blocks that were removed to make room for other blocks
+    pub evictions: u64,
+    /// Number of blocks held by the cache
+    pub blocks: usize,
+    /// Number of bytes held by the cache
+    pub size: usize,
+    /// Number of bytes the cache can hold
+    pub capacity: usize,
+}
+
+/// Decoded blocks of table files that are shared by every tree of a database.
+/// Once the cache holds more bytes then its capacity, the least recently used
+/// blocks are removed.
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    inner: Arc<Mutex<Lru>>,
+}
+
+#[derive(Debug)]
+struct Lru {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    /// Every cached block ordered from least to most recently used
+    order: BTreeMap<u64, BlockKey>,
+    /// Increases every time a block is used
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    entries: Arc<Vec<Entry>>,
+    size: usize,
+    tick: u64,
+}
+
+impl BlockCache {
+    /// Create a cache that can hold `capacity` bytes of blocks. A capacity of 0
+    /// turns the cache off.
+    pub fn new(capacity: usize) -> Self {
+        let lru = Lru {
+            blocks: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats {
+                capacity,
+                ..Default::default()
+            },
+        };
+        Self {
+            inner: Arc::new(Mutex::new(lru)),
+        }
+    }
+
+    /// Find a block and mark it as the most recently used block
+    pub fn get(&self, table: u64, block: usize) -> Option<Arc<Vec<Entry>>> {
+        let mut lru = self.inner.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        match lru.blocks.get_mut(&(table, block)) {
+            Some(cached) => {
+                let old = std::mem::replace(&mut cached.tick, tick);
+                let entries = cached.entries.clone();
+                lru.order.remove(&old);
+                lru.order.insert(tick, (table, block));
+                lru.stats.hits += 1;
+                Some(entries)
+            }
+            None => {
+                lru.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Add a block that takes up `size` bytes, removing the least recently used
+    /// blocks until it fits. Blocks larger then the whole cache are not kept.
+    pub fn insert(&self, table: u64, block: usize, entries: Arc<Vec<Entry>>, size: usize) {
+        let mut lru = self.inner.lock().unwrap();
+        if size > lru.stats.capacity {
+            return;
+        }
+        lru.remove(&(table, block));
+        while lru.stats.size + size > lru.stats.capacity {
+            let Some((_, key)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(cached) = lru.blocks.remove(&key) {
+                lru.stats.size -= cached.size;
+                lru.stats.evictions += 1;
+            }
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, (table, block));
+        lru.blocks.insert(
+            (table, block),
+            CachedBlock {
+                entries,
+                size,
+                tick,
+            },
+        );
+        lru.stats.size += size;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.inner.lock().unwrap();
+        CacheStats {
+            blocks: lru.blocks.len(),
+            ..lru.stats
+        }
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some(cached) = self.blocks.remove(key) {
+            self.order.remove(&cached.tick);
+            self.stats.size -= cached.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::BlockCache;
+
+    fn block() -> Arc<Vec<super::Entry>> {
+        Arc::new(vec![(b"key".to_vec(), None)])
+    }
+
+    #[test]
+    fn count_hits_and_misses() {
+        let cache = BlockCache::new(100);
+        assert!(cache.get(0, 0).is_none());
+        cache.insert(0, 0, block(), 10);
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(0, 1).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.blocks, stats.size), (1, 10));
+    }
+
+    #[test]
+    fn evict_the_least_recently_used_block() {
+        let cache = BlockCache::new(30);
+        cache.insert(0, 0, block(), 10);
+        cache.insert(0, 1, block(), 10);
+        cache.insert(1, 0, block(), 10);
+        // Use the oldest block so that the second block is evicted instead
+        assert!(cache.get(0, 0).is_some());
+        cache.insert(1, 1, block(), 10);
+
+        assert!(cache.get(0, 1).is_none());
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(1, 0).is_some());
+        assert!(cache.get(1, 1).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.size), (1, 30));
+    }
+
+    #[test]
+    fn never_hold_more_then_the_capacity() {
+        let cache = BlockCache::new(25);
+        cache.insert(0, 0, block(), 50);
+        assert_eq!(cache.stats().blocks, 0);
+
+        for block_id in 0..10 {
+            cache.insert(0, block_id, block(), 10);
+            assert!(cache.stats().size <= 25);
+        }
+        assert_eq!(cache.stats().blocks, 2);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{ops::Bound, sync::Arc};
+
+use super::{Entry, KeyRange, Table};
+
+/// Read the entries of a table that are inside of a range one block at a time
+pub struct TableIter<'a> {
+    table: &'a mut Table,
+    range: KeyRange,
+    /// Blocks that haven't been read yet
+    blocks: std::ops::Range<usize>,
+    entries: Arc<Vec<Entry>>,
+    /// Entries of the current block that haven't been returned yet
+    start: usize,
+    end: usize,
+    reverse: bool,
+}
+
+impl<'a> TableIter<'a> {
+    pub fn new(table: &'a mut Table, range: KeyRange, reverse: bool) -> Self {
+        // Only blocks that overlap with the range need to be read
+        let first = match &range.0 {
+            Bound::Included(start) => table.index.partition_point(|h| &h.last_key < start),
+            Bound::Excluded(start) => table.index.partition_point(|h| &h.last_key <= start),
+            Bound::Unbounded => 0,
+        };
+        let last = match &range.1 {
+            Bound::Included(end) => table.index.partition_point(|h| &h.first_key <= end),
+            Bound::Excluded(end) => table.index.partition_point(|h| &h.first_key < end),
+            Bound::Unbounded => table.len(),
+        };
+        Self {
+            table,
+            range,
+            blocks: first..last.max(first),
+            entries: Arc::new(Vec::new()),
+            start: 0,
+            end: 0,
+            reverse,
+        }
+    }
+
+    /// Index of the first entry and the index after the last entry of a block
+    /// that are inside of the range
+    fn bounds(&self, entries: &[Entry]) -> (usize, usize) {
+        let start = match &self.range.0 {
+            Bound::Included(start) => entries.partition_point(|(k, _)| k < start),
+            Bound::Excluded(start) => entries.partition_point(|(k, _)| k <= start),
+            Bound::Unbounded => 0,
+        };
+        let end = match &self.range.1 {
+            Bound::Included(end) => entries.partition_point(|(k, _)| k <= end),
+            Bound::Excluded(end) => entries.partition_point(|(k, _)| k < end),
+            Bound::Unbounded => entries.len(),
+        };
+        (start, end.max(start))
+    }
+}
+
+impl<'a> Iterator for TableIter<'a> {
+    type Item = anyhow::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.start < self.end {
+                let entry = if self.reverse {
+                    self.end -= 1;
+                    &self.entries[self.end]
+                } else {
+                    self.start += 1;
+                    &self.entries[self.start - 1]
+                };
+                return Some(Ok(entry.clone()));
+            }
+
+            let block = if self.reverse {
+                self.blocks.next_back()?
+            } else {
+                self.blocks.next()?
+            };
+            match self.table.read_block(block) {
+                Ok(entries) => {
+                    (self.start, self.end) = self.bounds(&entries);
+                    self.entries = entries;
+                }
+                Err(err) => {
+                    // Stop reading once part of the table can't be read
+                    self.blocks = 0..0;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::iter::Peekable;
+
+use super::Entry;
+
+/// Merge sorted sources of entries into a single sorted list of entries. When
+/// more then one source holds the same key, only the entry from the source that
+/// was given first is kept, so sources should be ordered from newest to oldest.
+pub struct Merge<I: Iterator<Item = anyhow::Result<Entry>>> {
+    sources: Vec<Peekable<I>>,
+    reverse: bool,
+}
+
+impl<I: Iterator<Item = anyhow::Result<Entry>>> Merge<I> {
+    pub fn new(sources: Vec<I>, reverse: bool) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            reverse,
+        }
+    }
+}
+
+impl<I: Iterator<Item = anyhow::Result<Entry>>> Iterator for Merge<I> {
+    type Item = anyhow::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the source that holds the next key
+        let mut next: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            let key = match source.peek() {
+                None => continue,
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) => key,
+            };
+            let is_next = match &next {
+                None => true,
+                Some((_, next)) if self.reverse => key > next,
+                Some((_, next)) => key < next,
+            };
+            if is_next {
+                next = Some((i, key.clone()));
+            }
+        }
+
+        let (source, key) = next?;
+        // Older sources that hold the same key are hidden by the newer source
+        for older in self.sources[source + 1..].iter_mut() {
+            if matches!(older.peek(), Some(Ok((k, _))) if *k == key) {
+                older.next();
+            }
+        }
+        self.sources[source].next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::actors::tree::MemRecord;
+
+    use super::{Entry, Merge};
+
+    fn source(entries: &[(u8, Option<u8>)]) -> std::vec::IntoIter<anyhow::Result<Entry>> {
+        entries
+            .iter()
+            .map(|(key, value)| {
+                let record = value.map(|data| MemRecord {
+                    version: 0,
+                    data: vec![data],
+                });
+                Ok((vec![*key], record))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn merge(sources: &[&[(u8, Option<u8>)]], reverse: bool) -> Vec<(u8, Option<u8>)> {
+        let sources = sources
+            .iter()
+            .map(|entries| {
+                let mut entries = entries.to_vec();
+                if reverse {
+                    entries.reverse();
+                }
+                source(&entries)
+            })
+            .collect();
+        Merge::new(sources, reverse)
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key[0], value.map(|record| record.data[0]))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn newer_sources_hide_older_sources() {
+        let newest: &[(u8, Option<u8>)] = &[(2, Some(20)), (4, None)];
+        let oldest: &[(u8, Option<u8>)] = &[(1, Some(1)), (2, Some(2)), (4, Some(4)), (5, Some(5))];
+        let expected = vec![(1, Some(1)), (2, Some(20)), (4, None), (5, Some(5))];
+        assert_eq!(merge(&[newest, oldest], false), expected);
+        assert_eq!(
+            merge(&[newest, oldest], true),
+            expected.into_iter().rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn return_errors_from_sources() {
+        let failing = vec![Err(anyhow::anyhow!("failed"))].into_iter();
+        let mut merge = Merge::new(vec![source(&[(1, Some(1))]), failing], false);
+        assert!(merge.next().unwrap().is_err());
+    }
+}
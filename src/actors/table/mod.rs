@@ -0,0 +1,475 @@
+//! Immutable sorted table files.
+//!
+//! Once a memtable grows too large it is frozen and written to a table file in
+//! the `storage` directory. A table holds the records of the memtable sorted by
+//! key and split into blocks, so a single record can be found by only reading
+//! the block that could hold it.
+//!
+//! ```text
+//! +-----------------+---------------+
+//! | magic (8 bytes) | version (u16) |                  file header
+//! +-----------------+---------------+---------------+
+//! | length (u32)    | crc (u32)     | block payload |  block 0
+//! +-----------------+---------------+---------------+
+//! | ...                                             |
+//! +-----------------+---------------+---------------+
+//! | length (u32)    | crc (u32)     | index payload |  index
+//! +-----------------+---------------+---------------+
+//! | index offset (u64) | magic (8 bytes) |              footer
+//! +--------------------+-----------------+
+//! ```
+//!
+//! Blocks and the index are written using the same frames as the WAL. A block
+//! holds its records serialized with bincode, and the index holds the first key,
+//! the last key and the location of every block. The index also holds a bloom
+//! filter over every key in the table, so most lookups of keys that aren't in
+//! the table never read a block.
+
+mod bloom;
+mod cache;
+mod iter;
+mod merge;
+mod options;
+
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    sync::Arc,
+};
+
+use super::{
+    fs::DbFile,
+    tree::MemRecord,
+    wal::frame::{self, Magic},
+};
+
+use bloom::BloomFilter;
+
+pub use cache::{BlockCache, CacheStats};
+pub use iter::TableIter;
+pub use merge::Merge;
+pub use options::TableOptions;
+
+pub const TABLE_MAGIC: Magic = *b"TKDB-SST";
+const FOOTER_SIZE: usize = 8 + TABLE_MAGIC.len();
+/// Number of bytes of keys and values a block holds before a new block is started
+const BLOCK_SIZE: usize = 4096;
+
+/// A record stored in a table. A value of `None` is a tombstone that hides any
+/// older value of the key.
+pub type Entry = (Vec<u8>, Option<MemRecord>);
+
+/// Range of encoded keys
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Returns true if no key can be inside of the range
+pub fn is_empty_range((start, end): &KeyRange) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Sorted entries read from a memtable or a table file
+pub type Entries<'a> = Box<dyn Iterator<Item = anyhow::Result<Entry>> + Send + Sync + 'a>;
+
+/// Name of the file that stores a table
+pub fn table_file_name(id: u64) -> String {
+    format!("TABLE-{}", id)
+}
+
+/// Location of a block inside of a table file
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlockHandle {
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Everything that is stored inside of the index frame of a table
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct TableIndex {
+    blocks: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+}
+
+/// Serialize entries that are sorted by key into the contents of a table file
+pub fn write_table<'a>(
+    entries: impl IntoIterator<Item = (&'a Vec<u8>, &'a Option<MemRecord>)>,
+    options: &TableOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let mut writer = TableWriter::new(Vec::new(), options)?;
+    for (key, value) in entries {
+        writer.add(key.clone(), value.clone())?;
+    }
+    writer.finish()
+}
+
+/// Write entries that are sorted by key to a table file one block at a time
+pub struct TableWriter<W: Write> {
+    out: W,
+    /// Number of bytes written to `out`
+    offset: u64,
+    index: Vec<BlockHandle>,
+    block: Vec<Entry>,
+    block_size: usize,
+    /// Hash of every key that was added, used to build the bloom filter
+    hashes: Vec<u64>,
+    bloom_bits_per_key: usize,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(mut out: W, options: &TableOptions) -> anyhow::Result<Self> {
+        let header = frame::header(&TABLE_MAGIC);
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            offset: header.len() as u64,
+            index: Vec::new(),
+            block: Vec::new(),
+            block_size: 0,
+            hashes: Vec::new(),
+            bloom_bits_per_key: options.bloom_bits_per_key,
+        })
+    }
+
+    /// Number of bytes the table would take up if it was finished now
+    pub fn size(&self) -> u64 {
+        self.offset + self.block_size as u64
+    }
+
+    /// Add the next entry. Entries must be added in sorted order.
+    pub fn add(&mut self, key: Vec<u8>, value: Option<MemRecord>) -> anyhow::Result<()> {
+        self.block_size += key.len() + value.as_ref().map(|v| v.data.len()).unwrap_or(0);
+        self.hashes.push(bloom::hash(&key));
+        self.block.push((key, value));
+        if self.block_size >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write the rest of the table and return where it was written to
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        let index = TableIndex {
+            filter: BloomFilter::build(&self.hashes, self.bloom_bits_per_key),
+            blocks: self.index,
+        };
+        let mut bytes = Vec::new();
+        frame::encode(&bincode::serialize(&index)?, &mut bytes);
+        bytes.extend_from_slice(&self.offset.to_be_bytes());
+        bytes.extend_from_slice(&TABLE_MAGIC);
+        self.out.write_all(&bytes)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_block(&mut self) -> anyhow::Result<()> {
+        let mut bytes = Vec::new();
+        frame::encode(&bincode::serialize(&self.block)?, &mut bytes);
+        self.out.write_all(&bytes)?;
+        self.index.push(BlockHandle {
+            first_key: self.block[0].0.clone(),
+            last_key: self.block[self.block.len() - 1].0.clone(),
+            offset: self.offset,
+            len: bytes.len() as u64,
+        });
+        self.offset += bytes.len() as u64;
+        self.block.clear();
+        self.block_size = 0;
+        Ok(())
+    }
+}
+
+/// A table file that has been opened for reading. Only the index is kept in
+/// memory, blocks are read from the file when they are needed, unless they are
+/// still inside of the block cache.
+#[derive(Debug)]
+pub struct Table {
+    id: u64,
+    file: DbFile,
+    /// Number of bytes in the file
+    size: u64,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    cache: Option<BlockCache>,
+}
+
+impl Table {
+    pub fn open(id: u64, mut file: DbFile) -> anyhow::Result<Self> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < (frame::HEADER_SIZE + FOOTER_SIZE) as u64 {
+            anyhow::bail!("Table {} is too short to be a table file", id);
+        }
+        let header = read_at(&mut file, 0, frame::HEADER_SIZE as u64)?;
+        frame::decode(&TABLE_MAGIC, &header)?;
+
+        let footer = read_at(&mut file, len - FOOTER_SIZE as u64, FOOTER_SIZE as u64)?;
+        if footer[8..] != TABLE_MAGIC {
+            anyhow::bail!("Table {} is missing its footer", id);
+        }
+        let index_offset = u64::from_be_bytes(footer[..8].try_into().unwrap());
+        let index_len = (len - FOOTER_SIZE as u64)
+            .checked_sub(index_offset)
+            .ok_or_else(|| anyhow::anyhow!("Table {} has an invalid index offset", id))?;
+        let bytes = read_at(&mut file, index_offset, index_len)?;
+        let (payload, _) = frame::read_frame(&bytes)
+            .ok_or_else(|| anyhow::anyhow!("Table {} has a corrupted index", id))?;
+        let index: TableIndex = bincode::deserialize(payload)?;
+        Ok(Self {
+            id,
+            file,
+            size: len,
+            index: index.blocks,
+            filter: index.filter,
+            cache: None,
+        })
+    }
+
+    /// Keep the blocks that are read from the table inside of a cache. Tables
+    /// that are only read once, like the inputs of a compaction, are better off
+    /// without one so they don't push out blocks that are used often.
+    pub fn with_cache(mut self, cache: BlockCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of blocks in the table
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Read all of the entries stored in a block
+    pub fn read_block(&mut self, block: usize) -> anyhow::Result<Arc<Vec<Entry>>> {
+        if let Some(entries) = self.cache.as_ref().and_then(|c| c.get(self.id, block)) {
+            return Ok(entries);
+        }
+        let handle = &self.index[block];
+        let bytes = read_at(&mut self.file, handle.offset, handle.len)?;
+        let (payload, _) = frame::read_frame(&bytes)
+            .ok_or_else(|| anyhow::anyhow!("Block {} of table {} is corrupted", block, self.id))?;
+        let entries = Arc::new(bincode::deserialize::<Vec<Entry>>(payload)?);
+        if let Some(cache) = self.cache.as_ref() {
+            cache.insert(self.id, block, entries.clone(), payload.len());
+        }
+        Ok(entries)
+    }
+
+    /// Find the entry of a key. Returns `None` if the table doesn't know about
+    /// the key and `Some(None)` if the key was deleted.
+    pub fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Option<MemRecord>>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        match self.index.get(block) {
+            Some(handle) if handle.first_key.as_slice() <= key => {}
+            _ => return Ok(None),
+        }
+        let entries = self.read_block(block)?;
+        let found = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key));
+        Ok(found.ok().map(|i| entries[i].1.clone()))
+    }
+
+    /// Returns false if the bloom filter of the table rules out the key
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter
+            .as_ref()
+            .map(|filter| filter.may_contain(key))
+            .unwrap_or(true)
+    }
+
+    /// Iterate over every entry in the table in order, or in reverse order
+    pub fn iter(&mut self, reverse: bool) -> TableIter<'_> {
+        TableIter::new(self, (Bound::Unbounded, Bound::Unbounded), reverse)
+    }
+
+    /// Iterate over the entries with a key inside of the range
+    pub fn range(&mut self, range: KeyRange, reverse: bool) -> TableIter<'_> {
+        TableIter::new(self, range, reverse)
+    }
+}
+
+fn read_at(file: &mut DbFile, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Write, ops::Bound};
+
+    use tokactor::Actor;
+
+    use crate::{
+        actors::{
+            fs::{DbFile, FileSystemFacade, OpenFileOptions},
+            tree::MemRecord,
+        },
+        FileSystem,
+    };
+
+    use super::{write_table, Table, TableOptions};
+
+    fn record(data: &str) -> Option<MemRecord> {
+        Some(MemRecord {
+            version: 0,
+            data: data.as_bytes().to_vec(),
+        })
+    }
+
+    async fn file(bytes: &[u8]) -> DbFile {
+        let fs = FileSystemFacade::new(FileSystem::in_memory(&[] as &[(&str, &str)]).start());
+        fs.open_base_dir().await.unwrap();
+        let options = OpenFileOptions::new("TABLE-0").read().write().create();
+        let mut file = fs.open(options).await.unwrap();
+        file.write_all(bytes).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    /// Enough records to fill more then one block
+    fn records() -> BTreeMap<Vec<u8>, Option<MemRecord>> {
+        (0..1000_u32)
+            .map(|i| {
+                let value = if i % 10 == 0 {
+                    None
+                } else {
+                    record(&format!("value {}", i))
+                };
+                (i.to_be_bytes().to_vec(), value)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn read_every_entry_of_a_table() {
+        let records = records();
+        let bytes = write_table(&records, &TableOptions::default()).unwrap();
+        let mut table = Table::open(0, file(&bytes).await).unwrap();
+        assert!(table.len() > 1);
+
+        for (key, value) in &records {
+            let found = table.get(key).unwrap().unwrap();
+            assert_eq!(found.map(|r| r.data), value.clone().map(|r| r.data));
+        }
+        assert!(table.get(&1000_u32.to_be_bytes()).unwrap().is_none());
+        assert!(table.get(&[]).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn iterate_over_a_table() {
+        let records = records();
+        let bytes = write_table(&records, &TableOptions::default()).unwrap();
+        let mut table = Table::open(0, file(&bytes).await).unwrap();
+
+        let keys = records.keys().cloned().collect::<Vec<_>>();
+        let found = table
+            .iter(false)
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(found, keys);
+
+        let reversed = table
+            .iter(true)
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(reversed, keys.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn open_an_empty_table() {
+        let bytes = write_table(&BTreeMap::new(), &TableOptions::default()).unwrap();
+        let mut table = Table::open(0, file(&bytes).await).unwrap();
+        assert!(table.get(b"key").unwrap().is_none());
+        assert_eq!(table.iter(false).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn fail_to_open_a_corrupted_table() {
+        let mut bytes = write_table(&records(), &TableOptions::default()).unwrap();
+        assert!(Table::open(0, file(&bytes[..bytes.len() - 1]).await).is_err());
+
+        let index = bytes.len() - 20;
+        bytes[index] ^= 0xff;
+        assert!(Table::open(0, file(&bytes).await).is_err());
+    }
+
+    #[tokio::test]
+    async fn rule_out_missing_keys_with_the_filter() {
+        let records = records();
+        let bytes = write_table(&records, &TableOptions::default()).unwrap();
+        let table = Table::open(0, file(&bytes).await).unwrap();
+        assert!(records.keys().all(|key| table.may_contain(key)));
+        let missing = (1000..2000_u32)
+            .filter(|i| table.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(missing < 50, "{} keys weren't ruled out", missing);
+    }
+
+    #[tokio::test]
+    async fn read_a_table_without_a_filter() {
+        let records = records();
+        let options = TableOptions {
+            bloom_bits_per_key: 0,
+        };
+        let bytes = write_table(&records, &options).unwrap();
+        let mut table = Table::open(0, file(&bytes).await).unwrap();
+        assert!(table.may_contain(&1000_u32.to_be_bytes()));
+        assert!(table.get(&1000_u32.to_be_bytes()).unwrap().is_none());
+        for (key, value) in &records {
+            let found = table.get(key).unwrap().unwrap();
+            assert_eq!(found.map(|r| r.data), value.clone().map(|r| r.data));
+        }
+    }
+
+    #[tokio::test]
+    async fn iterate_over_a_range_of_a_table() {
+        let records = records();
+        let bytes = write_table(&records, &TableOptions::default()).unwrap();
+        let mut table = Table::open(0, file(&bytes).await).unwrap();
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let keys = |table: &mut Table, range, reverse| {
+            table
+                .range(range, reverse)
+                .map(|entry| u32::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        let range = (Bound::Included(key(100)), Bound::Excluded(key(600)));
+        assert_eq!(
+            keys(&mut table, range.clone(), false),
+            (100..600).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(&mut table, range, true),
+            (100..600).rev().collect::<Vec<_>>()
+        );
+
+        let range = (Bound::Excluded(key(998)), Bound::Unbounded);
+        assert_eq!(keys(&mut table, range, false), vec![999]);
+        let range = (Bound::Unbounded, Bound::Included(key(1)));
+        assert_eq!(keys(&mut table, range, true), vec![1, 0]);
+        let range = (Bound::Included(key(2000)), Bound::Unbounded);
+        assert!(keys(&mut table, range, false).is_empty());
+    }
+}
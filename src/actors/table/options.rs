@@ -0,0 +1,15 @@
+/// Settings used when table files are written
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// Number of bits the bloom filter of a table uses for every key. A value
+    /// of 0 writes tables without a filter.
+    pub bloom_bits_per_key: usize,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            bloom_bits_per_key: 10,
+        }
+    }
+}
@@ -1,69 +1,360 @@
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{collections::HashMap, future::Future, io::Write, ops::Bound, pin::Pin, sync::Arc};
 
-use tokactor::{Actor, Ask, AsyncAsk, Ctx, Handler};
+use tokactor::{Actor, ActorRef, Ask, AsyncAsk, Ctx, Handler};
 
-use crate::actors::{
-    db::{RestoreComplete, TreeVersion},
-    subtree::SubTreeRestorer,
-    wal::{Item, Wal},
+use crate::{
+    actors::{
+        compaction::{Compact, Compacted, Compactor},
+        db::{RestoreComplete, TreeVersion},
+        fs::{FileSystemFacade, OpenFileOptions},
+        manifest::{Manifest, ManifestEvent, ReserveTableId},
+        subtree::SubTreeRestorer,
+        table::{
+            self, table_file_name, BlockCache, Entries, Entry, KeyRange, Merge, Table, TableOptions,
+        },
+        wal::{Durability, Item, Wal},
+    },
+    codec, AutoIncrement, TransactionConflict,
 };
 
 use super::{
-    memtable::MemTable, GetMemTableSnapshot, GetRecord, GetUniqueKey, InsertRecord, InsertSuccess,
-    ListEnd, PrimaryKey, Record, RecordValue, UpdateRecord,
+    memtable::{MemRecord, MemTable},
+    ApplyBatch, CompareAndSwap, DeleteRecord, GetRange, GetRecord, GetUniqueKey, InsertRecord,
+    InsertSuccess, ListEnd, ModifyRecord, PrimaryKey, ReadRecord, Record, RecordValue,
+    RestoreTables, UpdateRecord,
 };
 
+/// Number of auto-increment keys reserved in the manifest at a time
+const KEY_BLOCK_SIZE: u64 = 1024;
+
+/// Where the tree stores its table files, and where it records that they exist
+struct TableStorage {
+    fs: FileSystemFacade,
+    manifest: ActorRef<Manifest>,
+    cache: BlockCache,
+}
+
 pub struct TreeActor {
     name: String,
     memtable: MemTable,
+    /// Table files that hold everything that was flushed out of the memtable,
+    /// split into levels. Tables in level 0 can overlap and are ordered from
+    /// newest to oldest. Tables in every other level don't overlap and are
+    /// ordered by key.
+    levels: Vec<Vec<Table>>,
+    storage: Option<TableStorage>,
+    compactor: Compactor,
+    /// Only one compaction of the tree can run at a time
+    compacting: bool,
+    /// Size the memtable can grow to before it is flushed to a table file
+    memtable_size: usize,
+    table_options: TableOptions,
+    /// Last auto-increment key that was handed out
     max: Option<Vec<u8>>,
+    /// Last auto-increment key of the block that was reserved in the manifest
+    reserved: Option<Vec<u8>>,
     wal: Wal,
     versions: Vec<TreeVersion>,
     version: u16,
     sub_trees: Option<Vec<SubTreeRestorer>>,
     write_enabled: bool,
+    /// Number of writes applied to the tree since it was started
+    sequence: u64,
+    /// Sequence of the last write to every key of the memtable. Every other key
+    /// was last written at or before `flushed`.
+    written: HashMap<Vec<u8>, u64>,
+    /// Sequence of the last write that was flushed to a table file
+    flushed: u64,
 }
 
 impl Actor for TreeActor {}
 
 impl TreeActor {
-    pub fn new(name: String, versions: Vec<TreeVersion>, wal: Wal) -> Self {
+    pub fn new(
+        name: String,
+        versions: Vec<TreeVersion>,
+        wal: Wal,
+        memtable_size: usize,
+        table_options: TableOptions,
+        compactor: Compactor,
+    ) -> Self {
         assert!(!versions.is_empty());
         assert!(u16::MAX as usize > versions.len());
         let version = versions.len() as u16 - 1;
         Self {
             name,
             memtable: MemTable::new(),
+            levels: vec![Vec::new()],
+            storage: None,
+            compactor,
+            compacting: false,
+            memtable_size,
+            table_options,
             max: None,
+            reserved: None,
             wal,
             versions,
             version,
             sub_trees: None,
             write_enabled: false,
+            sequence: 0,
+            written: HashMap::new(),
+            flushed: 0,
+        }
+    }
+
+    /// Hand out the next auto-increment key of the tree. Keys are reserved in
+    /// blocks that are recorded in the manifest, so a key is never handed out
+    /// twice, even after the records that used it are deleted or the database is
+    /// restarted.
+    pub async fn next_key<Key: PrimaryKey + AutoIncrement>(&mut self) -> anyhow::Result<Key> {
+        let last = match self.max.clone() {
+            Some(max) => Some(max),
+            // Keys of a block that was only partly used before a restart are
+            // skipped. Trees that never reserved a block start after their
+            // largest stored key.
+            None => {
+                let stored = self.entries(true).next().transpose()?.map(|(key, _)| key);
+                stored.max(self.reserved.clone())
+            }
+        };
+        let key = match last {
+            Some(last) => codec::from_slice::<Key>(&last)?.increment(),
+            None => Key::default(),
+        };
+        let id = codec::to_vec(&key)?;
+
+        if self.reserved.as_ref().is_none_or(|end| &id > end) {
+            let end = codec::to_vec(&key.advance(KEY_BLOCK_SIZE - 1))?;
+            if let Some(storage) = self.storage.as_ref() {
+                let event = ManifestEvent::KeysReserved {
+                    tree: self.name.clone(),
+                    end: end.clone(),
+                };
+                storage.manifest.ask(event).await??;
+            }
+            self.reserved = Some(end);
         }
+        self.max = Some(id);
+        Ok(key)
+    }
+
+    /// Every entry of the tree in sorted order, or in reverse sorted order. The
+    /// memtable and the table files are merged so that only the newest entry of
+    /// each key is returned.
+    fn entries(&mut self, reverse: bool) -> Merge<Entries<'_>> {
+        self.entries_in((Bound::Unbounded, Bound::Unbounded), reverse)
     }
 
-    pub fn get_unique_id<Key: PrimaryKey>(&mut self) -> Key {
-        let key = if let Some(max) = self.max.as_ref() {
-            let mut key: Key = bincode::deserialize(max).unwrap();
-            key.increment()
+    /// Every entry of the tree with a key inside of the range. Only the blocks of
+    /// the table files that overlap with the range are read.
+    fn entries_in(&mut self, range: KeyRange, reverse: bool) -> Merge<Entries<'_>> {
+        if table::is_empty_range(&range) {
+            return Merge::new(Vec::new(), reverse);
+        }
+        let to_entry =
+            |(key, value): (&Vec<u8>, &Option<MemRecord>)| Ok((key.clone(), value.clone()));
+        let entries = self.memtable.range(range.clone());
+        let memtable: Entries<'_> = if reverse {
+            Box::new(entries.rev().map(to_entry))
         } else {
-            // TODO(Alec): Implementing a hack because i just want to move forward
-            //             with my life. Scan the entire memtable to find the largest
-            //             ID key. This shouldn't be what we actual use if we ever
-            //             move to production
-            if self.memtable.is_empty() {
-                Key::default()
-            } else {
-                // This is where the hack is (is this really a hack tho...)
-                let (max_key, _) = self.memtable.get_last().unwrap();
-                let mut key: Key = bincode::deserialize(&max_key).unwrap();
-                key.increment()
+            Box::new(entries.map(to_entry))
+        };
+        let mut sources = vec![memtable];
+        for table in self.levels.iter_mut().flatten() {
+            sources.push(Box::new(table.range(range.clone(), reverse)));
+        }
+        Merge::new(sources, reverse)
+    }
+
+    /// Turn entries into records of the latest version of the tree. Records
+    /// written by an older version are upgraded and written back to the tree.
+    async fn records(&mut self, entries: Vec<Entry>) -> anyhow::Result<Vec<Record>> {
+        let mut list = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            match value {
+                Some(mem) if mem.version == self.version => {
+                    list.push(Record::new(key, Some(mem.data)))
+                }
+                Some(mem) => {
+                    let (key, value) = self.upgrade(key, mem.data, mem.version).await?;
+                    self.write(key.clone(), Some(value.clone()), None).await?;
+                    list.push(Record::new(key, Some(value)))
+                }
+                None => list.push(Record::new(key, None)),
             }
+        }
+        Ok(list)
+    }
+
+    /// Find the newest value of a key in the memtable or the table files
+    fn get_record(&mut self, key: &[u8]) -> anyhow::Result<Option<MemRecord>> {
+        if let Some(record) = self.memtable.get(key) {
+            return Ok(record);
+        }
+        for table in self.levels.iter_mut().flatten() {
+            if let Some(record) = table.get(key)? {
+                return Ok(record);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the serialized value of a record, upgraded to the latest version of
+    /// the tree
+    async fn read(&mut self, key: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+        let record = self.get_record(&key)?;
+        let mut records = self.records(vec![(key, record)]).await?;
+        Ok(records.pop().and_then(|record| record.value))
+    }
+
+    /// Sequence of the last write to a key. A key that was flushed to a table
+    /// file reports the sequence of the flush, so it can only look changed when
+    /// it wasn't.
+    fn written_at(&self, key: &[u8]) -> u64 {
+        self.written.get(key).copied().unwrap_or(self.flushed)
+    }
+
+    /// Check that a record still holds the serialized value that was read from
+    /// it. A record written by an older version of the tree never matches.
+    fn is_unchanged(&mut self, key: &[u8], expected: Option<&Vec<u8>>) -> anyhow::Result<bool> {
+        Ok(match (self.get_record(key)?, expected) {
+            (None, None) => true,
+            (Some(record), Some(expected)) => {
+                record.version == self.version && &record.data == expected
+            }
+            _ => false,
+        })
+    }
+
+    /// Freeze the memtable and write it to a new table file. Once the table is
+    /// recorded in the manifest, the WAL no longer needs to keep the writes that
+    /// are inside of it.
+    async fn flush_memtable(&mut self) -> anyhow::Result<()> {
+        let (fs, manifest, cache) = match self.storage.as_ref() {
+            Some(storage) => (
+                storage.fs.clone(),
+                storage.manifest.clone(),
+                storage.cache.clone(),
+            ),
+            None => return Ok(()),
+        };
+        let id = manifest.ask(ReserveTableId).await?;
+
+        // TODO(Alec): More blocking operations on an async thread, the tree can't
+        //             do anything else while it is flushing anyways
+        let bytes = table::write_table(self.memtable.as_iter(), &self.table_options)?;
+        let options = OpenFileOptions::new(table_file_name(id))
+            .read()
+            .write()
+            .create()
+            .truncate();
+        let mut file = fs.open(options).await?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        let table = Table::open(id, file)?.with_cache(cache);
+
+        let tree = self.name.clone();
+        manifest
+            .ask(ManifestEvent::TableCreated { tree, id })
+            .await??;
+        self.levels[0].insert(0, table);
+        self.memtable = MemTable::new();
+        self.written.clear();
+        self.flushed = self.sequence;
+        self.wal.checkpoint(&self.name).await?;
+        Ok(())
+    }
+
+    /// Write a record to the WAL and then to the memtable. The memtable is only
+    /// changed once the WAL has accepted the write, so a failed write is never
+    /// visible to readers. A value of `None` writes a tombstone that deletes the
+    /// key.
+    async fn write(
+        &mut self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        durability: Option<Durability>,
+    ) -> anyhow::Result<()> {
+        if self.write_enabled {
+            let table = self.name.clone();
+            self.wal
+                .write(table, self.version, key.clone(), value.clone(), durability)
+                .await?;
+        }
+        self.apply(vec![(key, value)]).await;
+        Ok(())
+    }
+
+    /// Add writes that are already inside of the WAL to the memtable. The
+    /// memtable is only flushed once every write has been added, because the
+    /// flush marks all of them as persisted in the WAL.
+    async fn apply(&mut self, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        let mut size = 0;
+        for (key, value) in writes {
+            self.sequence += 1;
+            self.written.insert(key.clone(), self.sequence);
+            size = self.memtable.insert(key, self.version, value);
+        }
+        if self.write_enabled && size >= self.memtable_size {
+            // The write is already safe inside of the WAL, so a failed flush
+            // is retried on the next write.
+            if let Err(err) = self.flush_memtable().await {
+                println!("{err}");
+                println!("Failed to flush the memtable of {} to storage", self.name);
+            }
+        }
+    }
+
+    /// Start compacting the table files of the tree in the background if a
+    /// level has grown too large and no other compaction is running
+    fn maybe_compact(&mut self, ctx: &mut Ctx<Self>) {
+        if self.compacting || !self.write_enabled {
+            return;
+        }
+        if let Some(job) = self.pick_compaction() {
+            self.compacting = true;
+            let compactor = self.compactor.clone();
+            ctx.anonymous(async move { compactor.compact(job).await });
+        }
+    }
+
+    /// Pick the level that should be compacted into the level below it. Level 0
+    /// is compacted once it holds too many tables, because every one of them
+    /// needs to be read to find a key. Every other level is compacted once it
+    /// holds too many bytes. The whole level is merged with the whole level below
+    /// it, so the level below stays ordered by key.
+    fn pick_compaction(&self) -> Option<Compact> {
+        let storage = self.storage.as_ref()?;
+        let options = self.compactor.options();
+        let level = if self.levels[0].len() >= options.l0_compaction_trigger.max(1) {
+            0
+        } else {
+            (1..self.levels.len()).find(|level| {
+                let size: u64 = self.levels[*level].iter().map(Table::size).sum();
+                size > options.max_level_size(*level)
+            })?
         };
-        let serailize_key: Vec<u8> = bincode::serialize(&key).unwrap();
-        self.max = Some(serailize_key);
-        key
+        let output = level + 1;
+        let inputs = self.levels[level..self.levels.len().min(output + 1)]
+            .iter()
+            .flatten()
+            .map(Table::id)
+            .collect();
+        let drop_tombstones = self
+            .levels
+            .iter()
+            .skip(output + 1)
+            .all(|tables| tables.is_empty());
+        Some(Compact {
+            tree: self.name.clone(),
+            fs: storage.fs.clone(),
+            manifest: storage.manifest.clone(),
+            cache: storage.cache.clone(),
+            inputs,
+            level: output,
+            drop_tombstones,
+        })
     }
 
     pub async fn upgrade(
@@ -83,79 +374,159 @@ impl TreeActor {
     }
 }
 
-impl<Key: PrimaryKey> Ask<GetUniqueKey<Key>> for TreeActor {
-    type Result = Key;
+impl<Key: PrimaryKey + AutoIncrement> AsyncAsk<GetUniqueKey<Key>> for TreeActor {
+    type Output = anyhow::Result<Key>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle(&mut self, _: GetUniqueKey<Key>, _: &mut Ctx<Self>) -> Self::Result {
-        self.get_unique_id()
+    fn handle<'a>(&'a mut self, _: GetUniqueKey<Key>, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move { self.next_key().await })
     }
 }
 
 impl<Key> AsyncAsk<InsertRecord<Key>> for TreeActor
 where
-    Key: PrimaryKey,
+    Key: PrimaryKey + AutoIncrement,
 {
-    type Output = InsertSuccess<Key>;
+    type Output = anyhow::Result<InsertSuccess<Key>>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle<'a>(&'a mut self, msg: InsertRecord<Key>, _: &mut Ctx<Self>) -> Self::Future<'a> {
-        let key = if let Some(max) = self.max.as_ref() {
-            let mut key: Key = bincode::deserialize(max).unwrap();
-            key.increment()
-        } else {
-            // TODO(Alec): Implementing a hack because i just want to move forward
-            //             with my life. Scan the entire memtable to find the largest
-            //             ID key. This shouldn't be what we actual use if we ever
-            //             move to production
-            if self.memtable.is_empty() {
-                Key::default()
-            } else {
-                // This is where the hack is (is this really a hack tho...)
-                let (max_key, _) = self.memtable.get_last().unwrap();
-                let mut key: Key = bincode::deserialize(&max_key).unwrap();
-                key.increment()
-            }
-        };
-        let wal = self.wal.clone();
-        let table = self.name.clone();
-        let serailize_key: Vec<u8> = bincode::serialize(&key).unwrap();
+    fn handle<'a>(&'a mut self, msg: InsertRecord<Key>, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
+        Box::pin(async move {
+            // The key is reserved even if the write fails so that it is never reused
+            let key = self.next_key::<Key>().await?;
+            let serialized_key: Vec<u8> = codec::to_vec(&key)?;
+            self.write(serialized_key, Some(msg.value), msg.durability)
+                .await?;
+            Ok(InsertSuccess::new(key))
+        })
+    }
+}
+
+impl AsyncAsk<UpdateRecord> for TreeActor {
+    /// The serialized value the record held before, if it existed
+    type Output = anyhow::Result<Option<Vec<u8>>>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: UpdateRecord, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
+        Box::pin(async move {
+            let old = self.read(msg.key.clone()).await?;
+            self.write(msg.key, Some(msg.value), msg.durability).await?;
+            Ok(old)
+        })
+    }
+}
+
+impl AsyncAsk<DeleteRecord> for TreeActor {
+    /// The serialized value the record held, or `None` if it doesn't exist
+    type Output = anyhow::Result<Option<Vec<u8>>>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: DeleteRecord, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
+        Box::pin(async move {
+            let old = match self.read(msg.key.clone()).await? {
+                Some(old) => old,
+                None => return Ok(None),
+            };
+            self.write(msg.key, None, msg.durability).await?;
+            Ok(Some(old))
+        })
+    }
+}
 
-        self.memtable
-            .insert(serailize_key.clone(), self.version, Some(msg.value.clone()));
+impl AsyncAsk<ApplyBatch> for TreeActor {
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-        self.max = Some(serailize_key.clone());
-        let write_enabled: bool = self.write_enabled;
-        let version = self.version;
+    fn handle<'a>(&'a mut self, msg: ApplyBatch, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
         Box::pin(async move {
-            if write_enabled {
-                if let Err(err) = wal.write(table, version, serailize_key, msg.value).await {
-                    println!("{err}");
-                    println!("Insertion failed to succeed")
+            let ApplyBatch {
+                writes,
+                reads,
+                prepared,
+                committed,
+            } = msg;
+            for (key, sequence) in reads {
+                if self.written_at(&key) != sequence {
+                    let _ = prepared.send(Err(TransactionConflict::new(&self.name).into()));
+                    return Ok(());
                 }
             }
-            InsertSuccess::new(key)
+            let items = writes
+                .iter()
+                .map(|(key, value)| {
+                    Item::new(self.name.clone(), self.version, key.clone(), value.clone())
+                })
+                .collect();
+            if prepared.send(Ok(items)).is_err() {
+                anyhow::bail!("Batch was dropped before it was written to {}", self.name);
+            }
+            if committed.await != Ok(true) {
+                anyhow::bail!("Batch failed to be written to the WAL");
+            }
+            self.apply(writes).await;
+            Ok(())
         })
     }
 }
 
-impl AsyncAsk<UpdateRecord> for TreeActor {
-    type Output = ();
+impl AsyncAsk<ReadRecord> for TreeActor {
+    /// The serialized value of the record, and the sequence of the last write
+    /// to it
+    type Output = anyhow::Result<(Option<Vec<u8>>, u64)>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle<'a>(&'a mut self, msg: UpdateRecord, _: &mut Ctx<Self>) -> Self::Future<'a> {
-        let wal = self.wal.clone();
-        let table = self.name.clone();
-        self.memtable
-            .insert(msg.key.clone(), self.version, Some(msg.value.clone()));
-        let write_enabled: bool = self.write_enabled;
-        let version = self.version;
+    fn handle<'a>(&'a mut self, msg: ReadRecord, _: &mut Ctx<Self>) -> Self::Future<'a> {
         Box::pin(async move {
-            if write_enabled {
-                if let Err(err) = wal.write(table, version, msg.key, msg.value).await {
-                    println!("{err}");
-                    println!("Insertion failed to succeed")
+            // Reading a record can upgrade it, which writes it back
+            let value = self.read(msg.key.clone()).await?;
+            Ok((value, self.written_at(&msg.key)))
+        })
+    }
+}
+
+impl AsyncAsk<ModifyRecord> for TreeActor {
+    /// The old and new serialized value of the record, or `None` if the record
+    /// doesn't exist
+    type Output = anyhow::Result<Option<(Vec<u8>, Vec<u8>)>>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: ModifyRecord, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
+        Box::pin(async move {
+            let old = match self.read(msg.key.clone()).await? {
+                Some(old) => old,
+                None => return Ok(None),
+            };
+            let new = (msg.modify)(&old)?;
+            if let Some((prepared, confirmed)) = msg.confirm {
+                if prepared.send(new.clone()).is_err() || confirmed.await != Ok(true) {
+                    anyhow::bail!("Modify of a record of {} was cancelled", self.name);
                 }
             }
+            self.write(msg.key, Some(new.clone()), msg.durability)
+                .await?;
+            Ok(Some((old, new)))
+        })
+    }
+}
+
+impl AsyncAsk<CompareAndSwap> for TreeActor {
+    /// `true` if the value was written
+    type Output = anyhow::Result<bool>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: CompareAndSwap, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        self.maybe_compact(ctx);
+        Box::pin(async move {
+            if !self.is_unchanged(&msg.key, msg.expected.as_ref())? {
+                return Ok(false);
+            }
+            self.write(msg.key, Some(msg.value), msg.durability).await?;
+            Ok(true)
         })
     }
 }
@@ -169,11 +540,11 @@ impl<Key: PrimaryKey, Value: RecordValue> AsyncAsk<GetRecord<Key, Value>> for Tr
         msg: GetRecord<Key, Value>,
         ctx: &mut Ctx<Self>,
     ) -> Self::Future<'a> {
-        let option = self.memtable.get(&msg.key);
-        if option.is_none() {
-            return Box::pin(async move { Ok(None) });
-        }
-        let record = option.unwrap();
+        let record = match self.get_record(&msg.key) {
+            Ok(Some(record)) => record,
+            Ok(None) => return Box::pin(async move { Ok(None) }),
+            Err(err) => return Box::pin(async move { Err(err) }),
+        };
         if record.version == self.version {
             Box::pin(async move { Ok(Some(serde_json::from_slice(&record.data).unwrap())) })
         } else {
@@ -184,8 +555,7 @@ impl<Key: PrimaryKey, Value: RecordValue> AsyncAsk<GetRecord<Key, Value>> for Tr
                 // 1. Upgrade value to latest version
                 let (key, value) = self.upgrade(msg.key, record.data, record.version).await?;
                 // 2. Update record to reflect latest version
-                addr.async_ask(UpdateRecord::new(key.clone(), value))
-                    .await?;
+                self.write(key.clone(), Some(value), None).await?;
                 // 3. Get the newly updated record
                 addr.async_ask(GetRecord::<Key, Value>::new(key)).await?
             })
@@ -199,13 +569,14 @@ impl AsyncAsk<ListEnd> for TreeActor {
 
     fn handle<'a>(&'a mut self, msg: ListEnd, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
         let option = match msg {
-            ListEnd::Head => self.memtable.get_first(),
-            ListEnd::Tail => self.memtable.get_last(),
+            ListEnd::Head => self.entries(false).next(),
+            ListEnd::Tail => self.entries(true).next(),
+        };
+        let (key, value_opt) = match option {
+            None => return Box::pin(async { Ok(None) }),
+            Some(Err(err)) => return Box::pin(async { Err(err) }),
+            Some(Ok(entry)) => entry,
         };
-        if option.is_none() {
-            return Box::pin(async { Ok(None) });
-        }
-        let (key, value_opt) = option.unwrap();
         if value_opt.is_none() {
             return Box::pin(async move { Ok(Some(Record::new(key, None))) });
         }
@@ -219,8 +590,7 @@ impl AsyncAsk<ListEnd> for TreeActor {
                 println!("Upgrading");
                 let (key, value) = self.upgrade(key, value.data, value.version).await?;
                 // 2. Update record to reflect latest version
-                addr.async_ask(UpdateRecord::new(key.clone(), value))
-                    .await?;
+                self.write(key.clone(), Some(value), None).await?;
                 // 3. Return the result
                 addr.async_ask(msg).await?
             })
@@ -228,35 +598,60 @@ impl AsyncAsk<ListEnd> for TreeActor {
     }
 }
 
-impl AsyncAsk<GetMemTableSnapshot> for TreeActor {
+impl AsyncAsk<GetRange> for TreeActor {
     type Output = anyhow::Result<Vec<Record>>;
     type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle<'a>(&'a mut self, _: GetMemTableSnapshot, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
-        let addr = ctx.address();
-        // let max = self.version;
-        // let versions = self.versions.clone();
-        // let snapshot = self.memtable.as_sorted_vec();
+    fn handle<'a>(&'a mut self, msg: GetRange, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        Box::pin(async move {
+            let entries = self
+                .entries_in(msg.range, msg.reverse)
+                .filter(|entry| !matches!(entry, Ok((_, None))))
+                .take(msg.limit.unwrap_or(usize::MAX))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            self.records(entries).await
+        })
+    }
+}
+
+impl AsyncAsk<RestoreTables> for TreeActor {
+    type Output = anyhow::Result<()>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: RestoreTables, _: &mut Ctx<Self>) -> Self::Future<'a> {
         Box::pin(async move {
-            let mut list = Vec::with_capacity(self.memtable.len());
-            for (key, value) in self.memtable.as_iter() {
-                let key = key.clone();
-                if let Some(mem) = value {
-                    if self.version == mem.version {
-                        list.push(Record::new(key, Some(mem.data.clone())))
-                    } else {
-                        let (key, value) = self.upgrade(key, mem.data.clone(), mem.version).await?;
-                        // 2. Update record to reflect latest version
-                        addr.async_ask(UpdateRecord::new(key.clone(), value.clone()))
-                            .await
-                            .unwrap();
-                        list.push(Record::new(key, Some(value)))
-                    }
-                } else {
-                    list.push(Record::new(key, None));
+            let RestoreTables {
+                fs,
+                manifest,
+                cache,
+                levels,
+                sequence,
+            } = msg;
+            self.reserved = sequence;
+            self.levels = Vec::with_capacity(levels.len().max(1));
+            for (level, mut ids) in levels.into_iter().enumerate() {
+                // The manifest lists level 0 from oldest to newest
+                if level == 0 {
+                    ids.reverse();
                 }
+                let mut tables = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let file = fs
+                        .open(OpenFileOptions::new(table_file_name(id)).read())
+                        .await?;
+                    tables.push(Table::open(id, file)?.with_cache(cache.clone()));
+                }
+                self.levels.push(tables);
+            }
+            if self.levels.is_empty() {
+                self.levels.push(Vec::new());
             }
-            Ok(list)
+            self.storage = Some(TableStorage {
+                fs,
+                manifest,
+                cache,
+            });
+            Ok(())
         })
     }
 }
@@ -283,11 +678,53 @@ impl Handler<Item> for TreeActor {
 impl Ask<RestoreComplete> for TreeActor {
     type Result = ();
 
-    fn handle(&mut self, _: RestoreComplete, _: &mut Ctx<Self>) -> Self::Result {
+    fn handle(&mut self, _: RestoreComplete, ctx: &mut Ctx<Self>) -> Self::Result {
         // Basically just take all of the subtrees messages. When this happen, the subtree
         // will stop restoring messages and move into a ready state.
         let _ = self.sub_trees.take();
         self.write_enabled = true;
+        self.maybe_compact(ctx);
+    }
+}
+
+impl Handler<Compacted> for TreeActor {
+    fn handle(&mut self, msg: Compacted, ctx: &mut Ctx<Self>) {
+        self.compacting = false;
+        let Compacted {
+            level,
+            inputs,
+            outputs,
+        } = msg;
+        match outputs {
+            Ok(outputs) => {
+                for tables in self.levels.iter_mut() {
+                    tables.retain(|table| !inputs.contains(&table.id()));
+                }
+                if self.levels.len() <= level {
+                    self.levels.resize_with(level + 1, Vec::new);
+                }
+                self.levels[level].extend(outputs);
+
+                // The manifest no longer knows about the inputs, so nothing will
+                // ever read them again
+                if let Some(storage) = self.storage.as_ref() {
+                    let fs = storage.fs.clone();
+                    ctx.anonymous_task(async move {
+                        for id in inputs {
+                            if let Err(err) = fs.remove_file(table_file_name(id)).await {
+                                println!("Failed to remove compacted table {}: {}", id, err);
+                            }
+                        }
+                    });
+                }
+            }
+            Err(err) => {
+                println!("{err}");
+                println!("Failed to compact the tables of {}", self.name);
+                return;
+            }
+        }
+        self.maybe_compact(ctx);
     }
 }
 
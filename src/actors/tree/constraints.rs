@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use crate::{Constraint, ConstraintViolation};
+
+use super::RecordValue;
+
+type BoxedConstraint<Value> = Box<dyn Constraint<Value> + Send + Sync>;
+
+/// Every constraint of a tree. Values are checked against all of them before
+/// they are written.
+pub struct Constraints<Value: RecordValue> {
+    constraints: Arc<Vec<BoxedConstraint<Value>>>,
+}
+
+impl<Value: RecordValue> Constraints<Value> {
+    pub fn new(constraints: Vec<BoxedConstraint<Value>>) -> Self {
+        Self {
+            constraints: Arc::new(constraints),
+        }
+    }
+
+    /// Fails with a [`ConstraintViolation`] naming the first constraint that
+    /// rejects the value
+    pub fn check(&self, tree: &str, value: &Value) -> anyhow::Result<()> {
+        match self.constraints.iter().find(|c| !c.check(value)) {
+            Some(constraint) => Err(ConstraintViolation::new(tree, constraint.name()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<Value: RecordValue> Default for Constraints<Value> {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl<Value: RecordValue> Clone for Constraints<Value> {
+    fn clone(&self) -> Self {
+        Self {
+            constraints: Arc::clone(&self.constraints),
+        }
+    }
+}
+
+impl<Value: RecordValue> std::fmt::Debug for Constraints<Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.constraints.iter().map(|c| c.name()))
+            .finish()
+    }
+}
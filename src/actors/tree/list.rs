@@ -1,29 +1,103 @@
-use crate::Tree;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    marker::PhantomData,
+    ops::Bound,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use super::{record_bin_to_value, PrimaryKey, Record, RecordValue};
+use futures::Stream;
+use tokactor::ActorRef;
 
+use super::{record_bin_to_value, GetRange, PrimaryKey, Record, RecordValue, TreeActor};
+
+/// Number of records read from the tree at a time
+const DEFAULT_BATCH_SIZE: usize = 256;
+
+type Batch = Pin<Box<dyn Future<Output = anyhow::Result<Vec<Record>>> + Send>>;
+
+/// Stream every record of a tree from the smallest key to the largest key.
+/// Records are read from the tree one batch at a time when the stream runs out
+/// of records, so only a single batch is ever held in memory. Records written
+/// after the stream is created may or may not be returned.
 pub struct ListStream<Key: PrimaryKey, Value: RecordValue> {
-    _tree: Tree<Key, Value>,
-    mem_table_snapshot: Vec<Record>,
-    index: usize,
+    tree: ActorRef<TreeActor>,
+    batch_size: usize,
+    /// Key of the last record that was read from the tree
+    cursor: Option<Vec<u8>>,
+    records: VecDeque<Record>,
+    pending: Option<Batch>,
+    done: bool,
+    _types: PhantomData<fn() -> (Key, Value)>,
 }
 
 impl<Key: PrimaryKey, Value: RecordValue> ListStream<Key, Value> {
-    pub async fn new(_tree: Tree<Key, Value>) -> Self {
-        let snapshot = _tree.get_mem_table_snapshot().await.unwrap();
+    pub fn new(tree: ActorRef<TreeActor>) -> Self {
         Self {
-            _tree,
-            mem_table_snapshot: snapshot,
-            index: 0,
+            tree,
+            batch_size: DEFAULT_BATCH_SIZE,
+            cursor: None,
+            records: VecDeque::new(),
+            pending: None,
+            done: false,
+            _types: PhantomData,
         }
     }
 
-    pub async fn next(&mut self) -> Option<(Key, Option<Value>)> {
-        let result = self
-            .mem_table_snapshot
-            .get(self.index)
-            .map(record_bin_to_value);
-        self.index += 1;
-        result
+    /// Set the number of records read from the tree at a time
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn next_batch(&self) -> Batch {
+        let start = match &self.cursor {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+        let msg = GetRange::new((start, Bound::Unbounded), false, Some(self.batch_size));
+        let tree = self.tree.clone();
+        Box::pin(async move { tree.async_ask(msg).await? })
+    }
+}
+
+impl<Key: PrimaryKey, Value: RecordValue> Stream for ListStream<Key, Value> {
+    type Item = anyhow::Result<(Key, Value)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(record) = self.records.pop_front() {
+                if let (key, Some(value)) = record_bin_to_value(&record) {
+                    return Poll::Ready(Some(Ok((key, value))));
+                }
+                continue;
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let batch = match &mut self.pending {
+                Some(batch) => batch,
+                None => {
+                    let batch = self.next_batch();
+                    self.pending.insert(batch)
+                }
+            };
+            let result = futures::ready!(batch.as_mut().poll(cx));
+            self.pending = None;
+            match result {
+                Ok(records) => {
+                    // A short batch means that the end of the tree was reached
+                    self.done = records.len() < self.batch_size;
+                    self.cursor = records.last().map(|record| record.key.clone());
+                    self.records = records.into();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
     }
 }
@@ -1,6 +1,11 @@
-use std::collections::{btree_map::Iter, BTreeMap};
+use std::collections::{
+    btree_map::{Iter, Range},
+    BTreeMap,
+};
 
-#[derive(Debug, Clone)]
+use crate::actors::table::KeyRange;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct MemRecord {
     pub version: u16,
     pub data: Vec<u8>,
@@ -21,46 +26,25 @@ impl MemTable {
 
     pub fn insert(&mut self, key: Vec<u8>, version: u16, value: Option<Vec<u8>>) -> usize {
         let key_len = key.len();
-        let value_len = if value.is_none() {
-            1
-        } else {
-            1 + value.as_ref().unwrap().len()
-        };
+        let value_len = 1 + value.as_ref().map(Vec::len).unwrap_or(0);
         self.size += key_len + value_len;
         let record = value.map(|data| MemRecord { version, data });
         self.map.insert(key, record);
         self.size
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<MemRecord> {
-        match self.map.get(key) {
-            Some(value) => value.clone(),
-            None => None,
-        }
-    }
-
-    pub fn get_first(&self) -> Option<(Vec<u8>, Option<MemRecord>)> {
-        self.map
-            .first_key_value()
-            .map(|(k, v)| (k.clone(), v.clone()))
-    }
-
-    /// Largest value in the tree
-    pub fn get_last(&self) -> Option<(Vec<u8>, Option<MemRecord>)> {
-        self.map
-            .last_key_value()
-            .map(|(k, v)| (k.clone(), v.clone()))
+    /// Find the entry of a key. Returns `None` if the memtable doesn't know about
+    /// the key and `Some(None)` if the key was deleted.
+    pub fn get(&self, key: &[u8]) -> Option<Option<MemRecord>> {
+        self.map.get(key).cloned()
     }
 
     pub fn as_iter(&self) -> Iter<'_, Vec<u8>, Option<MemRecord>> {
         self.map.iter()
     }
 
-    pub fn len(&self) -> usize {
-        self.map.len()
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+    /// Every entry with a key inside of the range. The range must not be empty.
+    pub fn range(&self, range: KeyRange) -> Range<'_, Vec<u8>, Option<MemRecord>> {
+        self.map.range(range)
     }
 }
@@ -2,18 +2,29 @@ use std::{any::Any, marker::PhantomData};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::AutoIncrement;
+use tokactor::ActorRef;
+use tokio::sync::oneshot;
 
+use crate::{
+    actors::{
+        fs::FileSystemFacade,
+        manifest::Manifest,
+        table::{BlockCache, KeyRange},
+        wal::{Durability, Item},
+    },
+    AutoIncrement,
+};
+
+/// A type that can be used as the key of a tree. Keys are stored in the same
+/// order as `Ord` orders them. Only keys that implement [`AutoIncrement`] can be
+/// generated by the tree, every other key is picked by the caller.
 pub trait PrimaryKey:
-    Serialize
-    + DeserializeOwned
-    + AutoIncrement
-    + Any
-    + std::fmt::Debug
-    + Default
-    + Send
-    + Sync
-    + 'static
+    Serialize + DeserializeOwned + Ord + Clone + Any + std::fmt::Debug + Send + Sync + 'static
+{
+}
+
+impl<T> PrimaryKey for T where
+    T: Serialize + DeserializeOwned + Ord + Clone + Any + std::fmt::Debug + Send + Sync + 'static
 {
 }
 
@@ -28,16 +39,18 @@ impl<T> RecordValue for T where
 }
 
 #[derive(Debug)]
-pub struct InsertRecord<Key: PrimaryKey> {
+pub struct InsertRecord<Key: PrimaryKey + AutoIncrement> {
     _key: PhantomData<Key>,
     pub value: Vec<u8>,
+    pub durability: Option<Durability>,
 }
 
-impl<Key: PrimaryKey> InsertRecord<Key> {
-    pub fn new(value: Vec<u8>) -> Self {
+impl<Key: PrimaryKey + AutoIncrement> InsertRecord<Key> {
+    pub fn new(value: Vec<u8>, durability: Option<Durability>) -> Self {
         Self {
             _key: PhantomData,
             value,
+            durability,
         }
     }
 }
@@ -56,11 +69,146 @@ impl<Key: PrimaryKey> InsertSuccess<Key> {
 pub struct UpdateRecord {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
+    pub durability: Option<Durability>,
 }
 
 impl UpdateRecord {
-    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
-        Self { key, value }
+    pub fn new(key: Vec<u8>, value: Vec<u8>, durability: Option<Durability>) -> Self {
+        Self {
+            key,
+            value,
+            durability,
+        }
+    }
+}
+
+/// Turns the serialized value of a record into its new serialized value
+pub type Modify = Box<dyn FnOnce(&[u8]) -> anyhow::Result<Vec<u8>> + Send + Sync>;
+
+/// Change the value of a record inside of a single turn of the tree, so no
+/// other write can happen between reading the record and writing it back
+pub struct ModifyRecord {
+    pub key: Vec<u8>,
+    pub modify: Modify,
+    pub durability: Option<Durability>,
+    /// Hands the new value out and waits to be told if it can be written
+    pub confirm: Option<(oneshot::Sender<Vec<u8>>, oneshot::Receiver<bool>)>,
+}
+
+impl ModifyRecord {
+    pub fn new(key: Vec<u8>, modify: Modify, durability: Option<Durability>) -> Self {
+        Self {
+            key,
+            modify,
+            durability,
+            confirm: None,
+        }
+    }
+
+    /// Send the new value to `prepared` and only write it once `confirmed`
+    /// receives `true`
+    pub fn confirm_with(
+        mut self,
+        prepared: oneshot::Sender<Vec<u8>>,
+        confirmed: oneshot::Receiver<bool>,
+    ) -> Self {
+        self.confirm = Some((prepared, confirmed));
+        self
+    }
+}
+
+impl std::fmt::Debug for ModifyRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModifyRecord")
+            .field("key", &self.key)
+            .field("durability", &self.durability)
+            .finish()
+    }
+}
+
+/// Write a value only if the record still holds the expected serialized value.
+/// An expected value of `None` means that the record must not exist.
+#[derive(Debug)]
+pub struct CompareAndSwap {
+    pub key: Vec<u8>,
+    pub expected: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+    pub durability: Option<Durability>,
+}
+
+impl CompareAndSwap {
+    pub fn new(
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+        durability: Option<Durability>,
+    ) -> Self {
+        Self {
+            key,
+            expected,
+            value,
+            durability,
+        }
+    }
+}
+
+/// Write a tombstone that hides every older value of the key. Nothing is
+/// written if the key doesn't hold a value.
+#[derive(Debug)]
+pub struct DeleteRecord {
+    pub key: Vec<u8>,
+    pub durability: Option<Durability>,
+}
+
+impl DeleteRecord {
+    pub fn new(key: Vec<u8>, durability: Option<Durability>) -> Self {
+        Self { key, durability }
+    }
+}
+
+/// Apply the writes of a batch once the batch has been committed to the WAL.
+/// The tree checks that none of the records read by the batch have changed,
+/// hands back the WAL items for its writes and then doesn't handle any other
+/// message until it learns if the batch was committed, so readers never see
+/// part of a batch.
+#[derive(Debug)]
+pub struct ApplyBatch {
+    pub writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// Keys that were read by the batch, and the sequence of the last write to
+    /// them when they were read
+    pub reads: Vec<(Vec<u8>, u64)>,
+    pub prepared: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    /// Receives `true` once the batch is in the WAL, or `false` if it failed
+    pub committed: oneshot::Receiver<bool>,
+}
+
+impl ApplyBatch {
+    pub fn new(
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        reads: Vec<(Vec<u8>, u64)>,
+        prepared: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+        committed: oneshot::Receiver<bool>,
+    ) -> Self {
+        Self {
+            writes,
+            reads,
+            prepared,
+            committed,
+        }
+    }
+}
+
+/// Get the serialized value of a record, upgraded to the latest version of the
+/// tree, along with the sequence of the last write to it. The sequence changes
+/// with every write, even one that writes back a value the record held before.
+#[derive(Debug)]
+pub struct ReadRecord {
+    pub key: Vec<u8>,
+}
+
+impl ReadRecord {
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
     }
 }
 
@@ -100,13 +248,44 @@ impl Record {
 }
 impl Record {}
 
+/// Get the records with a key inside of a range. Deleted records are skipped.
 #[derive(Debug)]
-pub struct GetMemTableSnapshot;
+pub struct GetRange {
+    pub range: KeyRange,
+    /// Return records from the largest key to the smallest key
+    pub reverse: bool,
+    /// Largest number of records to return
+    pub limit: Option<usize>,
+}
+
+impl GetRange {
+    pub fn new(range: KeyRange, reverse: bool, limit: Option<usize>) -> Self {
+        Self {
+            range,
+            reverse,
+            limit,
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct GetUniqueKey<Key: PrimaryKey>(PhantomData<Key>);
-impl<Key: PrimaryKey> Default for GetUniqueKey<Key> {
+pub struct GetUniqueKey<Key: PrimaryKey + AutoIncrement>(PhantomData<Key>);
+impl<Key: PrimaryKey + AutoIncrement> Default for GetUniqueKey<Key> {
     fn default() -> Self {
         Self(PhantomData)
     }
 }
+
+/// Open the table files that hold the flushed memtables of the tree
+#[derive(Debug)]
+pub struct RestoreTables {
+    /// Directory that stores all of the table files
+    pub fs: FileSystemFacade,
+    pub manifest: ActorRef<Manifest>,
+    pub cache: BlockCache,
+    /// Table files of the tree split into levels, in the order the manifest
+    /// lists them
+    pub levels: Vec<Vec<u64>>,
+    /// Last auto-increment key that the tree reserved, if it ever reserved one
+    pub sequence: Option<Vec<u8>>,
+}
@@ -1,33 +1,54 @@
 mod actor;
+mod constraints;
 mod list;
 mod memtable;
 mod messages;
 
-use std::sync::Arc;
+use std::{
+    future::Future,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 
 pub use actor::*;
+pub use memtable::MemRecord;
 pub use messages::*;
+use serde::Serialize;
 use tokactor::{Actor, ActorRef, Ctx, DeadActorResult, Handler};
-use tokio::{sync::RwLock, task::JoinSet};
+use tokio::{
+    sync::{oneshot, OwnedRwLockReadGuard, RwLock},
+    task::JoinSet,
+};
 
-use self::list::ListStream;
+pub use self::constraints::Constraints;
+pub use self::list::ListStream;
+
+use crate::{codec, AutoIncrement, KeyAlreadyExists, KeyPrefix};
 
 use super::{
+    compaction::Compactor,
     db::TreeVersion,
-    subtree::{SubTreeRestorer, SubTreeSubscriber},
-    wal::Wal,
+    subtree::{Backfilled, Claims, SubTreeRestorer, SubTreeSubscriber, UniqueGuard},
+    table::TableOptions,
+    wal::{Durability, Wal},
 };
 
+/// Records read by a backfill while the writes of the tree are held
+const BACKFILL_PAGE_SIZE: usize = 256;
+
 pub fn tree_actor<A>(
     name: String,
     versions: Vec<TreeVersion>,
     wal: Wal,
+    memtable_size: usize,
+    table_options: TableOptions,
+    compactor: Compactor,
     ctx: &mut Ctx<A>,
 ) -> ActorRef<TreeActor>
 where
     A: Actor + Handler<DeadActorResult<TreeActor>>,
 {
-    let tree = TreeActor::new(name, versions, wal);
+    let tree = TreeActor::new(name, versions, wal, memtable_size, table_options, compactor);
     ctx.spawn(tree)
 }
 
@@ -37,8 +58,20 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
+    name: String,
     inner: ActorRef<TreeActor>,
+    /// Only changed while `writes` is held exclusively, so reading it with a
+    /// write permit never waits
     subscribers: Arc<RwLock<Vec<SubTreeSubscriber<Key, Value>>>>,
+    /// Unique indexes that every write has to claim its identities from
+    guards: Arc<RwLock<Vec<UniqueGuard<Key, Value>>>>,
+    /// Every write holds a permit from before it reads the subscribers of the
+    /// tree until it has been applied. A new subscriber takes the lock for
+    /// itself, so each write is either read by its backfill or tells it about
+    /// the change, never both.
+    writes: Arc<RwLock<()>>,
+    constraints: Constraints<Value>,
+    durability: Option<Durability>,
 }
 
 impl<Key, Value> Tree<Key, Value>
@@ -46,35 +79,54 @@ where
     Key: PrimaryKey,
     Value: RecordValue,
 {
-    pub fn new(inner: ActorRef<TreeActor>) -> Self {
+    pub fn new(name: String, inner: ActorRef<TreeActor>) -> Self {
         Self {
+            name,
             inner,
             subscribers: Arc::new(RwLock::new(vec![])),
+            guards: Arc::new(RwLock::new(vec![])),
+            writes: Arc::new(RwLock::new(())),
+            constraints: Constraints::default(),
+            durability: None,
+        }
+    }
+
+    pub(crate) fn with_constraints(mut self, constraints: Constraints<Value>) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    /// Get a handle to the same tree whose writes use `durability` instead of
+    /// the default durability of the database. Useful for overriding the
+    /// durability of a single write, or for tables that are only a cache.
+    pub fn with_durability(&self, durability: Durability) -> Self {
+        Self {
+            durability: Some(durability),
+            ..self.duplicate()
         }
     }
 
     /// Insert a value into the database. On insert, the key and value are shared
-    /// between all actors
+    /// between all actors. The key is generated by the tree, use [`Tree::put`] or
+    /// [`Tree::insert_new`] to pick the key.
     pub async fn insert(&self, value: Value) -> anyhow::Result<Key>
     where
-        Key: PrimaryKey,
+        Key: AutoIncrement,
     {
-        let key = self.get_unique_key().await.unwrap();
-        let id = bincode::serialize(&key)?;
+        self.check(&value)?;
         let json = serde_json::to_vec(&value)?;
-        let record = UpdateRecord::new(id, json);
-
-        let arc_key = Arc::new(key.clone());
+        let record = InsertRecord::<Key>::new(json, self.durability);
         let arc_value = Arc::new(value);
-        let subscribers = self
-            .subscribers
-            .try_read()
-            .unwrap()
-            .iter()
-            .map(Clone::clone)
-            .collect::<Vec<_>>();
+        let claims = self.claim(None, &arc_value).await?;
 
-        self.inner.async_ask(record).await.unwrap();
+        // The key is picked and the value written by the same message, so no
+        // other write can land in between
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        let result = self.inner.async_ask(record).await;
+        drop(permit);
+        let InsertSuccess { key } = claims.release_on_err(flatten(result)).await?;
+        let arc_key = Arc::new(key.clone());
         // TODO(Alec): I know, I know, we should be doing something in between
         //             aware blocks but in this case it's ok, i swear!!!
         let mut set = JoinSet::new();
@@ -84,66 +136,239 @@ where
             set.spawn(subscriber.created(key, value));
         }
         while let Some(res) = set.join_next().await {
-            res?.unwrap();
+            res??;
         }
 
         Ok(key)
     }
 
-    pub async fn update(&self, id: impl Into<Key>, value: Value) -> anyhow::Result<()> {
+    /// Insert a value under a key picked by the caller. Fails with
+    /// [`KeyAlreadyExists`] without writing anything if the key already holds a
+    /// value.
+    pub async fn insert_new(&self, id: impl Into<Key>, value: Value) -> anyhow::Result<()> {
+        self.check(&value)?;
         let key = id.into();
-        let old = self.get(key.clone()).await?.map(Arc::new);
+        let msg = CompareAndSwap::new(
+            codec::to_vec(&key)?,
+            None,
+            serde_json::to_vec(&value)?,
+            self.durability,
+        );
+        let arc_value = Arc::new(value);
+        let claims = self.claim(Some(&key), &arc_value).await?;
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        let result = self.inner.async_ask(msg).await;
+        drop(permit);
+        if !claims.release_unless_written(flatten(result)).await? {
+            return Err(KeyAlreadyExists::new(&self.name, &key).into());
+        }
+
+        let arc_key = Arc::new(key);
+        let created = subscribers
+            .into_iter()
+            .map(|subscriber| subscriber.created(arc_key.clone(), arc_value.clone()));
+        futures::future::try_join_all(created).await?;
+        Ok(())
+    }
+
+    /// Replace the value of a key
+    pub async fn update(&self, id: impl Into<Key>, value: Value) -> anyhow::Result<()> {
+        self.put(id, value).await
+    }
 
-        let id = bincode::serialize(&key)?;
+    /// Write a value under a key picked by the caller, replacing the value the
+    /// key held if there was one. Every index and aggregate of the tree is told
+    /// about the write before this returns.
+    pub async fn put(&self, id: impl Into<Key>, value: Value) -> anyhow::Result<()> {
+        self.check(&value)?;
+        let key = id.into();
+        let id = codec::to_vec(&key)?;
         let json = serde_json::to_vec(&value)?;
 
-        let arc_key = Arc::new(key.clone());
         let arc_value = Arc::new(value);
-        let subscribers = self
-            .subscribers
-            .try_read()
-            .unwrap()
-            .iter()
-            .map(Clone::clone)
-            .collect::<Vec<_>>();
-
-        let record = UpdateRecord::new(id, json);
-        self.inner.async_ask(record).await.unwrap();
-
-        // UGGGHHH, this should be done within a transaction! Yes! One change
-        // can lead to many more changes happening, but ALL WE CARE ABOUT IS THAT
-        // OUR WRITE SUCCEEDED.
-        //
-        // We should be able to wait for all updates to complete though from within
-        // the transaction.
-        //
-        // We do need to figure out a better way to do this, but maybe we just do
-        // versioning first
-        tokio::spawn(async move {
-            // TODO(Alec): I know, I know, we should be doing something in between
-            //             aware blocks but in this case it's ok, i swear!!!
-            let mut set = JoinSet::new();
-            for subscriber in subscribers.into_iter() {
-                let key = arc_key.clone();
-                let value = arc_value.clone();
-                let old = old.clone();
-                set.spawn(async move { subscriber.updated(key, old, value).await });
-            }
-            while let Some(res) = set.join_next().await {
-                res.unwrap().unwrap();
-            }
+        let claims = self.claim(Some(&key), &arc_value).await?;
+
+        let record = UpdateRecord::new(id, json, self.durability);
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        // The old value is read by the same message that replaces it, so no
+        // other write can change it in between
+        let result = self.inner.async_ask(record).await;
+        drop(permit);
+        let old = claims.release_on_err(flatten(result)).await?;
+        let old = old.map(|old| serde_json::from_slice(&old)).transpose()?;
+        Self::notify_updated(subscribers, key, old, &arc_value).await
+    }
+
+    /// Change a record in place. `modify` runs inside of the tree, so no other
+    /// write to the tree can happen between reading the record and writing it
+    /// back. Returns the new value, or `None` without calling `modify` if the
+    /// record doesn't exist.
+    pub async fn modify<F>(&self, id: impl Into<Key>, modify: F) -> anyhow::Result<Option<Value>>
+    where
+        F: FnOnce(&mut Value) + Send + Sync + 'static,
+    {
+        let key = id.into();
+        let (tree, constraints) = (self.name.clone(), self.constraints.clone());
+        let modify = Box::new(move |bytes: &[u8]| {
+            let mut value: Value = serde_json::from_slice(bytes)?;
+            modify(&mut value);
+            constraints.check(&tree, &value)?;
+            Ok(serde_json::to_vec(&value)?)
         });
+        let msg = ModifyRecord::new(codec::to_vec(&key)?, modify, self.durability);
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        let change = if self.guards.read().await.is_empty() {
+            self.inner.async_ask(msg).await??
+        } else {
+            self.modify_claimed(&key, msg).await?
+        };
+        drop(permit);
+        let (old, new) = match change {
+            Some(change) => change,
+            None => return Ok(None),
+        };
+        let old = serde_json::from_slice(&old)?;
+        let new = Arc::new(serde_json::from_slice(&new)?);
+        Self::notify_updated(subscribers, key, Some(old), &new).await?;
+        into_value(new).map(Some)
+    }
+
+    /// Modify a record of a tree that has unique indexes. The new value is only
+    /// known inside of the tree, so the tree waits for its identities to be
+    /// claimed before writing it.
+    async fn modify_claimed(
+        &self,
+        key: &Key,
+        msg: ModifyRecord,
+    ) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (prepared_tx, prepared_rx) = oneshot::channel();
+        let (claimed_tx, claimed_rx) = oneshot::channel();
+        let msg = msg.confirm_with(prepared_tx, claimed_rx);
+        let inner = self.inner.clone();
+        let modified = tokio::spawn(async move { inner.async_ask(msg).await });
+
+        // Returning early drops `claimed_tx`, which cancels the write
+        let claims = match prepared_rx.await {
+            Ok(new) => {
+                let new = Arc::new(serde_json::from_slice(&new)?);
+                Some(self.claim(Some(key), &new).await?)
+            }
+            // The record doesn't exist, or the new value couldn't be made
+            Err(_) => None,
+        };
+        let _ = claimed_tx.send(true);
+        let result = flatten(modified.await?);
+        match claims {
+            Some(claims) => claims.release_on_err(result).await,
+            None => result,
+        }
+    }
+
+    /// Replace the value of a record only if it still holds `expected`, where
+    /// `None` means that the record must not exist yet. Values are compared by
+    /// their serialized bytes, so a record that was written by an older version
+    /// of the tree never matches. Returns `false` without writing anything if
+    /// the record doesn't hold `expected`.
+    pub async fn compare_and_swap(
+        &self,
+        id: impl Into<Key>,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> anyhow::Result<bool> {
+        self.check(&new)?;
+        let key = id.into();
+        let expected = expected.map(serde_json::to_vec).transpose()?;
+        let msg = CompareAndSwap::new(
+            codec::to_vec(&key)?,
+            expected.clone(),
+            serde_json::to_vec(&new)?,
+            self.durability,
+        );
+        let new = Arc::new(new);
+        let claims = self.claim(Some(&key), &new).await?;
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        let result = self.inner.async_ask(msg).await;
+        drop(permit);
+        if !claims.release_unless_written(flatten(result)).await? {
+            return Ok(false);
+        }
+        let old = expected
+            .map(|old| serde_json::from_slice(&old))
+            .transpose()?;
+        Self::notify_updated(subscribers, key, old, &new).await?;
+        Ok(true)
+    }
 
+    /// Tell every index and aggregate of the tree about a write that has been
+    /// made
+    async fn notify_updated(
+        subscribers: Vec<SubTreeSubscriber<Key, Value>>,
+        key: Key,
+        old: Option<Value>,
+        new: &Arc<Value>,
+    ) -> anyhow::Result<()> {
+        let (key, old) = (Arc::new(key), old.map(Arc::new));
+        let updated = subscribers
+            .iter()
+            .map(|subscriber| subscriber.updated(key.clone(), old.clone(), new.clone()));
+        futures::future::try_join_all(updated).await?;
         Ok(())
     }
 
+    /// Delete a record and return the value it held. Nothing is written if the
+    /// record doesn't exist. Every index and aggregate of the tree is told about
+    /// the deleted value before this returns.
+    pub async fn delete(&self, id: impl Into<Key>) -> anyhow::Result<Option<Value>> {
+        let key = id.into();
+        let id = codec::to_vec(&key)?;
+        let record = DeleteRecord::new(id, self.durability);
+        let permit = self.write_permit().await;
+        let subscribers = self.subscribers();
+        // The old value is read by the same message that deletes it, so no
+        // other write can change it in between
+        let old = self.inner.async_ask(record).await??;
+        drop(permit);
+        let old = match old {
+            Some(old) => Arc::new(serde_json::from_slice::<Value>(&old)?),
+            None => return Ok(None),
+        };
+
+        let arc_key = Arc::new(key);
+        let mut set = JoinSet::new();
+        for subscriber in subscribers.into_iter() {
+            set.spawn(subscriber.deleted(arc_key.clone(), old.clone()));
+        }
+        while let Some(res) = set.join_next().await {
+            res??;
+        }
+
+        into_value(old).map(Some)
+    }
+
     pub async fn get(&self, key: impl Into<Key>) -> anyhow::Result<Option<Value>> {
         let key = key.into();
-        let bin = bincode::serialize(&key).unwrap();
+        let bin = codec::to_vec(&key)?;
         let msg = GetRecord::<Key, Value>::new(bin);
         self.inner.async_ask(msg).await?
     }
 
+    /// Fails with a [`crate::ConstraintViolation`] if any constraint of the tree
+    /// rejects the value
+    pub(crate) fn check(&self, value: &Value) -> anyhow::Result<()> {
+        self.constraints.check(&self.name, value)
+    }
+
+    /// The serialized value of a record, as it is stored in the tree, and the
+    /// sequence of the last write to it
+    pub(crate) async fn get_bytes(&self, key: &Key) -> anyhow::Result<(Option<Vec<u8>>, u64)> {
+        let msg = ReadRecord::new(codec::to_vec(key)?);
+        self.inner.async_ask(msg).await?
+    }
+
     pub async fn get_first(&self) -> anyhow::Result<Option<(Key, Option<Value>)>> {
         self.get_head_or_tail(ListEnd::Head).await
     }
@@ -152,32 +377,196 @@ where
         self.get_head_or_tail(ListEnd::Tail).await
     }
 
-    pub async fn list(&self) -> ListStream<Key, Value> {
-        let tree = Self::new(self.inner.clone());
-        ListStream::new(tree).await
+    /// Every record with a key inside of the range, from the smallest key to the
+    /// largest key
+    pub async fn range(&self, range: impl RangeBounds<Key>) -> anyhow::Result<Vec<(Key, Value)>> {
+        self.get_range(range, false, None).await
+    }
+
+    /// Every record with a key inside of the range, from the largest key to the
+    /// smallest key
+    pub async fn range_rev(
+        &self,
+        range: impl RangeBounds<Key>,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        self.get_range(range, true, None).await
+    }
+
+    /// Up to `limit` records starting at `key`, from the smallest key to the
+    /// largest key. Use the key after the last record returned to get the next
+    /// page of records.
+    pub async fn scan_from(
+        &self,
+        key: impl Into<Key>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        let range = (Bound::Included(key.into()), Bound::Unbounded);
+        self.get_range(range, false, Some(limit)).await
+    }
+
+    async fn get_range(
+        &self,
+        range: impl RangeBounds<Key>,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        let encode = |bound: Bound<&Key>| -> anyhow::Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(codec::to_vec(key)?),
+                Bound::Excluded(key) => Bound::Excluded(codec::to_vec(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let range = (encode(range.start_bound())?, encode(range.end_bound())?);
+        self.get_byte_range(range, reverse, limit).await
+    }
+
+    async fn get_byte_range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        let records = self
+            .inner
+            .async_ask(GetRange::new(range, reverse, limit))
+            .await??;
+        Ok(records
+            .iter()
+            .map(record_bin_to_value)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect())
+    }
+
+    /// Every record with a composite key that starts with `prefix`, from the
+    /// smallest key to the largest key
+    pub async fn prefix<P>(&self, prefix: &P) -> anyhow::Result<Vec<(Key, Value)>>
+    where
+        Key: KeyPrefix<P>,
+        P: Serialize,
+    {
+        self.prefix_bytes(codec::to_vec(prefix)?).await
+    }
+
+    /// Every record with a key whose encoding starts with `prefix`
+    pub(crate) async fn prefix_bytes(&self, prefix: Vec<u8>) -> anyhow::Result<Vec<(Key, Value)>> {
+        let end = codec::prefix_end(&prefix);
+        self.get_byte_range((Bound::Included(prefix), end), false, None)
+            .await
+    }
+
+    /// Stream every record of the tree from the smallest key to the largest
+    /// key. Records are read from the tree in batches as the stream is polled.
+    pub fn list(&self) -> ListStream<Key, Value> {
+        ListStream::new(self.inner.clone())
     }
 
     pub async fn register_restorer(&self, restorer: SubTreeRestorer) {
         self.inner.send_async(restorer).await.unwrap();
     }
 
-    pub(crate) fn register_subscriber(&self, subscriber: SubTreeSubscriber<Key, Value>) {
+    /// Stop telling a subscriber about writes
+    pub(crate) async fn unsubscribe(&self, subscriber: &SubTreeSubscriber<Key, Value>) {
+        let _writes = self.writes.write().await;
+        let mut subscribers = self.subscribers.try_write().unwrap();
+        subscribers.retain(|other| !other.is(subscriber));
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn address(&self) -> ActorRef<TreeActor> {
+        self.inner.clone()
+    }
+
+    pub(crate) async fn register_guard(&self, guard: UniqueGuard<Key, Value>) {
+        self.guards.write().await.push(guard);
+    }
+
+    /// Claim the identities of a value that is about to be written from every
+    /// unique index of the tree. Fails with a [`crate::UniqueViolation`] if an
+    /// identity already belongs to another record.
+    pub(crate) fn claim(
+        &self,
+        key: Option<&Key>,
+        value: &Arc<Value>,
+    ) -> impl Future<Output = anyhow::Result<Claims<Key, Value>>> + Send + 'static {
+        let guards = Arc::clone(&self.guards);
+        let (key, value) = (key.cloned().map(Arc::new), value.clone());
+        async move {
+            let guards = guards.read().await.clone();
+            Claims::claim(guards, key, value).await
+        }
+    }
+
+    /// Every index and aggregate that is told about changes to the tree
+    pub(crate) fn subscribers(&self) -> Vec<SubTreeSubscriber<Key, Value>> {
+        let subscribers = self.subscribers.try_read().unwrap();
+        subscribers
+            .iter()
+            .map(|subscriber| subscriber.snapshot())
+            .collect()
+    }
+
+    /// Held by a write from before it reads the subscribers of the tree until
+    /// it has been applied. Subscribers must not be told about the write while
+    /// the permit is held, because a subscriber could be waiting for it.
+    pub(crate) async fn write_permit(&self) -> OwnedRwLockReadGuard<()> {
+        self.writes().read_owned().await
+    }
+
+    pub(crate) fn writes(&self) -> Arc<RwLock<()>> {
+        Arc::clone(&self.writes)
+    }
+
+    /// Subscribe to changes of the tree. Only writes to records that the
+    /// backfill of the subscriber has read are sent to it, see
+    /// [`Tree::backfill_page`].
+    pub(crate) async fn subscribe(&self, subscriber: SubTreeSubscriber<Key, Value>) {
+        let _writes = self.writes.write().await;
         self.subscribers.try_write().unwrap().push(subscriber);
     }
 
+    /// Read the next page of records for the backfill of a subscriber, without
+    /// any write landing in between. Every write is either part of a page or is
+    /// sent to the subscriber, but never both. Returns no records once every
+    /// record has been read.
+    pub(crate) async fn backfill_page(
+        &self,
+        subscriber: &SubTreeSubscriber<Key, Value>,
+    ) -> anyhow::Result<Vec<(Key, Value)>> {
+        let start = match subscriber.backfilled() {
+            Backfilled::Nothing => Bound::Unbounded,
+            Backfilled::Until(key) => Bound::Excluded(key),
+            Backfilled::Everything => return Ok(Vec::new()),
+        };
+        let _writes = self.writes.write().await;
+        let range = (start, Bound::Unbounded);
+        let records = self
+            .get_range(range, false, Some(BACKFILL_PAGE_SIZE))
+            .await?;
+        match records.last() {
+            Some((key, _)) if records.len() == BACKFILL_PAGE_SIZE => {
+                subscriber.set_backfilled(Backfilled::Until(key.clone()))
+            }
+            _ => subscriber.set_backfilled(Backfilled::Everything),
+        }
+        Ok(records)
+    }
+
     pub(crate) fn duplicate(&self) -> Self {
         Self {
+            name: self.name.clone(),
             inner: self.inner.clone(),
             subscribers: Arc::clone(&self.subscribers),
+            guards: Arc::clone(&self.guards),
+            writes: Arc::clone(&self.writes),
+            constraints: self.constraints.clone(),
+            durability: self.durability,
         }
     }
 
-    pub(crate) async fn get_mem_table_snapshot(&self) -> anyhow::Result<Vec<Record>> {
-        let result = self.inner.async_ask(GetMemTableSnapshot).await?;
-        let list = result?;
-        Ok(list)
-    }
-
     async fn get_head_or_tail(&self, end: ListEnd) -> anyhow::Result<Option<(Key, Option<Value>)>> {
         let result = self.inner.async_ask(end).await?;
         if let Some(option) = result? {
@@ -190,16 +579,18 @@ where
     /// Get a unique key that has not been saved to the database. Calling this
     /// will return the current MAX value of the unique key and then increment
     /// the max value of the key.
-    async fn get_unique_key(&self) -> anyhow::Result<Key> {
-        let key = self.inner.ask(GetUniqueKey::<Key>::default()).await?;
-        Ok(key)
+    pub(crate) async fn get_unique_key(&self) -> anyhow::Result<Key>
+    where
+        Key: AutoIncrement,
+    {
+        self.inner.async_ask(GetUniqueKey::<Key>::default()).await?
     }
 }
 
 fn record_bin_to_value<Key: PrimaryKey, Value: RecordValue>(
     record: &Record,
 ) -> (Key, Option<Value>) {
-    let key = bincode::deserialize(&record.key).unwrap();
+    let key = codec::from_slice(&record.key).unwrap();
     if let Some(value) = &record.value {
         let json_value = serde_json::from_slice(value).unwrap();
         (key, Some(json_value))
@@ -207,3 +598,20 @@ fn record_bin_to_value<Key: PrimaryKey, Value: RecordValue>(
         (key, None)
     }
 }
+
+/// Take a value back from the indexes and aggregates that were told about it.
+/// They are done with it, so this is almost always the only reference left.
+fn into_value<Value: RecordValue>(value: Arc<Value>) -> anyhow::Result<Value> {
+    match Arc::try_unwrap(value) {
+        Ok(value) => Ok(value),
+        Err(value) => Ok(serde_json::from_value(serde_json::to_value(&*value)?)?),
+    }
+}
+
+/// Turn the result of asking the tree into a single result
+fn flatten<T, E>(result: Result<anyhow::Result<T>, E>) -> anyhow::Result<T>
+where
+    anyhow::Error: From<E>,
+{
+    result?
+}
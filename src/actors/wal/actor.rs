@@ -1,73 +1,320 @@
 use std::{
-    io::{IoSlice, Write},
-    time::Duration,
+    io::{Read, Write},
+    pin::Pin,
 };
 
 use anyhow::Error;
-use tokactor::{Actor, AnonymousRef, Ask, Ctx, Handler};
+use futures::Future;
+use tokactor::{Actor, ActorRef, AnonymousRef, AsyncAsk, Ctx, Handler};
 use tokio::{sync::oneshot, time::Instant};
 
-use crate::actors::{fs::DbFile, wal::messages::WalRestoredItems};
+use crate::actors::{
+    fs::{DbFile, FileSystemFacade, OpenFileOptions},
+    manifest::{Manifest, ManifestEvent},
+    wal::messages::WalRestoredItems,
+};
 
-use super::messages::{Flush, Insert, WalRestore};
+use super::{
+    frame::{self, WAL_MAGIC},
+    item::Item,
+    messages::{Checkpoint, Flush, Insert, ReadOnly, Rotated, WalRestore},
+    options::{Durability, WalOptions},
+    segment::{segment_file_name, Segments, WalPosition},
+};
 
 struct FlushTask {
     now: Instant,
-    _handle: AnonymousRef,
+    handle: AnonymousRef,
+}
+
+/// Writes that are waiting to be committed to the WAL together
+#[derive(Default)]
+struct Buffer {
+    /// Every batch of items is written to its own frame
+    batches: Vec<Vec<Item>>,
+    /// Number of items inside of all of the batches
+    records: usize,
+    notifiers: Vec<oneshot::Sender<Result<(), Error>>>,
+    /// Number of bytes the items take up once they are framed
+    bytes: usize,
+    /// Strongest durability that any of the items asked for
+    durability: Durability,
+}
+
+/// The segment of the WAL that writes are currently appended to
+struct ActiveSegment {
+    id: u64,
+    file: DbFile,
+    size: u64,
+}
+
+/// Where the WAL stores its segments, and where it records that they exist
+struct WalStorage {
+    fs: FileSystemFacade,
+    manifest: ActorRef<Manifest>,
 }
 
 pub struct WalActor {
     flush: Option<FlushTask>,
-    buffer: Vec<Insert>,
-    disk: DbFile,
-    flush_buffer_sync: Duration,
+    buffer: Buffer,
+    active: Option<ActiveSegment>,
+    storage: Option<WalStorage>,
+    segments: Segments,
+    rotating: bool,
+    /// Number of commits in a row that failed to write to disk
+    failures: usize,
+    /// Why every new write is rejected. Set once too many commits fail, or once
+    /// the database loses an actor that it can't work without.
+    read_only: Option<String>,
+    options: WalOptions,
 }
 
-impl WalActor {}
-
 impl WalActor {
-    pub fn new(disk: DbFile, flush_buffer_sync: Duration) -> Self {
+    /// Create a WAL that has no segment to write to yet. The segments are opened
+    /// once the database is restored, writes that are flushed before then will
+    /// fail.
+    pub fn new(options: WalOptions) -> Self {
         Self {
             flush: None,
-            buffer: Vec::new(),
-            disk,
-            flush_buffer_sync,
+            buffer: Buffer::default(),
+            active: None,
+            storage: None,
+            segments: Segments::default(),
+            rotating: false,
+            failures: 0,
+            read_only: None,
+            options,
         }
     }
 
-    fn flush(&mut self, task: FlushTask) -> (bool, Vec<oneshot::Sender<Result<(), Error>>>) {
+    fn flush(&mut self, started: Instant) -> (bool, Vec<oneshot::Sender<Result<(), Error>>>) {
         let now = Instant::now();
-        println!(
+        let buffer = std::mem::take(&mut self.buffer);
+        tracing::trace!(
             "Writing {} records after {} milliseconds",
-            self.buffer.len(),
-            (now - task.now).as_millis()
+            buffer.records,
+            (now - started).as_millis()
         );
 
+        let notifiers = buffer.notifiers;
+        let active = match self.active.as_mut() {
+            Some(active) => active,
+            None => {
+                println!("Failed to write buffer to wal disk because it hasn't been restored");
+                return (true, notifiers);
+            }
+        };
+
         // serialize all objects
-        let mut vectored = vec![];
-        let mut notifiers = vec![];
-        for write in self.buffer.drain(..) {
-            println!("{}", write.item);
-            vectored.push(bincode::serialize(&write.item).unwrap());
-            notifiers.push(write.tx);
+        let mut bytes = Vec::with_capacity(buffer.bytes);
+        for batch in buffer.batches {
+            frame::encode(&bincode::serialize(&batch).unwrap(), &mut bytes);
+            let end = WalPosition::new(active.id, active.size + bytes.len() as u64);
+            for item in batch {
+                tracing::trace!("{}", item);
+                self.segments.record_write(&item.table, end);
+            }
         }
 
-        let vec = vectored.iter().map(|v| IoSlice::new(v)).collect::<Vec<_>>();
-
         let mut is_error = false;
 
-        if let Err(err) = self.disk.write_vectored(&vec) {
+        if let Err(err) = active.file.write_all(&bytes) {
             is_error = true;
             println!("{err}");
             println!("Failed to write buffer to wal disk");
         }
-        if let Err(err) = self.disk.flush() {
+        let synced = match buffer.durability {
+            Durability::None => Ok(()),
+            Durability::Flush => active.file.flush(),
+            Durability::SyncData => active.file.sync_data(),
+            Durability::SyncAll => active.file.sync_all(),
+        };
+        if let Err(err) = synced {
             is_error = true;
             println!("{err}");
-            println!("Failed to flush wal disk");
+            println!("Failed to sync wal disk");
+        }
+
+        if is_error {
+            // Remove any part of the buffer that made it to disk so that the
+            // next write starts directly after the last complete frame.
+            if let Err(err) = active.file.set_len(active.size) {
+                println!("{err}");
+                println!("Failed to remove partial write from wal disk");
+            }
+        } else {
+            active.size += bytes.len() as u64;
         }
         (is_error, notifiers)
     }
+
+    /// Write everything in the buffer to the WAL and let the writers know once
+    /// it has been done.
+    fn commit(&mut self, ctx: &mut Ctx<Self>) {
+        // A flush can be requested while shutting down without any writes waiting
+        let FlushTask { now, handle } = match self.flush.take() {
+            Some(flush) => flush,
+            None => return,
+        };
+        // The buffer may have filled up before the timer went off
+        handle.halt();
+        let (is_error, notifiers) = self.flush(now);
+        if is_error {
+            self.failures += 1;
+            if self.failures >= self.options.max_write_failures && self.read_only.is_none() {
+                println!(
+                    "WAL failed to write {} times in a row, rejecting all new writes",
+                    self.failures
+                );
+                self.read_only = Some(format!("failing to write to disk {} times", self.failures));
+            }
+        } else {
+            self.failures = 0;
+        }
+        ctx.anonymous_task(async move {
+            for notifier in notifiers {
+                let result = if is_error {
+                    Err(anyhow::Error::msg("Failed to flush buffer to wal disk"))
+                } else {
+                    Ok(())
+                };
+                let _ = notifier.send(result);
+            }
+        });
+        self.rotate_if_full(ctx);
+    }
+
+    /// Read all of the items from a segment that still need to be replayed.
+    fn read_segment(
+        &mut self,
+        id: u64,
+        file: &mut DbFile,
+    ) -> anyhow::Result<(Vec<Item>, u64, u64)> {
+        // TODO(Alec): ooohhh aren't you naugthy, doing a blocking operation on
+        //             an async thread. LOL who cares for now :P
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let frames = frame::decode(&WAL_MAGIC, &bytes)?;
+
+        self.segments.open(id);
+        let mut valids = Vec::with_capacity(frames.frames.len());
+        let mut invalids = Vec::new();
+        for frame in &frames.frames {
+            let batch: Vec<Item> = match bincode::deserialize(frame.payload) {
+                Ok(batch) => batch,
+                Err(err) => anyhow::bail!("Failed to read a record from the WAL log: {err}"),
+            };
+            let end = WalPosition::new(id, frame.end as u64);
+            for item in &batch {
+                self.segments.record_write(&item.table, end);
+            }
+            // Items of a batch are only replayed together
+            if batch.iter().any(|item| !item.is_valid()) {
+                invalids.extend(batch);
+                continue;
+            }
+            for item in batch {
+                if !self.segments.is_persisted(&item.table, end) {
+                    valids.push(item);
+                }
+            }
+        }
+
+        if !invalids.is_empty() {
+            println!("Found invalid records in the WAL");
+            for invalid in invalids {
+                println!("INVALID: {:?}", invalid);
+            }
+        }
+
+        // Drop the torn tail of the log so that new frames are written directly
+        // after the last complete frame.
+        let discarded = frames.discarded(&bytes) as u64;
+        let mut size = frames.valid_len as u64;
+        if frames.valid_len == 0 {
+            file.set_len(0)?;
+            file.write_all(&frame::header(&WAL_MAGIC))?;
+            file.flush()?;
+            size = frame::HEADER_SIZE as u64;
+        } else if discarded > 0 {
+            println!(
+                "Discarding {} bytes from the end of WAL segment {}",
+                discarded, id
+            );
+            file.set_len(size)?;
+        }
+        Ok((valids, size, discarded))
+    }
+
+    /// Start writing to a new segment once the active segment is full.
+    fn rotate_if_full(&mut self, ctx: &mut Ctx<Self>) {
+        let (active, storage) = match (self.active.as_ref(), self.storage.as_ref()) {
+            (Some(active), Some(storage)) => (active, storage),
+            _ => return,
+        };
+        if self.rotating || active.size < self.options.segment_size {
+            return;
+        }
+
+        self.rotating = true;
+        let id = active.id + 1;
+        let fs = storage.fs.clone();
+        let manifest = storage.manifest.clone();
+        ctx.anonymous(async move {
+            let result = open_segment(&fs, &manifest, id).await;
+            Rotated(result.map(|file| (id, file)))
+        });
+    }
+
+    /// Stop tracking all of the segments that have been persisted and return
+    /// them so they can be deleted.
+    fn take_deletable_segments(&mut self) -> Vec<u64> {
+        let active = match self.active.as_ref() {
+            Some(active) => active.id,
+            None => return vec![],
+        };
+        let deletable = self.segments.deletable(active);
+        for id in &deletable {
+            self.segments.remove(*id);
+        }
+        deletable
+    }
+}
+
+/// Record a new segment in the manifest and then create the file for it
+async fn open_segment(
+    fs: &FileSystemFacade,
+    manifest: &ActorRef<Manifest>,
+    id: u64,
+) -> anyhow::Result<DbFile> {
+    manifest
+        .ask(ManifestEvent::WalSegmentCreated { id })
+        .await??;
+    let options = OpenFileOptions::new(segment_file_name(id))
+        .read()
+        .write()
+        .create()
+        .truncate();
+    let mut file = fs.open(options).await?;
+    file.write_all(&frame::header(&WAL_MAGIC))?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// Record that the segments are deleted in the manifest and then remove them
+async fn delete_segments(fs: &FileSystemFacade, manifest: &ActorRef<Manifest>, ids: Vec<u64>) {
+    for id in ids {
+        let result = async {
+            manifest
+                .ask(ManifestEvent::WalSegmentDeleted { id })
+                .await??;
+            fs.remove_file(segment_file_name(id)).await
+        };
+        if let Err(err) = result.await {
+            println!("{err}");
+            println!("Failed to delete WAL segment {}", id);
+        }
+    }
 }
 
 impl Actor for WalActor {
@@ -88,77 +335,237 @@ impl Actor for WalActor {
 
 impl Handler<Insert> for WalActor {
     fn handle(&mut self, message: Insert, ctx: &mut tokactor::Ctx<Self>) {
-        println!("Pushed Message...");
-        self.buffer.push(message);
+        let Insert {
+            tx,
+            items,
+            durability,
+        } = message;
+        if let Some(reason) = self.read_only.as_ref() {
+            let _ = tx.send(Err(anyhow::anyhow!(
+                "The WAL is read-only after {}",
+                reason
+            )));
+            return;
+        }
+
+        let durability = durability.unwrap_or(self.options.durability);
+        if durability == Durability::None {
+            // The writer doesn't care if the write makes it to disk
+            let _ = tx.send(Ok(()));
+        } else {
+            self.buffer.notifiers.push(tx);
+        }
+        self.buffer.bytes +=
+            frame::FRAME_HEADER_SIZE + bincode::serialized_size(&items).unwrap_or(0) as usize;
+        self.buffer.durability = self.buffer.durability.max(durability);
+        self.buffer.records += items.len();
+        self.buffer.batches.push(items);
 
         // If no flush task is currently in the queue,
         if self.flush.is_none() {
             let now = Instant::now();
             let address = ctx.address();
-            let duration = self.flush_buffer_sync;
-            let _handle = ctx.anonymous_task(async move {
+            let duration = self.options.flush_buffer_sync;
+            let handle = ctx.anonymous_task(async move {
                 let _ = address.schedule(duration).await.send_async(Flush).await;
             });
-            self.flush = Some(FlushTask { now, _handle });
+            self.flush = Some(FlushTask { now, handle });
+        }
+
+        if self.buffer.records >= self.options.group_commit_records
+            || self.buffer.bytes >= self.options.group_commit_bytes
+        {
+            self.commit(ctx);
         }
     }
 }
 
 impl Handler<Flush> for WalActor {
     fn handle(&mut self, _: Flush, context: &mut tokactor::Ctx<Self>) {
-        assert!(self.flush.is_some());
-        let flush = self.flush.take().unwrap();
-        let (is_error, notifiers) = self.flush(flush);
-        context.anonymous_task(async move {
-            for notifier in notifiers {
-                let result = if is_error {
-                    Err(anyhow::Error::msg("Failed to flush buffer to wal disk"))
-                } else {
-                    Ok(())
-                };
-                let _ = notifier.send(result);
+        self.commit(context);
+    }
+}
+
+impl Handler<ReadOnly> for WalActor {
+    fn handle(&mut self, ReadOnly(reason): ReadOnly, _: &mut Ctx<Self>) {
+        println!("WAL is rejecting all new writes after {}", reason);
+        self.read_only.get_or_insert(reason);
+    }
+}
+
+impl Handler<Rotated> for WalActor {
+    fn handle(&mut self, Rotated(result): Rotated, ctx: &mut Ctx<Self>) {
+        self.rotating = false;
+        let (id, file) = match result {
+            Ok(segment) => segment,
+            Err(err) => {
+                println!("{err}");
+                println!("Failed to rotate WAL segment");
+                return;
             }
+        };
+
+        self.segments.open(id);
+        self.active = Some(ActiveSegment {
+            id,
+            file,
+            size: frame::HEADER_SIZE as u64,
         });
+
+        let deletable = self.take_deletable_segments();
+        if let (false, Some(storage)) = (deletable.is_empty(), self.storage.as_ref()) {
+            let fs = storage.fs.clone();
+            let manifest = storage.manifest.clone();
+            ctx.anonymous_task(async move { delete_segments(&fs, &manifest, deletable).await });
+        }
     }
 }
 
-impl Ask<WalRestore> for WalActor {
-    type Result = WalRestoredItems;
+impl AsyncAsk<WalRestore> for WalActor {
+    type Output = anyhow::Result<WalRestoredItems>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
 
-    fn handle(&mut self, msg: WalRestore, _: &mut Ctx<Self>) -> Self::Result {
-        assert!(self.buffer.is_empty());
+    fn handle<'a>(&'a mut self, msg: WalRestore, _: &mut Ctx<Self>) -> Self::Future<'a> {
+        assert!(self.buffer.batches.is_empty());
         assert!(self.flush.is_none());
 
-        let path = msg.path;
-        assert!(path.is_file());
+        Box::pin(async move {
+            let WalRestore {
+                fs,
+                manifest,
+                state,
+            } = msg;
+            self.segments = Segments::new(state.checkpoints);
 
-        // TODO(Alec): ooohhh aren't you naugthy, doing a blocking operation on
-        //             an async thread. LOL who cares for now :P
-        // self.disk = DbFile::restore(path).unwrap();
-        // if self.disk.is_empty() {
-        return WalRestoredItems::new(vec![]);
-        // }
-        // let mut reader = BufReader::new(self.disk.as_reader());
-        // reader.fill_buf().unwrap();
-
-        // let mut items: Vec<Item> = Vec::new();
-        // while !reader.buffer().is_empty() {
-        //     if let Ok(item) = bincode::deserialize_from(&mut reader) {
-        //         items.push(item);
-        //     } else {
-        //         panic!("Failed to read a record from the WAL log")
-        //     }
-        // }
-        // let (valids, invalids): (Vec<Item>, Vec<Item>) =
-        //     items.into_iter().partition(|i| i.is_valid());
-
-        // if !invalids.is_empty() {
-        //     println!("Found invalid records in the WAL");
-        //     for invalid in invalids {
-        //         println!("INVALID: {:?}", invalid);
-        //     }
-        // }
-
-        // WalRestoredItems::new(valids)
+            let mut ids = state.wal_segments.into_iter().collect::<Vec<_>>();
+            if ids.is_empty() {
+                manifest
+                    .ask(ManifestEvent::WalSegmentCreated { id: 0 })
+                    .await??;
+                ids.push(0);
+            }
+
+            // Replay every segment in order. The last segment is the one that we
+            // continue to write to.
+            let mut items = Vec::new();
+            let mut discarded = 0;
+            for id in ids {
+                let options = OpenFileOptions::new(segment_file_name(id))
+                    .read()
+                    .write()
+                    .create();
+                let mut file = fs.open(options).await?;
+                let (segment_items, size, torn) = self.read_segment(id, &mut file)?;
+                items.extend(segment_items);
+                discarded += torn;
+                self.active = Some(ActiveSegment { id, file, size });
+            }
+            self.storage = Some(WalStorage { fs, manifest });
+
+            Ok(WalRestoredItems::new(items, discarded))
+        })
+    }
+}
+
+impl AsyncAsk<Checkpoint> for WalActor {
+    type Output = anyhow::Result<WalPosition>;
+    type Future<'a> = Pin<Box<dyn Future<Output = Self::Output> + Send + Sync + 'a>>;
+
+    fn handle<'a>(&'a mut self, msg: Checkpoint, ctx: &mut Ctx<Self>) -> Self::Future<'a> {
+        // Writes made with `Durability::None` are acknowledged while they are
+        // still in the buffer, so write them out before recording where they end
+        self.commit(ctx);
+        Box::pin(async move {
+            let (active, storage) = match (self.active.as_ref(), self.storage.as_ref()) {
+                (Some(active), Some(storage)) => (active, storage),
+                _ => anyhow::bail!("Can't checkpoint the WAL before it has been restored"),
+            };
+
+            // Every write the table has made so far has been flushed to the
+            // active segment, so they all end before the current position.
+            let position = WalPosition::new(active.id, active.size);
+            let fs = storage.fs.clone();
+            let manifest = storage.manifest.clone();
+            manifest
+                .ask(ManifestEvent::Checkpoint {
+                    table: msg.table.clone(),
+                    position,
+                })
+                .await??;
+            self.segments.checkpoint(msg.table, position);
+
+            let deletable = self.take_deletable_segments();
+            delete_segments(&fs, &manifest, deletable).await;
+            Ok(position)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokactor::Actor;
+
+    use crate::{
+        actors::{
+            fs::{FileSystemFacade, OpenFileOptions},
+            wal::{Wal, WalOptions},
+        },
+        FileSystem,
+    };
+
+    use super::{ActiveSegment, WalActor};
+
+    /// A WAL whose active segment can't be written to
+    async fn broken_wal(max_write_failures: usize) -> Wal {
+        let fs = FileSystemFacade::new(FileSystem::in_memory(&[("/WAL-0", "")][..]).start());
+        fs.open_base_dir().await.unwrap();
+        let file = fs.open(OpenFileOptions::new("WAL-0").read()).await.unwrap();
+
+        let options = WalOptions {
+            group_commit_records: 1,
+            max_write_failures,
+            ..Default::default()
+        };
+        let wal = WalActor {
+            active: Some(ActiveSegment {
+                id: 0,
+                file,
+                size: 0,
+            }),
+            ..WalActor::new(options)
+        };
+        Wal { inner: wal.start() }
+    }
+
+    async fn write(wal: &Wal) -> anyhow::Result<()> {
+        wal.write("table".to_string(), 0, vec![0], Some(b"0".to_vec()), None)
+            .await
+    }
+
+    #[tokio::test]
+    async fn return_failed_writes_to_the_writer() {
+        let wal = broken_wal(usize::MAX).await;
+        assert!(write(&wal).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn become_read_only_after_repeated_failures() {
+        let wal = broken_wal(2).await;
+        for _ in 0..2 {
+            let err = write(&wal).await.unwrap_err();
+            assert!(!err.to_string().contains("read-only"));
+        }
+        let err = write(&wal).await.unwrap_err();
+        assert!(err.to_string().contains("read-only"));
+    }
+
+    #[tokio::test]
+    async fn become_read_only_when_told_to() {
+        let wal = broken_wal(usize::MAX).await;
+        wal.read_only("the manifest stopped").await;
+        let err = write(&wal).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("read-only after the manifest stopped"));
     }
 }
@@ -0,0 +1,222 @@
+//! On disk format of the WAL.
+//!
+//! A WAL file starts with a header that identifies the file, followed by a list
+//! of frames. Every frame holds exactly one serialized record, which for the WAL
+//! is a batch of items that were written together. The same format is used by
+//! the manifest log, only the magic bytes in the header are different.
+//!
+//! ```text
+//! +-----------------+---------------+
+//! | magic (8 bytes) | version (u16) |            file header
+//! +-----------------+---------------+---------+
+//! | length (u32)    | crc (u32)     | payload |  frame 0
+//! +-----------------+---------------+---------+
+//! | length (u32)    | crc (u32)     | payload |  frame 1
+//! +-----------------+---------------+---------+
+//! ```
+//!
+//! All integers are written big endian. The `length` is the size of the payload
+//! in bytes and the `crc` is a CRC-32 (iSCSI) of the payload. A frame is only
+//! considered complete when all of its bytes exist and the crc matches. If the
+//! process crashes in the middle of a write, the last frame is left torn and
+//! everything from the start of that frame is discarded on recovery.
+
+use crc::{Crc, CRC_32_ISCSI};
+
+pub type Magic = [u8; 8];
+
+pub const WAL_MAGIC: Magic = *b"TKDB-WAL";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = WAL_MAGIC.len() + 2;
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Create the header that is written to the start of every file
+pub fn header(magic: &Magic) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..magic.len()].copy_from_slice(magic);
+    header[magic.len()..].copy_from_slice(&VERSION.to_be_bytes());
+    header
+}
+
+/// Append a frame containing `payload` to the end of `output`
+pub fn encode(payload: &[u8], output: &mut Vec<u8>) {
+    let length = u32::try_from(payload.len()).expect("WAL record is larger then 4GB");
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(&CRC.checksum(payload).to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+/// A complete frame that was read from a file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub payload: &'a [u8],
+    /// Offset into the file of the first byte after this frame
+    pub end: usize,
+}
+
+/// All of the frames that could be read from a file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frames<'a> {
+    /// Every complete frame, in the order they were written
+    pub frames: Vec<Frame<'a>>,
+    /// Number of bytes at the start of the file that are valid. Everything
+    /// after this point is a torn frame and should be truncated.
+    pub valid_len: usize,
+}
+
+impl<'a> Frames<'a> {
+    #[cfg(test)]
+    pub fn payloads(&self) -> Vec<&'a [u8]> {
+        self.frames.iter().map(|frame| frame.payload).collect()
+    }
+
+    /// Number of bytes that can't be recovered from the file
+    pub fn discarded(&self, bytes: &[u8]) -> usize {
+        bytes.len() - self.valid_len
+    }
+}
+
+/// Read every complete frame from the contents of a file that starts with the
+/// header for `magic`. Reading stops at the first frame that is incomplete or
+/// fails its crc check.
+///
+/// An empty file has no frames and a valid length of 0, the caller is expected
+/// to write the [`header`] before writing any frames. A file that is shorter then
+/// the header is treated as a header that was torn while being written. Returns
+/// an error if the header exists but it isn't a header we understand.
+pub fn decode<'a>(magic: &Magic, bytes: &'a [u8]) -> anyhow::Result<Frames<'a>> {
+    if bytes.len() < HEADER_SIZE {
+        if magic.starts_with(&bytes[..bytes.len().min(magic.len())]) {
+            return Ok(Frames {
+                frames: vec![],
+                valid_len: 0,
+            });
+        }
+        anyhow::bail!("File header is invalid, it is not a {:?} file", magic);
+    }
+
+    let (found, version) = bytes[..HEADER_SIZE].split_at(magic.len());
+    if found != magic {
+        anyhow::bail!("File header is invalid, it is not a {:?} file", magic);
+    }
+    let version = u16::from_be_bytes([version[0], version[1]]);
+    if version != VERSION {
+        anyhow::bail!(
+            "File version {} is not supported. Expected version {}",
+            version,
+            VERSION
+        );
+    }
+
+    let mut frames = vec![];
+    let mut offset = HEADER_SIZE;
+    while let Some((payload, length)) = read_frame(&bytes[offset..]) {
+        offset += length;
+        frames.push(Frame {
+            payload,
+            end: offset,
+        });
+    }
+
+    Ok(Frames {
+        frames,
+        valid_len: offset,
+    })
+}
+
+/// Read the frame at the start of `bytes`. Returns the payload and the number
+/// of bytes the frame takes up, or `None` if the frame is incomplete or fails
+/// its crc check.
+pub fn read_frame(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let header = bytes.get(..FRAME_HEADER_SIZE)?;
+    let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(header[4..].try_into().unwrap());
+
+    let payload = bytes.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length)?;
+    if CRC.checksum(payload) != crc {
+        return None;
+    }
+    Some((payload, FRAME_HEADER_SIZE + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, header, HEADER_SIZE, WAL_MAGIC};
+
+    fn log(records: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = header(&WAL_MAGIC).to_vec();
+        for record in records {
+            encode(record, &mut bytes);
+        }
+        bytes
+    }
+
+    #[test]
+    fn decode_empty_file() {
+        let frames = decode(&WAL_MAGIC, &[]).unwrap();
+        assert!(frames.frames.is_empty());
+        assert_eq!(frames.valid_len, 0);
+    }
+
+    #[test]
+    fn decode_torn_header() {
+        let bytes = &header(&WAL_MAGIC)[..4];
+        let frames = decode(&WAL_MAGIC, bytes).unwrap();
+        assert!(frames.frames.is_empty());
+        assert_eq!(frames.discarded(bytes), 4);
+    }
+
+    #[test]
+    fn fail_to_decode_invalid_header() {
+        assert!(decode(&WAL_MAGIC, b"not a wal file").is_err());
+        assert!(decode(&WAL_MAGIC, b"nope").is_err());
+
+        let mut bytes = header(&WAL_MAGIC).to_vec();
+        bytes[HEADER_SIZE - 1] += 1;
+        assert!(decode(&WAL_MAGIC, &bytes).is_err());
+    }
+
+    #[test]
+    fn decode_complete_frames() {
+        let bytes = log(&[b"hello", b"", b"world"]);
+        let frames = decode(&WAL_MAGIC, &bytes).unwrap();
+        assert_eq!(frames.payloads(), vec![&b"hello"[..], b"", b"world"]);
+        assert_eq!(frames.frames[0].end, HEADER_SIZE + 8 + 5);
+        assert_eq!(frames.frames[1].end, HEADER_SIZE + 16 + 5);
+        assert_eq!(frames.valid_len, bytes.len());
+        assert_eq!(frames.discarded(&bytes), 0);
+    }
+
+    #[test]
+    fn decode_stops_at_torn_frame_header() {
+        let bytes = log(&[b"hello", b"world"]);
+        let valid = log(&[b"hello"]).len();
+        for torn in valid + 1..valid + 8 {
+            let frames = decode(&WAL_MAGIC, &bytes[..torn]).unwrap();
+            assert_eq!(frames.payloads(), vec![&b"hello"[..]]);
+            assert_eq!(frames.valid_len, valid);
+            assert_eq!(frames.discarded(&bytes[..torn]), torn - valid);
+        }
+    }
+
+    #[test]
+    fn decode_stops_at_torn_payload() {
+        let bytes = log(&[b"hello", b"world"]);
+        let torn = &bytes[..bytes.len() - 1];
+        let frames = decode(&WAL_MAGIC, torn).unwrap();
+        assert_eq!(frames.payloads(), vec![&b"hello"[..]]);
+        assert_eq!(frames.discarded(torn), 12);
+    }
+
+    #[test]
+    fn decode_stops_at_corrupted_payload() {
+        let mut bytes = log(&[b"hello", b"world", b"again"]);
+        let second = log(&[b"hello"]).len();
+        bytes[second + 9] ^= 0xFF;
+        let frames = decode(&WAL_MAGIC, &bytes).unwrap();
+        assert_eq!(frames.payloads(), vec![&b"hello"[..]]);
+        assert_eq!(frames.valid_len, second);
+    }
+}
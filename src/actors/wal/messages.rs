@@ -1,17 +1,33 @@
-use std::path::PathBuf;
-
+use tokactor::ActorRef;
 use tokio::sync::oneshot;
 
-use super::item::Item;
+use crate::actors::{
+    fs::{DbFile, FileSystemFacade},
+    manifest::{Manifest, ManifestState},
+};
+
+use super::{item::Item, options::Durability};
 
+/// Append items to the WAL. All of the items are written in the same frame, so
+/// after a crash either every item is recovered or none of them are.
 pub struct Insert {
     pub tx: oneshot::Sender<anyhow::Result<()>>,
-    pub item: Item,
+    pub items: Vec<Item>,
+    /// Overrides the default durability of the WAL for this write
+    pub durability: Option<Durability>,
 }
 
 impl Insert {
-    pub fn new(tx: oneshot::Sender<anyhow::Result<()>>, item: Item) -> Self {
-        Self { tx, item }
+    pub fn new(
+        tx: oneshot::Sender<anyhow::Result<()>>,
+        items: Vec<Item>,
+        durability: Option<Durability>,
+    ) -> Self {
+        Self {
+            tx,
+            items,
+            durability,
+        }
     }
 }
 
@@ -20,18 +36,36 @@ pub struct Flush;
 
 #[derive(Debug)]
 pub struct WalRestore {
-    pub path: PathBuf,
+    /// Directory that stores all of the WAL segments
+    pub fs: FileSystemFacade,
+    pub manifest: ActorRef<Manifest>,
+    pub state: ManifestState,
 }
 
 pub struct WalRestoredItems {
     pub items: Vec<Item>,
+    /// Number of bytes that were truncated from the end of the log because
+    /// they belonged to a frame that was never completely written.
+    pub discarded: u64,
 }
 
 impl WalRestoredItems {
-    pub fn new(items: Vec<Item>) -> Self {
-        Self { items }
+    pub fn new(items: Vec<Item>, discarded: u64) -> Self {
+        Self { items, discarded }
     }
 }
 
+/// Mark every write of a table that has been written to the WAL so far as
+/// persisted outside of the WAL.
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub table: String,
+}
+
+/// Reject every new write from now on, because of the reason given
+#[derive(Debug)]
+pub struct ReadOnly(pub String);
+
+/// A new segment was opened and writes should now be sent to it
 #[derive(Debug)]
-pub struct DumpWal(pub PathBuf);
+pub struct Rotated(pub anyhow::Result<(u64, DbFile)>);
@@ -1,32 +1,39 @@
 mod actor;
+pub(crate) mod frame;
 mod item;
 mod messages;
+mod options;
+mod segment;
 
-use std::path::PathBuf;
-
-use tokactor::ActorRef;
+use tokactor::{Actor, ActorRef, Ctx, DeadActorResult, Handler};
 use tokio::sync::oneshot;
 
-use self::messages::WalRestore;
+use self::messages::{Checkpoint, ReadOnly, WalRestore};
+
+use super::{
+    fs::FileSystemFacade,
+    manifest::{Manifest, ManifestState},
+};
 
 pub use self::item::Item;
 pub use actor::WalActor;
 pub use messages::{Insert, WalRestoredItems};
+pub use options::{Durability, WalOptions};
+pub use segment::WalPosition;
 
 #[derive(Clone)]
 pub struct Wal {
     inner: ActorRef<WalActor>,
 }
 
-// pub fn new_wal_actor<A>(ctx: &mut Ctx<A>, flush_buffer_sync: Duration) -> Wal
-// where
-//     A: Actor + Handler<DeadActorResult<WalActor>>,
-// {
-//     // let disk = DbFile::in_memory(FNode::new());
-//     let wal = WalActor::new(disk, flush_buffer_sync);
-//     let address = ctx.spawn(wal);
-//     Wal { inner: address }
-// }
+pub fn new_wal_actor<A>(ctx: &mut Ctx<A>, options: WalOptions) -> Wal
+where
+    A: Actor + Handler<DeadActorResult<WalActor>>,
+{
+    let wal = WalActor::new(options);
+    let address = ctx.spawn(wal);
+    Wal { inner: address }
+}
 
 impl Wal {
     pub async fn write(
@@ -34,23 +41,234 @@ impl Wal {
         table: String,
         version: u16,
         key: Vec<u8>,
-        value: Vec<u8>,
+        value: Option<Vec<u8>>,
+        durability: Option<Durability>,
+    ) -> anyhow::Result<()> {
+        let item = Item::new(table, version, key, value);
+        self.write_batch(vec![item], durability).await
+    }
+
+    /// Write every item to the WAL as a single frame. Either all of the items are
+    /// recovered after a crash or none of them are.
+    pub async fn write_batch(
+        &self,
+        items: Vec<Item>,
+        durability: Option<Durability>,
     ) -> anyhow::Result<()> {
         let (tx, rx) = oneshot::channel();
-        let item = Item::new(table, version, key, Some(value));
-        let insert = Insert::new(tx, item);
+        let insert = Insert::new(tx, items, durability);
 
         if (self.inner.send_async(insert).await).is_err() {
             anyhow::bail!("Failed to write message to database")
         }
-        if (rx.await).is_err() {
-            anyhow::bail!("Database accepted write but the response failed to be recieved. Write may not have succeeded");
-        } else {
-            Ok(())
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("Database accepted write but the response failed to be recieved. Write may not have succeeded"),
+        }
+    }
+
+    /// Read all of the items stored inside of the WAL segments listed in the
+    /// manifest that haven't been checkpointed yet. Once read, the last segment
+    /// is kept by the WAL and all future writes are appended to it.
+    pub async fn restore(
+        &self,
+        fs: FileSystemFacade,
+        manifest: ActorRef<Manifest>,
+        state: ManifestState,
+    ) -> anyhow::Result<WalRestoredItems> {
+        self.inner
+            .async_ask(WalRestore {
+                fs,
+                manifest,
+                state,
+            })
+            .await?
+    }
+
+    /// Reject every write that is made from now on. Writes that are already
+    /// waiting to be committed are still written.
+    pub async fn read_only(&self, reason: impl ToString) {
+        if self
+            .inner
+            .send_async(ReadOnly(reason.to_string()))
+            .await
+            .is_err()
+        {
+            println!("Failed to make the WAL read-only");
+        }
+    }
+
+    /// Mark every write to `table` that has been acknowledged so far as persisted.
+    /// Segments that only hold persisted writes are deleted.
+    pub async fn checkpoint(&self, table: impl ToString) -> anyhow::Result<WalPosition> {
+        self.inner
+            .async_ask(Checkpoint {
+                table: table.to_string(),
+            })
+            .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, time::Duration};
+
+    use tokactor::Actor;
+
+    use crate::{
+        actors::{
+            fs::{FileSystemFacade, OpenFileOptions},
+            manifest::Manifest,
+        },
+        FileSystem,
+    };
+
+    use super::{
+        segment::segment_file_name, Durability, Item, Wal, WalActor, WalOptions, WalPosition,
+    };
+
+    fn segmented() -> WalOptions {
+        WalOptions {
+            flush_buffer_sync: Duration::from_millis(1),
+            segment_size: 64,
+            ..Default::default()
+        }
+    }
+
+    /// Never commit because of the timer, only because the buffer filled up
+    fn grouped(group_commit_records: usize) -> WalOptions {
+        WalOptions {
+            flush_buffer_sync: Duration::from_secs(3600),
+            group_commit_records,
+            ..Default::default()
         }
     }
 
-    pub async fn restore(&self, wals: PathBuf) -> WalRestoredItems {
-        self.inner.ask(WalRestore { path: wals }).await.unwrap()
+    async fn init(options: WalOptions) -> (FileSystemFacade, Wal) {
+        let fs = FileSystemFacade::new(FileSystem::in_memory(&[] as &[(&str, &str)]).start());
+        fs.open_base_dir().await.unwrap();
+        fs.validate_or_create_dir("manifest").await.unwrap();
+        fs.validate_or_create_dir("wal").await.unwrap();
+
+        let manifest = Manifest::recover(fs.rebase("manifest")).await.unwrap();
+        let state = manifest.state().clone();
+        let wal = Wal {
+            inner: WalActor::new(options).start(),
+        };
+        wal.restore(fs.rebase("wal"), manifest.start(), state)
+            .await
+            .unwrap();
+        (fs, wal)
+    }
+
+    /// Restore a new WAL from the segments that were written to `fs`
+    async fn reopen(fs: &FileSystemFacade) -> Vec<Item> {
+        let manifest = Manifest::recover(fs.rebase("manifest")).await.unwrap();
+        let state = manifest.state().clone();
+        let wal = Wal {
+            inner: WalActor::new(WalOptions::default()).start(),
+        };
+        let restored = wal
+            .restore(fs.rebase("wal"), manifest.start(), state)
+            .await
+            .unwrap();
+        restored.items
+    }
+
+    async fn write_one(wal: &Wal, table: &str, key: u8, durability: Option<Durability>) {
+        let value = serde_json::to_vec(&"x".repeat(32)).unwrap();
+        wal.write(table.to_string(), 0, vec![key], Some(value), durability)
+            .await
+            .unwrap();
+    }
+
+    async fn write(wal: &Wal, table: &str, count: u8) {
+        for i in 0..count {
+            write_one(wal, table, i, None).await;
+        }
+        // give the wal a chance to finish rotating
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    async fn segments(fs: &FileSystemFacade) -> Vec<u64> {
+        let manifest = Manifest::recover(fs.rebase("manifest")).await.unwrap();
+        let segments: Vec<u64> = manifest.state().wal_segments.iter().copied().collect();
+        for id in &segments {
+            assert!(fs
+                .rebase("wal")
+                .read_file(segment_file_name(*id))
+                .await
+                .is_ok());
+        }
+        segments
+    }
+
+    #[tokio::test]
+    async fn rotate_full_segments() {
+        let (fs, wal) = init(segmented()).await;
+        assert_eq!(segments(&fs).await, vec![0]);
+        write(&wal, "a", 3).await;
+        assert_eq!(segments(&fs).await, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn delete_segments_once_every_table_is_checkpointed() {
+        let (fs, wal) = init(segmented()).await;
+        write(&wal, "a", 1).await;
+        write(&wal, "b", 1).await;
+        assert_eq!(segments(&fs).await, vec![0, 1, 2]);
+
+        let position = wal.checkpoint("a").await.unwrap();
+        assert_eq!(position, WalPosition::new(2, 10));
+        assert_eq!(segments(&fs).await, vec![1, 2]);
+        assert!(fs
+            .rebase("wal")
+            .read_file(segment_file_name(0))
+            .await
+            .is_err());
+
+        wal.checkpoint("b").await.unwrap();
+        assert_eq!(segments(&fs).await, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn commit_once_the_buffer_holds_enough_records() {
+        let (_, wal) = init(grouped(2)).await;
+        let first = write_one(&wal, "a", 0, None);
+        let second = write_one(&wal, "a", 1, Some(Durability::SyncAll));
+        let both = futures::future::join(first, second);
+        tokio::time::timeout(Duration::from_secs(1), both)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn acknowledge_writes_without_durability_immediately() {
+        let (_, wal) = init(grouped(usize::MAX)).await;
+        let write = write_one(&wal, "a", 0, Some(Durability::None));
+        tokio::time::timeout(Duration::from_secs(1), write)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_every_item_of_a_batch_or_none_of_them() {
+        let (fs, wal) = init(grouped(1)).await;
+        write_one(&wal, "a", 0, None).await;
+        let batch = (1..4)
+            .map(|key| Item::new("b".to_string(), 0, vec![key], Some(b"1".to_vec())))
+            .collect();
+        wal.write_batch(batch, None).await.unwrap();
+
+        let keys = |items: Vec<Item>| items.into_iter().map(|i| i.key[0]).collect::<Vec<_>>();
+        assert_eq!(keys(reopen(&fs).await), vec![0, 1, 2, 3]);
+
+        // Tear the last byte off of the batch
+        let options = OpenFileOptions::new(segment_file_name(0)).read().write();
+        let mut file = fs.rebase("wal").open(options).await.unwrap();
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).unwrap();
+        file.set_len(bytes.len() as u64 - 1).unwrap();
+        assert_eq!(keys(reopen(&fs).await), vec![0]);
     }
 }
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+/// How sure the database needs to be that a write has been saved before it is
+/// acknowledged. Each level includes the guarantees of the levels before it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Durability {
+    /// Acknowledge the write as soon as it has been buffered by the WAL. The
+    /// write is lost if the process crashes before the buffer is written.
+    None,
+    /// Acknowledge the write once it has been handed to the operating system.
+    /// The write survives the process crashing, but not the machine.
+    #[default]
+    Flush,
+    /// Acknowledge the write once its data has been synced to disk
+    SyncData,
+    /// Acknowledge the write once its data and the metadata of the WAL have
+    /// been synced to disk
+    SyncAll,
+}
+
+/// Settings that decide when the WAL writes to disk
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    /// Default durability of a write that doesn't ask for its own
+    pub durability: Durability,
+    /// Longest amount of time a write is buffered before it is committed
+    pub flush_buffer_sync: Duration,
+    /// Commit the buffer once it holds this many bytes
+    pub group_commit_bytes: usize,
+    /// Commit the buffer once it holds this many records
+    pub group_commit_records: usize,
+    /// Size a segment can grow to before a new segment is started
+    pub segment_size: u64,
+    /// Number of commits in a row that can fail before the WAL stops accepting
+    /// writes
+    pub max_write_failures: usize,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            flush_buffer_sync: Duration::from_millis(10),
+            group_commit_bytes: 1024 * 1024,
+            group_commit_records: 1024,
+            segment_size: 64 * 1024 * 1024,
+            max_write_failures: 3,
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Position inside of the WAL. Positions are ordered by segment first and then
+/// by the byte offset inside of that segment.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct WalPosition {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+impl WalPosition {
+    pub fn new(segment: u64, offset: u64) -> Self {
+        Self { segment, offset }
+    }
+}
+
+/// Name of the file that stores a WAL segment
+pub fn segment_file_name(id: u64) -> String {
+    format!("WAL-{}", id)
+}
+
+/// Keep track of which trees wrote to each segment of the WAL and how far each
+/// tree has been persisted outside of the WAL. A segment can be deleted once
+/// all of the trees that wrote to it have been persisted past their last write
+/// in that segment.
+#[derive(Debug, Default)]
+pub struct Segments {
+    /// For every live segment, the end of the last write of each table
+    written: BTreeMap<u64, HashMap<String, u64>>,
+    /// Every write that ends at or before the checkpoint of a table is persisted
+    checkpoints: HashMap<String, WalPosition>,
+}
+
+impl Segments {
+    pub fn new(checkpoints: HashMap<String, WalPosition>) -> Self {
+        Self {
+            written: BTreeMap::new(),
+            checkpoints,
+        }
+    }
+
+    /// Start tracking a segment, even if nothing has been written to it yet
+    pub fn open(&mut self, segment: u64) {
+        self.written.entry(segment).or_default();
+    }
+
+    /// Record that `table` wrote a frame that ends at `end`
+    pub fn record_write(&mut self, table: &str, end: WalPosition) {
+        let tables = self.written.entry(end.segment).or_default();
+        match tables.get_mut(table) {
+            Some(offset) => *offset = end.offset.max(*offset),
+            None => {
+                tables.insert(table.to_string(), end.offset);
+            }
+        }
+    }
+
+    /// Record that every write of `table` that ends at or before `position` has
+    /// been persisted somewhere else.
+    pub fn checkpoint(&mut self, table: impl ToString, position: WalPosition) {
+        let checkpoint = self.checkpoints.entry(table.to_string()).or_default();
+        *checkpoint = position.max(*checkpoint);
+    }
+
+    /// Check if a write of `table` that ended at `end` has already been persisted
+    /// and doesn't need to be replayed.
+    pub fn is_persisted(&self, table: &str, end: WalPosition) -> bool {
+        self.checkpoints
+            .get(table)
+            .map(|checkpoint| end <= *checkpoint)
+            .unwrap_or(false)
+    }
+
+    /// Segments older then the `active` segment that no longer hold any writes
+    /// that would need to be replayed.
+    pub fn deletable(&self, active: u64) -> Vec<u64> {
+        self.written
+            .range(..active)
+            .filter(|(segment, tables)| {
+                tables.iter().all(|(table, offset)| {
+                    self.is_persisted(table, WalPosition::new(**segment, *offset))
+                })
+            })
+            .map(|(segment, _)| *segment)
+            .collect()
+    }
+
+    /// Stop tracking a segment once it has been deleted
+    pub fn remove(&mut self, segment: u64) {
+        self.written.remove(&segment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Segments, WalPosition};
+
+    #[test]
+    fn positions_order_by_segment_then_offset() {
+        assert!(WalPosition::new(0, 100) < WalPosition::new(1, 0));
+        assert!(WalPosition::new(1, 10) < WalPosition::new(1, 11));
+    }
+
+    #[test]
+    fn empty_segments_can_be_deleted_unless_active() {
+        let mut segments = Segments::default();
+        segments.open(0);
+        segments.open(1);
+        assert_eq!(segments.deletable(1), vec![0]);
+        assert_eq!(segments.deletable(0), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn segment_is_kept_until_every_table_is_persisted() {
+        let mut segments = Segments::default();
+        segments.record_write("a", WalPosition::new(0, 10));
+        segments.record_write("b", WalPosition::new(0, 20));
+        segments.record_write("a", WalPosition::new(1, 10));
+        assert!(segments.deletable(2).is_empty());
+
+        segments.checkpoint("a", WalPosition::new(1, 10));
+        assert_eq!(segments.deletable(2), vec![1]);
+
+        segments.checkpoint("b", WalPosition::new(0, 19));
+        assert_eq!(segments.deletable(2), vec![1]);
+
+        segments.checkpoint("b", WalPosition::new(0, 20));
+        assert_eq!(segments.deletable(2), vec![0, 1]);
+
+        segments.remove(0);
+        assert_eq!(segments.deletable(2), vec![1]);
+    }
+
+    #[test]
+    fn writes_after_a_checkpoint_are_not_persisted() {
+        let checkpoints = HashMap::from([("a".to_string(), WalPosition::new(1, 50))]);
+        let segments = Segments::new(checkpoints);
+        assert!(segments.is_persisted("a", WalPosition::new(0, 500)));
+        assert!(segments.is_persisted("a", WalPosition::new(1, 50)));
+        assert!(!segments.is_persisted("a", WalPosition::new(1, 51)));
+        assert!(!segments.is_persisted("b", WalPosition::new(0, 1)));
+    }
+
+    #[test]
+    fn checkpoints_never_move_backwards() {
+        let mut segments = Segments::default();
+        segments.checkpoint("a", WalPosition::new(2, 0));
+        segments.checkpoint("a", WalPosition::new(1, 0));
+        assert!(segments.is_persisted("a", WalPosition::new(1, 100)));
+    }
+}
@@ -0,0 +1,228 @@
+//! Order-preserving encoding of keys.
+//!
+//! Trees store their keys as bytes, and the memtable and table files sort keys
+//! by comparing those bytes. Keys are encoded so that comparing the bytes of two
+//! keys gives the same result as comparing the keys with `Ord`:
+//!
+//! - Unsigned integers are written big-endian. Signed integers have their sign
+//!   bit flipped first, so negative numbers come before positive numbers.
+//! - Strings and byte arrays end with `0x00 0x00`, and every `0x00` inside of
+//!   them is escaped as `0x00 0xFF`, so a prefix comes before anything longer.
+//! - Every element of a sequence starts with `0x01` and the sequence ends with
+//!   `0x00`, so sequences are compared one element at a time.
+//! - Tuples and structs are their fields one after the other, enums are the
+//!   index of their variant followed by its fields and options are `0x00` for
+//!   `None` or `0x01` followed by the value.
+//!
+//! The encoding isn't self describing, so a key can only be decoded as the type
+//! that encoded it.
+
+mod de;
+mod ser;
+
+use std::{fmt, ops::Bound};
+
+use serde::{Deserialize, Serialize};
+
+/// Encode a key into bytes that sort in the same order as the key
+pub fn to_vec<T: Serialize + ?Sized>(key: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = ser::Serializer { output: Vec::new() };
+    key.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+/// Decode a key that was encoded with [`to_vec`]
+pub fn from_slice<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    let mut deserializer = de::Deserializer { input: bytes };
+    let key = T::deserialize(&mut deserializer)?;
+    if !deserializer.input.is_empty() {
+        return Err(Error::new("Found bytes after the end of the key"));
+    }
+    Ok(key)
+}
+
+/// The end of the range of keys whose encoding starts with `prefix`. This is
+/// the smallest key that is larger than every key with the prefix.
+pub fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Error {
+    fn new(msg: impl ToString) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid key: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Debug, ops::Bound};
+
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use crate::{U32, U64};
+
+    use super::{from_slice, prefix_end, to_vec};
+
+    /// Check that every key decodes back to itself and that the keys are sorted
+    /// in the same order as their encoding
+    fn assert_ordered<T>(mut keys: Vec<T>)
+    where
+        T: Serialize + DeserializeOwned + Ord + Debug,
+    {
+        keys.sort();
+        let encoded = keys.iter().map(|k| to_vec(k).unwrap()).collect::<Vec<_>>();
+        for (key, bytes) in keys.iter().zip(encoded.iter()) {
+            assert_eq!(&from_slice::<T>(bytes).unwrap(), key);
+        }
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert_eq!(
+                keys[i].cmp(&keys[i + 1]),
+                pair[0].cmp(&pair[1]),
+                "{:?} and {:?}",
+                keys[i],
+                keys[i + 1]
+            );
+        }
+    }
+
+    #[test]
+    fn order_unsigned_integers() {
+        assert_ordered((0..1000_u32).map(U32::new).collect());
+        assert_ordered(
+            vec![0, 1, 255, 256, 65535, 65536, u64::MAX]
+                .into_iter()
+                .map(U64::new)
+                .collect(),
+        );
+        assert_ordered(vec![0_u8, 1, 127, 128, 255]);
+        assert_ordered(vec![0_u128, 1, u64::MAX as u128 + 1, u128::MAX]);
+    }
+
+    #[test]
+    fn order_signed_integers() {
+        assert_ordered(vec![i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX]);
+        assert_ordered(vec![i8::MIN, -1, 0, 1, i8::MAX]);
+    }
+
+    #[test]
+    fn order_strings() {
+        let strings = [
+            "",
+            "a",
+            "a\0",
+            "a\0b",
+            "aa",
+            "ab",
+            "b",
+            "\u{ff}",
+            "\u{1F600}",
+        ];
+        assert_ordered(strings.iter().map(|s| s.to_string()).collect());
+    }
+
+    #[test]
+    fn order_composite_keys() {
+        let mut keys = vec![];
+        for a in ["", "a", "ab"] {
+            for b in [0_u32, 1, 256] {
+                keys.push((a.to_string(), U32::new(b)));
+                keys.push((a.to_string(), U32::new(b)));
+            }
+        }
+        assert_ordered(keys);
+
+        assert_ordered(vec![
+            vec![],
+            vec![0_u16],
+            vec![0, 0],
+            vec![0, 1],
+            vec![1],
+            vec![256, 0],
+        ]);
+        assert_ordered(vec![None, Some(0_u32), Some(1)]);
+        assert_ordered(vec![(false, -1_i32), (false, 1), (true, i32::MIN)]);
+    }
+
+    #[test]
+    fn order_enums() {
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+        enum Key {
+            Unit,
+            Number(u64),
+            Pair { name: String, id: u32 },
+        }
+        assert_ordered(vec![
+            Key::Unit,
+            Key::Number(0),
+            Key::Number(300),
+            Key::Pair {
+                name: "a".to_string(),
+                id: 9,
+            },
+            Key::Pair {
+                name: "b".to_string(),
+                id: 1,
+            },
+        ]);
+    }
+
+    #[test]
+    fn fail_to_decode_invalid_keys() {
+        assert!(from_slice::<u32>(&[0, 0, 1]).is_err());
+        assert!(from_slice::<u32>(&[0, 0, 0, 1, 2]).is_err());
+        assert!(from_slice::<String>(b"abc").is_err());
+        assert!(from_slice::<String>(&[0xff, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn find_keys_that_start_with_a_prefix() {
+        let prefix = to_vec(&U32::new(7)).unwrap();
+        let end = match prefix_end(&prefix) {
+            Bound::Excluded(end) => end,
+            bound => panic!("{:?}", bound),
+        };
+        let keys = [
+            ((U32::new(6), "z"), false),
+            ((U32::new(7), ""), true),
+            ((U32::new(7), "z"), true),
+            ((U32::new(8), ""), false),
+        ];
+        for (key, inside) in keys {
+            let key = to_vec(&key).unwrap();
+            assert_eq!(key >= prefix && key < end, inside, "{:?}", key);
+        }
+
+        assert_eq!(prefix_end(&[1, 0xff]), Bound::Excluded(vec![2]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), Bound::Unbounded);
+    }
+}
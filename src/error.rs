@@ -0,0 +1,145 @@
+/// A transaction read a record that was changed by another writer before the
+/// transaction committed. Nothing the transaction wrote was saved, and running
+/// the transaction again will read the new value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionConflict {
+    tree: String,
+}
+
+impl TransactionConflict {
+    pub(crate) fn new(tree: impl ToString) -> Self {
+        Self {
+            tree: tree.to_string(),
+        }
+    }
+
+    /// Name of the tree that holds the record that changed
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+}
+
+impl std::fmt::Display for TransactionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A record of {} was changed while the transaction was running",
+            self.tree
+        )
+    }
+}
+
+impl std::error::Error for TransactionConflict {}
+
+/// A record was inserted under a key that already holds a value. Nothing was
+/// written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyAlreadyExists {
+    tree: String,
+    key: String,
+}
+
+impl KeyAlreadyExists {
+    pub(crate) fn new(tree: impl ToString, key: &impl std::fmt::Debug) -> Self {
+        Self {
+            tree: tree.to_string(),
+            key: format!("{:?}", key),
+        }
+    }
+
+    /// Name of the tree that already holds the key
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+
+    /// Debug representation of the key
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl std::fmt::Display for KeyAlreadyExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key {} already exists in {}", self.key, self.tree)
+    }
+}
+
+impl std::error::Error for KeyAlreadyExists {}
+
+/// A value was rejected by one of the constraints of a tree. Nothing was
+/// written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    tree: String,
+    constraint: String,
+}
+
+impl ConstraintViolation {
+    pub(crate) fn new(tree: impl ToString, constraint: impl ToString) -> Self {
+        Self {
+            tree: tree.to_string(),
+            constraint: constraint.to_string(),
+        }
+    }
+
+    /// Name of the tree the value was written to
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+
+    /// Name of the constraint that rejected the value
+    pub fn constraint(&self) -> &str {
+        &self.constraint
+    }
+}
+
+impl std::fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Value written to {} was rejected by the constraint {}",
+            self.tree, self.constraint
+        )
+    }
+}
+
+impl std::error::Error for ConstraintViolation {}
+
+/// A value was written with an identity that already belongs to another record
+/// of a unique index. Nothing was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniqueViolation {
+    index: String,
+    id: String,
+}
+
+impl UniqueViolation {
+    pub(crate) fn new(index: impl ToString, id: &impl std::fmt::Debug) -> Self {
+        Self {
+            index: index.to_string(),
+            id: format!("{:?}", id),
+        }
+    }
+
+    /// Name of the unique index that rejected the value
+    pub fn index(&self) -> &str {
+        &self.index
+    }
+
+    /// Debug representation of the identity that is already taken
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl std::fmt::Display for UniqueViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} already exists in the unique index {}",
+            self.id, self.index
+        )
+    }
+}
+
+impl std::error::Error for UniqueViolation {}
@@ -1,4 +1,6 @@
 #[macro_use]
 mod number;
+mod prefix;
 
 pub use number::*;
+pub use prefix::KeyPrefix;
@@ -1,5 +1,3 @@
-use crate::actors::tree::PrimaryKey;
-
 macro_rules! auto_increment_id_number_impl {
     ($id: ident, $ty: ty) => {
 
@@ -51,9 +49,16 @@ macro_rules! auto_increment_id_number_impl {
                 self.0 += 1;
                 Self(self.0)
             }
-        }
 
-        impl PrimaryKey for $id {}
+            fn advance(&self, count: u64) -> Self {
+                // Stops at the largest id instead of overflowing
+                if count > <$ty>::MAX as u64 {
+                    Self(<$ty>::MAX)
+                } else {
+                    Self(self.0.saturating_add(count as $ty))
+                }
+            }
+        }
     };
 }
 
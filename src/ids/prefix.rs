@@ -0,0 +1,14 @@
+/// A composite key that starts with `P`. Keys are stored one component after
+/// another, so every key that starts with the same components is stored next
+/// to each other and can be read with a single range. A single leading
+/// component is passed on its own, and longer prefixes as a tuple.
+pub trait KeyPrefix<P> {}
+
+impl<A, B> KeyPrefix<A> for (A, B) {}
+
+impl<A, B, C> KeyPrefix<A> for (A, B, C) {}
+impl<A, B, C> KeyPrefix<(A, B)> for (A, B, C) {}
+
+impl<A, B, C, D> KeyPrefix<A> for (A, B, C, D) {}
+impl<A, B, C, D> KeyPrefix<(A, B)> for (A, B, C, D) {}
+impl<A, B, C, D> KeyPrefix<(A, B, C)> for (A, B, C, D) {}
@@ -1,25 +1,43 @@
 mod actors;
+mod codec;
+mod error;
 mod ids;
 mod record;
 mod relationships;
 
 use std::fmt::{Debug, Display};
 
-pub use actors::db::Database;
+pub use actors::db::{Database, DatabaseOptions, Transaction, WriteBatch};
 pub use actors::subtree::AggregateTree;
+pub use actors::subtree::BackfillProgress;
+pub use actors::subtree::SortedIndex;
 pub use actors::subtree::SubTree;
-pub use actors::tree::Tree;
+pub use actors::subtree::UniqueIndex;
+pub use actors::table::CacheStats;
+pub use actors::tree::{ListStream, Tree};
 use actors::tree::{PrimaryKey, RecordValue};
+pub use actors::wal::Durability;
+pub use error::{ConstraintViolation, KeyAlreadyExists, TransactionConflict, UniqueViolation};
 pub use ids::*;
 pub use relationships::*;
 
 pub use actors::fs::FileSystem;
-pub use record::{Aggregate, Change, Update};
+pub use record::{Aggregate, Change, Constraint, SecondaryIndex, Update};
 
 /// Allow for an ID to be incrementable. Support the ability to increment the
 /// ID inside the interal framework.
 pub trait AutoIncrement: Ord + Default + Display + Debug + Clone {
     fn increment(&mut self) -> Self;
+
+    /// The key `count` increments after this one. Used to reserve a block of
+    /// keys at a time.
+    fn advance(&self, count: u64) -> Self {
+        let mut key = self.clone();
+        for _ in 0..count {
+            key.increment();
+        }
+        key
+    }
 }
 
 pub trait QueryTree<Key: PrimaryKey, Value: RecordValue> {
@@ -27,9 +45,3 @@ pub trait QueryTree<Key: PrimaryKey, Value: RecordValue> {
 
     fn bucket(value: &Value) -> Self::ID;
 }
-
-#[derive(serde::Serialize, serde::Deserialize)]
-pub(crate) enum WalEvents {
-    CreateSchema { id: u64, name: String },
-    CreateTree { id: u64, parent: u64, name: String },
-}
@@ -35,8 +35,16 @@ pub trait Aggregate<K, V>: Default {
 /// what can be created for a given record. Before saving the record to disk, check
 /// that the constraint is valid. Return `false` if the record being created does
 /// not match what is expected.
+///
+/// Constraints are added to a tree when it is created with
+/// [`crate::Database::create`].
 pub trait Constraint<T> {
     fn check(&self, t: &T) -> bool;
+
+    /// Name of the constraint that is reported when it rejects a write
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
 }
 
 /// Create an index by implementing a compare function. Declare exactly what the
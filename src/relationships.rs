@@ -15,9 +15,7 @@ pub struct ID<Key: PrimaryKey, Value: RecordValue + 'static> {
 unsafe impl<Key: PrimaryKey, Value: RecordValue + 'static> Send for ID<Key, Value> {}
 unsafe impl<Key: PrimaryKey, Value: RecordValue + 'static> Sync for ID<Key, Value> {}
 
-impl<Key: PrimaryKey, Value: RecordValue + 'static> PrimaryKey for ID<Key, Value> {}
-
-impl<Key: PrimaryKey, Value: RecordValue + 'static> Default for ID<Key, Value> {
+impl<Key: PrimaryKey + Default, Value: RecordValue + 'static> Default for ID<Key, Value> {
     fn default() -> Self {
         Self {
             key: Default::default(),
@@ -26,11 +24,17 @@ impl<Key: PrimaryKey, Value: RecordValue + 'static> Default for ID<Key, Value> {
     }
 }
 
-impl<Key: PrimaryKey, Value: RecordValue + 'static> AutoIncrement for ID<Key, Value> {
+impl<Key: PrimaryKey + AutoIncrement, Value: RecordValue + 'static> AutoIncrement
+    for ID<Key, Value>
+{
     fn increment(&mut self) -> Self {
         let key = self.key.increment();
         Self::new(key)
     }
+
+    fn advance(&self, count: u64) -> Self {
+        Self::new(self.key.advance(count))
+    }
 }
 
 impl<Key: PrimaryKey, Value: RecordValue + 'static> Eq for ID<Key, Value> {}
@@ -47,7 +51,7 @@ impl<Key: PrimaryKey, Value: RecordValue + 'static> Ord for ID<Key, Value> {
 }
 impl<Key: PrimaryKey, Value: RecordValue + 'static> PartialOrd for ID<Key, Value> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.key.partial_cmp(&other.key)
+        Some(self.cmp(other))
     }
 }
 
@@ -88,7 +92,9 @@ impl<Key: PrimaryKey, Value: RecordValue + 'static> From<Key> for ID<Key, Value>
     }
 }
 
-impl<Key: PrimaryKey, Value: RecordValue + 'static> std::fmt::Display for ID<Key, Value> {
+impl<Key: PrimaryKey + std::fmt::Display, Value: RecordValue + 'static> std::fmt::Display
+    for ID<Key, Value>
+{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.key)
     }
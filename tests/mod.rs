@@ -1,6 +1,6 @@
 // use std::path::Path;
 
-// use tokactordb::{Database, FileSystem, Tree, U32};
+// use tokactordb::{Database, DatabaseOptions, Durability, FileSystem, Tree, U32};
 
 // #[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 // struct Counter {
@@ -50,3 +50,1643 @@
 
 //     drop(db);
 // }
+
+use std::{cmp::Ordering, env::temp_dir, path::PathBuf};
+
+use futures::{StreamExt, TryStreamExt};
+use tokactordb::{
+    Aggregate, Change, Constraint, ConstraintViolation, Database, DatabaseOptions, Durability,
+    FileSystem, KeyAlreadyExists, SecondaryIndex, TransactionConflict, Tree, UniqueViolation,
+    Update, U32,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Counter {
+    name: String,
+    count: usize,
+}
+
+impl Counter {
+    fn new(name: impl ToString, count: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            count,
+        }
+    }
+}
+
+struct Db {
+    db: Database,
+    counter: Tree<U32, Counter>,
+}
+
+fn clean_dir(name: &str) -> PathBuf {
+    let path = temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+async fn open(path: &PathBuf) -> Db {
+    open_with(path, DatabaseOptions::new()).await
+}
+
+async fn open_with(path: &PathBuf, options: DatabaseOptions) -> Db {
+    let filesystem = FileSystem::system(path);
+    let db = Database::with_options(filesystem, options).await.unwrap();
+    let counter = db
+        .create::<U32, Counter>("counter")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+    Db { db, counter }
+}
+
+#[tokio::test]
+async fn restore_records_written_before_restart() {
+    let path = clean_dir("tokactordb-restore-records");
+
+    let db = open(&path).await;
+    let first = db.counter.insert(Counter::new("first", 1)).await.unwrap();
+    let second = db.counter.insert(Counter::new("second", 2)).await.unwrap();
+    db.counter
+        .update(first, Counter::new("first", 10))
+        .await
+        .unwrap();
+    drop(db);
+
+    let db = open(&path).await;
+    assert_eq!(
+        db.counter.get(first).await.unwrap(),
+        Some(Counter::new("first", 10))
+    );
+    assert_eq!(
+        db.counter.get(second).await.unwrap(),
+        Some(Counter::new("second", 2))
+    );
+
+    // New keys continue on from the restored records
+    let third = db.counter.insert(Counter::new("third", 3)).await.unwrap();
+    assert!(third > second);
+}
+
+#[tokio::test]
+async fn restore_truncates_torn_wal_tail() {
+    let path = clean_dir("tokactordb-restore-torn-tail");
+
+    let db = open(&path).await;
+    let first = db.counter.insert(Counter::new("first", 1)).await.unwrap();
+    drop(db);
+
+    // Simulate a crash part way through writing the next frame
+    let wal = path.join("wal").join("WAL-0");
+    let len = std::fs::metadata(&wal).unwrap().len();
+    let mut bytes = std::fs::read(&wal).unwrap();
+    bytes.extend_from_slice(&[0, 0, 0, 100, 1, 2, 3]);
+    std::fs::write(&wal, bytes).unwrap();
+
+    let db = open(&path).await;
+    assert_eq!(std::fs::metadata(&wal).unwrap().len(), len);
+    assert_eq!(
+        db.counter.get(first).await.unwrap(),
+        Some(Counter::new("first", 1))
+    );
+    let second = db.counter.insert(Counter::new("second", 2)).await.unwrap();
+    drop(db);
+
+    let db = open(&path).await;
+    assert_eq!(
+        db.counter.get(second).await.unwrap(),
+        Some(Counter::new("second", 2))
+    );
+}
+
+#[tokio::test]
+async fn restore_records_written_with_sync_durability() {
+    let path = clean_dir("tokactordb-restore-synced");
+    let options = DatabaseOptions::new()
+        .durability(Durability::SyncData)
+        .group_commit_records(1);
+
+    let db = open_with(&path, options.clone()).await;
+    let first = db.counter.insert(Counter::new("first", 1)).await.unwrap();
+    let second = db
+        .counter
+        .with_durability(Durability::SyncAll)
+        .insert(Counter::new("second", 2))
+        .await
+        .unwrap();
+    drop(db);
+
+    let db = open_with(&path, options).await;
+    assert_eq!(
+        db.counter.get(first).await.unwrap(),
+        Some(Counter::new("first", 1))
+    );
+    assert_eq!(
+        db.counter.get(second).await.unwrap(),
+        Some(Counter::new("second", 2))
+    );
+}
+
+#[tokio::test]
+async fn restore_records_flushed_to_table_files() {
+    let path = clean_dir("tokactordb-restore-tables");
+    let options = DatabaseOptions::new()
+        .memtable_size(256)
+        .wal_segment_size(512);
+
+    let db = open_with(&path, options.clone()).await;
+    let mut keys = vec![];
+    for i in 0..50 {
+        let key = db.counter.insert(Counter::new("counter", i)).await.unwrap();
+        keys.push(key);
+    }
+    db.counter
+        .update(keys[0], Counter::new("updated", 100))
+        .await
+        .unwrap();
+    drop(db);
+
+    let tables = std::fs::read_dir(path.join("storage")).unwrap().count();
+    assert!(tables > 1);
+
+    let db = open_with(&path, options).await;
+    assert_eq!(
+        db.counter.get(keys[0]).await.unwrap(),
+        Some(Counter::new("updated", 100))
+    );
+    for (i, key) in keys.iter().enumerate().skip(1) {
+        assert_eq!(
+            db.counter.get(*key).await.unwrap(),
+            Some(Counter::new("counter", i))
+        );
+    }
+
+    let count = db
+        .counter
+        .list()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+        .len();
+    assert_eq!(count, keys.len());
+
+    // New keys continue on from the records stored in the table files
+    let key = db.counter.insert(Counter::new("new", 0)).await.unwrap();
+    assert!(!keys.contains(&key));
+}
+
+#[tokio::test]
+async fn compact_table_files_in_the_background() {
+    let path = clean_dir("tokactordb-compact-tables");
+    let options = DatabaseOptions::new()
+        .memtable_size(256)
+        .wal_segment_size(512)
+        .l0_compaction_trigger(2)
+        .compaction_rate(0);
+
+    let db = open_with(&path, options.clone()).await;
+    let mut keys = vec![];
+    for i in 0..200 {
+        let key = db.counter.insert(Counter::new("counter", i)).await.unwrap();
+        keys.push(key);
+    }
+    for (i, key) in keys.iter().enumerate().step_by(10) {
+        db.counter
+            .update(*key, Counter::new("updated", i))
+            .await
+            .unwrap();
+    }
+    // Give the last compaction time to finish
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    drop(db);
+
+    // Without compaction every flushed memtable would still have its own file
+    let tables = std::fs::read_dir(path.join("storage")).unwrap().count();
+    assert!(tables <= 4, "found {} table files", tables);
+
+    let db = open_with(&path, options).await;
+    for (i, key) in keys.iter().enumerate() {
+        let name = if i % 10 == 0 { "updated" } else { "counter" };
+        assert_eq!(
+            db.counter.get(*key).await.unwrap(),
+            Some(Counter::new(name, i))
+        );
+    }
+}
+
+#[tokio::test]
+async fn cache_blocks_read_from_table_files() {
+    let path = clean_dir("tokactordb-block-cache");
+    let options = DatabaseOptions::new()
+        .memtable_size(256)
+        .block_cache_size(64 * 1024);
+
+    let db = open_with(&path, options).await;
+    let mut keys = vec![];
+    for i in 0..50 {
+        let key = db.counter.insert(Counter::new("counter", i)).await.unwrap();
+        keys.push(key);
+    }
+    let before = db.db.block_cache_stats();
+    for _ in 0..2 {
+        assert_eq!(
+            db.counter.get(keys[0]).await.unwrap(),
+            Some(Counter::new("counter", 0))
+        );
+    }
+
+    // The first read loads the block and the second one finds it in the cache
+    let after = db.db.block_cache_stats();
+    assert_eq!(after.misses - before.misses, 1);
+    assert_eq!(after.hits - before.hits, 1);
+    assert!(after.size > 0 && after.size <= after.capacity);
+}
+
+#[tokio::test]
+async fn order_keys_numerically() {
+    let path = clean_dir("tokactordb-key-order");
+    let options = DatabaseOptions::new().memtable_size(1024);
+
+    let db = open_with(&path, options.clone()).await;
+    for i in 0..300 {
+        db.counter.insert(Counter::new("counter", i)).await.unwrap();
+    }
+    let (first, _) = db.counter.get_first().await.unwrap().unwrap();
+    let (last, _) = db.counter.get_last().await.unwrap().unwrap();
+    assert_eq!((*first, *last), (0, 299));
+
+    let mut list = db.counter.list();
+    let mut expected = 0;
+    while let Some((key, value)) = list.try_next().await.unwrap() {
+        assert_eq!(*key, expected);
+        assert_eq!(value, Counter::new("counter", expected as usize));
+        expected += 1;
+    }
+    assert_eq!(expected, 300);
+    drop(db);
+
+    // New keys continue on after the block of keys reserved before the
+    // restart, so some keys are skipped
+    let db = open_with(&path, options).await;
+    let key = db.counter.insert(Counter::new("new", 0)).await.unwrap();
+    assert!(*key >= 300);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Ticket {
+    board: U32,
+    name: String,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct BoardStats {
+    total: usize,
+}
+
+impl Aggregate<U32, Ticket> for BoardStats {
+    fn observe(&mut self, change: Change<&U32, &Ticket>) {
+        match change.update {
+            Update::Set { old: None, .. } => self.total += 1,
+            Update::Set { .. } => {}
+            Update::Del { .. } => self.total -= 1,
+        }
+    }
+}
+
+#[tokio::test]
+async fn delete_records_from_trees_indexes_and_aggregates() {
+    let path = clean_dir("tokactordb-delete");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db
+        .create::<U32, Ticket>("tickets")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    let board_tickets = db
+        .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    let board_stats = db
+        .create_aggregate("board stats", &tickets, BoardStats::default(), |ticket| {
+            Some(&ticket.board)
+        })
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let board = U32::new(7);
+    let ticket = |name: &str| Ticket {
+        board,
+        name: name.to_string(),
+    };
+    let first = tickets.insert(ticket("first")).await.unwrap();
+    let second = tickets.insert(ticket("second")).await.unwrap();
+    assert_eq!(board_stats.get(board).await.unwrap().unwrap().total, 2);
+
+    assert_eq!(tickets.delete(first).await.unwrap(), Some(ticket("first")));
+    assert_eq!(tickets.get(first).await.unwrap(), None);
+    assert_eq!(tickets.delete(first).await.unwrap(), None);
+
+    assert_eq!(
+        board_tickets.list(board).await.unwrap(),
+        vec![ticket("second")]
+    );
+    assert_eq!(board_stats.get(board).await.unwrap().unwrap().total, 1);
+
+    let live = tickets
+        .list()
+        .map_ok(|(key, _)| key)
+        .try_collect::<Vec<_>>();
+    assert_eq!(live.await.unwrap(), vec![second]);
+
+    // Deleted keys are never handed out again
+    let third = tickets.insert(ticket("third")).await.unwrap();
+    assert!(third > second);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct TaggedTicket {
+    name: String,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct TagStats {
+    total: usize,
+    updates: usize,
+}
+
+impl Aggregate<U32, TaggedTicket> for TagStats {
+    fn observe(&mut self, change: Change<&U32, &TaggedTicket>) {
+        match change.update {
+            Update::Set { old: None, .. } => self.total += 1,
+            Update::Set { .. } => self.updates += 1,
+            Update::Del { .. } => self.total -= 1,
+        }
+    }
+}
+
+#[tokio::test]
+async fn index_records_that_belong_to_many_buckets() {
+    let path = clean_dir("tokactordb-multi-index");
+    let open = || async {
+        let db = Database::new(FileSystem::system(&path)).await.unwrap();
+        let tickets = db.create::<U32, TaggedTicket>("tickets").unwrap();
+        let tickets = tickets.unwrap().await.unwrap();
+        let by_tag = db
+            .create_multi_index("tickets by tag", &tickets, |ticket| ticket.tags.clone())
+            .await
+            .unwrap();
+        let tag_stats = db
+            .create_multi_aggregate("tag stats", &tickets, TagStats::default(), |ticket| {
+                ticket.tags.clone()
+            })
+            .await
+            .unwrap();
+        db.restore().await.unwrap();
+        (db, tickets, by_tag, tag_stats)
+    };
+    let ticket = |name: &str, tags: &[&str]| TaggedTicket {
+        name: name.to_string(),
+        tags: tags.iter().map(|tag| tag.to_string()).collect(),
+    };
+
+    let (db, tickets, by_tag, tag_stats) = open().await;
+    let a = tickets.insert(ticket("a", &["bug", "ui"])).await.unwrap();
+    let b = tickets.insert(ticket("b", &["bug", "bug"])).await.unwrap();
+    let c = tickets.insert(ticket("c", &[])).await.unwrap();
+    assert_eq!(
+        by_tag.list("bug".to_string()).await.unwrap(),
+        vec![ticket("a", &["bug", "ui"]), ticket("b", &["bug", "bug"])]
+    );
+    assert_eq!(
+        by_tag.list("ui".to_string()).await.unwrap(),
+        vec![ticket("a", &["bug", "ui"])]
+    );
+    let stats = tag_stats.get("bug".to_string()).await.unwrap().unwrap();
+    assert_eq!(stats.total, 2);
+
+    // Only the buckets whose membership changed see a create or delete
+    tickets
+        .modify(a, |ticket| ticket.tags = vec!["ui".into(), "docs".into()])
+        .await
+        .unwrap();
+    tickets
+        .modify(c, |ticket| ticket.name = "still untagged".into())
+        .await
+        .unwrap();
+    tickets.delete(b).await.unwrap();
+    let a_ticket = ticket("a", &["ui", "docs"]);
+    assert_eq!(by_tag.list("bug".to_string()).await.unwrap(), vec![]);
+    assert_eq!(
+        by_tag.list("docs".to_string()).await.unwrap(),
+        vec![a_ticket.clone()]
+    );
+    let ui = tag_stats.get("ui".to_string()).await.unwrap().unwrap();
+    assert_eq!((ui.total, ui.updates), (1, 1));
+    let bug = tag_stats.get("bug".to_string()).await.unwrap().unwrap();
+    assert_eq!(bug.total, 0);
+    drop((db, tickets, by_tag, tag_stats));
+
+    let (_db, _tickets, by_tag, tag_stats) = open().await;
+    assert_eq!(by_tag.list("ui".to_string()).await.unwrap(), vec![a_ticket]);
+    let docs = tag_stats.get("docs".to_string()).await.unwrap().unwrap();
+    assert_eq!(docs.total, 1);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Task {
+    board: U32,
+    email: String,
+    completed: bool,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct TaskStats {
+    total: usize,
+}
+
+impl Aggregate<U32, Task> for TaskStats {
+    fn observe(&mut self, change: Change<&U32, &Task>) {
+        match change.update {
+            Update::Set { old: None, .. } => self.total += 1,
+            Update::Set { .. } => {}
+            Update::Del { .. } => self.total -= 1,
+        }
+    }
+}
+
+#[tokio::test]
+async fn index_records_by_computed_and_composite_identities() {
+    let path = clean_dir("tokactordb-computed-index");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tasks = db.create::<U32, Task>("tasks").unwrap();
+    let tasks = tasks.unwrap().await.unwrap();
+    let by_progress = db
+        .create_computed_index("tasks by progress", &tasks, |task| {
+            Some((task.board, task.completed))
+        })
+        .await
+        .unwrap();
+    let by_email = db
+        .create_computed_index("tasks by email", &tasks, |task| {
+            Some(task.email.to_lowercase())
+        })
+        .await
+        .unwrap();
+    let progress = db
+        .create_computed_aggregate("board progress", &tasks, TaskStats::default(), |task| {
+            Some((task.board, task.completed))
+        })
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let task = |board: u32, email: &str, completed: bool| Task {
+        board: U32::new(board),
+        email: email.to_string(),
+        completed,
+    };
+    let first = tasks
+        .insert(task(7, "Ann@Example.com", false))
+        .await
+        .unwrap();
+    tasks
+        .insert(task(7, "bob@example.com", true))
+        .await
+        .unwrap();
+    tasks
+        .insert(task(7, "cat@example.com", false))
+        .await
+        .unwrap();
+    tasks
+        .insert(task(8, "dan@example.com", false))
+        .await
+        .unwrap();
+
+    // All incomplete tickets on board 7
+    let incomplete = by_progress.list((U32::new(7), false)).await.unwrap();
+    assert_eq!(
+        incomplete,
+        vec![
+            task(7, "Ann@Example.com", false),
+            task(7, "cat@example.com", false)
+        ]
+    );
+    let board = by_progress.list_prefix(&U32::new(7)).await.unwrap();
+    assert_eq!(
+        board,
+        vec![
+            task(7, "Ann@Example.com", false),
+            task(7, "cat@example.com", false),
+            task(7, "bob@example.com", true),
+        ]
+    );
+    assert_eq!(
+        by_email.list("ann@example.com".to_string()).await.unwrap(),
+        vec![task(7, "Ann@Example.com", false)]
+    );
+
+    tasks
+        .modify(first, |task| task.completed = true)
+        .await
+        .unwrap();
+    let totals = progress.get_prefix(&U32::new(7)).await.unwrap();
+    let totals = totals
+        .into_iter()
+        .map(|(id, stats)| (id, stats.total))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        totals,
+        vec![((U32::new(7), false), 1), ((U32::new(7), true), 2)]
+    );
+}
+
+#[tokio::test]
+async fn read_records_by_the_prefix_of_their_key() {
+    let path = clean_dir("tokactordb-key-prefix");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let cells = db.create::<(U32, String, U32), Counter>("cells").unwrap();
+    let cells = cells.unwrap().await.unwrap();
+    db.restore().await.unwrap();
+
+    let key = |a: u32, b: &str, c: u32| (U32::new(a), b.to_string(), U32::new(c));
+    for (a, b, c) in [
+        (1, "a", 1),
+        (2, "a", 2),
+        (2, "a", 1),
+        (2, "ab", 1),
+        (3, "", 0),
+    ] {
+        cells
+            .put(key(a, b, c), Counter::new(b, c as usize))
+            .await
+            .unwrap();
+    }
+
+    let keys = |records: Vec<(_, Counter)>| records.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(
+        keys(cells.prefix(&U32::new(2)).await.unwrap()),
+        vec![key(2, "a", 1), key(2, "a", 2), key(2, "ab", 1)]
+    );
+    assert_eq!(
+        keys(cells.prefix(&(U32::new(2), "a".to_string())).await.unwrap()),
+        vec![key(2, "a", 1), key(2, "a", 2)]
+    );
+    assert!(cells.prefix(&U32::new(4)).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn backfill_indexes_created_on_populated_trees() {
+    let path = clean_dir("tokactordb-backfill");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+    let tickets = std::sync::Arc::new(tickets.unwrap().await.unwrap());
+    db.restore().await.unwrap();
+
+    let ticket = |board: u32, name: String| Ticket {
+        board: U32::new(board),
+        name,
+    };
+    for i in 0..40 {
+        let board = i % 2 + 1;
+        tickets
+            .insert(ticket(board, format!("old {i}")))
+            .await
+            .unwrap();
+    }
+
+    // Keep writing while the index and aggregate are built
+    let writer = {
+        let tickets = tickets.clone();
+        tokio::spawn(async move {
+            for i in 0..30 {
+                let key = tickets.insert(ticket(1, format!("new {i}"))).await.unwrap();
+                if i % 3 == 0 {
+                    tickets.delete(key).await.unwrap();
+                } else if i % 3 == 1 {
+                    let moved = tickets.modify(key, |ticket| ticket.board = U32::new(2));
+                    moved.await.unwrap();
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+    let board_tickets = db
+        .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    let board_stats = db
+        .create_aggregate("board stats", &tickets, BoardStats::default(), |ticket| {
+            Some(&ticket.board)
+        })
+        .await
+        .unwrap();
+    writer.await.unwrap();
+
+    for board in [1, 2] {
+        let mut expected = tickets
+            .list()
+            .try_filter(|(_, ticket)| futures::future::ready(ticket.board == U32::new(board)))
+            .map_ok(|(_, ticket)| ticket.name)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut indexed = board_tickets
+            .list(U32::new(board))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|ticket| ticket.name)
+            .collect::<Vec<_>>();
+        expected.sort();
+        indexed.sort();
+        assert_eq!(indexed, expected);
+        let stats = board_stats.get(U32::new(board)).await.unwrap().unwrap();
+        assert_eq!(stats.total, expected.len());
+    }
+    drop((db, tickets, board_tickets, board_stats));
+
+    // The backfilled index was saved like any other tree
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+    let tickets = tickets.unwrap().await.unwrap();
+    let board_tickets = db
+        .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+    let board = board_tickets.list(U32::new(1)).await.unwrap();
+    assert_eq!(board.len(), 20 + 10);
+}
+
+/// Sum of the name lengths of the tickets on a board, which counts an update
+/// that is observed twice
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct NameLengths {
+    total: usize,
+}
+
+impl Aggregate<U32, Ticket> for NameLengths {
+    fn observe(&mut self, change: Change<&U32, &Ticket>) {
+        match change.update {
+            Update::Set { old, new } => {
+                self.total -= old.map(|old| old.name.len()).unwrap_or(0);
+                self.total += new.name.len();
+            }
+            Update::Del { old } => self.total -= old.name.len(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn backfill_trees_a_page_at_a_time_while_they_are_written() {
+    let path = clean_dir("tokactordb-backfill-pages");
+    let options = DatabaseOptions::new().durability(Durability::None);
+    let db = Database::with_options(FileSystem::system(&path), options)
+        .await
+        .unwrap();
+    let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+    let tickets = std::sync::Arc::new(tickets.unwrap().await.unwrap());
+    db.restore().await.unwrap();
+
+    let ticket = |board: u32, name: String| Ticket {
+        board: U32::new(board),
+        name,
+    };
+    let mut keys = Vec::new();
+    for i in 0..1000 {
+        let key = tickets.insert(ticket(i % 3, format!("old {i}"))).await;
+        keys.push(key.unwrap());
+    }
+
+    // Once the first page has been read, write to records on both sides of it
+    let mut progress = db.backfill_progress();
+    let writers = {
+        let (tickets, mut progress) = (tickets.clone(), progress.clone());
+        tokio::spawn(async move {
+            progress.changed().await.unwrap();
+            let writes = (0..100).map(|i| {
+                let (tickets, key) = (tickets.clone(), keys[i * 37 % keys.len()]);
+                tokio::spawn(async move {
+                    match i % 3 {
+                        0 => drop(tickets.delete(key).await.unwrap()),
+                        1 => {
+                            let renamed = tickets.modify(key, |ticket| ticket.name += " renamed");
+                            renamed.await.unwrap();
+                        }
+                        _ => drop(tickets.insert(ticket(1, format!("new {i}"))).await.unwrap()),
+                    }
+                })
+            });
+            futures::future::try_join_all(writes).await.unwrap();
+        })
+    };
+    let name_lengths = db
+        .create_aggregate("name lengths", &tickets, NameLengths::default(), |ticket| {
+            Some(&ticket.board)
+        })
+        .await
+        .unwrap();
+    let backfilled = progress.borrow_and_update().clone().unwrap();
+    assert_eq!(backfilled.name, "name lengths");
+    assert!(backfilled.done);
+    writers.await.unwrap();
+
+    // Every write was observed once, either by the backfill or as a change
+    let records = tickets.list().try_collect::<Vec<_>>().await.unwrap();
+    for board in 0..3 {
+        let expected = records
+            .iter()
+            .filter(|(_, ticket)| ticket.board == U32::new(board))
+            .map(|(_, ticket)| ticket.name.len())
+            .sum::<usize>();
+        let lengths = name_lengths.get(U32::new(board)).await.unwrap().unwrap();
+        assert_eq!(lengths.total, expected);
+    }
+}
+
+#[tokio::test]
+async fn insert_records_concurrently() {
+    let path = clean_dir("tokactordb-concurrent-inserts");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db
+        .create::<U32, Ticket>("tickets")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    let board_stats = db
+        .create_aggregate("board stats", &tickets, BoardStats::default(), |ticket| {
+            Some(&ticket.board)
+        })
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let board = U32::new(3);
+    let tickets = std::sync::Arc::new(tickets);
+    let inserts = (0..50).map(|i| {
+        let tickets = tickets.clone();
+        tokio::spawn(async move {
+            let ticket = Ticket {
+                board,
+                name: format!("ticket {i}"),
+            };
+            tickets.insert(ticket).await.unwrap()
+        })
+    });
+    let mut keys = futures::future::try_join_all(inserts).await.unwrap();
+    keys.sort();
+    keys.dedup();
+    assert_eq!(keys.len(), 50);
+
+    // Subscribers are told about every insert before it returns
+    assert_eq!(board_stats.get(board).await.unwrap().unwrap().total, 50);
+}
+
+#[tokio::test]
+async fn restore_deleted_records() {
+    let path = clean_dir("tokactordb-restore-deleted");
+    let options = DatabaseOptions::new().memtable_size(256);
+
+    let db = open_with(&path, options.clone()).await;
+    let mut keys = vec![];
+    for i in 0..20 {
+        keys.push(db.counter.insert(Counter::new("counter", i)).await.unwrap());
+    }
+    for key in keys.iter().step_by(2) {
+        db.counter.delete(*key).await.unwrap().unwrap();
+    }
+    drop(db);
+
+    let db = open_with(&path, options).await;
+    for (i, key) in keys.iter().enumerate() {
+        let expected = (i % 2 == 1).then(|| Counter::new("counter", i));
+        assert_eq!(db.counter.get(*key).await.unwrap(), expected);
+    }
+}
+
+async fn open_tickets(path: &PathBuf) -> (Database, Tree<U32, Ticket>) {
+    let db = Database::new(FileSystem::system(path)).await.unwrap();
+    let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+    let tickets = tickets.unwrap().await.unwrap();
+    (db, tickets)
+}
+
+#[tokio::test]
+async fn restore_indexes_and_aggregates_of_deleted_records() {
+    let path = clean_dir("tokactordb-restore-deleted-sub-trees");
+    let ticket = |board: u32, name: &str| Ticket {
+        board: U32::new(board),
+        name: name.to_string(),
+    };
+
+    let (db, tickets) = open_tickets(&path).await;
+    db.create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    db.create_aggregate("board stats", &tickets, BoardStats::default(), |ticket| {
+        Some(&ticket.board)
+    })
+    .await
+    .unwrap();
+    db.restore().await.unwrap();
+    let moved = tickets.insert(ticket(7, "moved")).await.unwrap();
+    let deleted = tickets.insert(ticket(7, "deleted")).await.unwrap();
+    tickets.update(moved, ticket(8, "moved")).await.unwrap();
+    tickets.delete(deleted).await.unwrap();
+    drop((db, tickets));
+
+    let (db, tickets) = open_tickets(&path).await;
+    let board_tickets = db
+        .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    let board_stats = db
+        .create_aggregate("board stats", &tickets, BoardStats::default(), |ticket| {
+            Some(&ticket.board)
+        })
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+    assert!(board_tickets.list(U32::new(7)).await.unwrap().is_empty());
+    assert_eq!(
+        board_stats.get(U32::new(7)).await.unwrap().unwrap().total,
+        0
+    );
+    assert_eq!(
+        board_tickets.list(U32::new(8)).await.unwrap(),
+        vec![ticket(8, "moved")]
+    );
+    assert_eq!(
+        board_stats.get(U32::new(8)).await.unwrap().unwrap().total,
+        1
+    );
+}
+
+#[tokio::test]
+async fn never_reuse_keys_of_deleted_records() {
+    let path = clean_dir("tokactordb-never-reuse-keys");
+
+    let db = open(&path).await;
+    let mut keys = vec![];
+    for i in 0..5 {
+        keys.push(db.counter.insert(Counter::new("counter", i)).await.unwrap());
+    }
+    // Without any records left the largest key can't be found by scanning
+    for key in &keys {
+        db.counter.delete(*key).await.unwrap().unwrap();
+    }
+    let key = db.counter.insert(Counter::new("next", 5)).await.unwrap();
+    assert!(keys.iter().all(|issued| key > *issued));
+    db.counter.delete(key).await.unwrap().unwrap();
+    keys.push(key);
+    drop(db);
+
+    let db = open(&path).await;
+    let key = db
+        .counter
+        .insert(Counter::new("restarted", 6))
+        .await
+        .unwrap();
+    assert!(keys.iter().all(|issued| key > *issued));
+}
+
+#[tokio::test]
+async fn scan_ranges_of_records() {
+    let path = clean_dir("tokactordb-range");
+    let options = DatabaseOptions::new().memtable_size(512);
+
+    let db = open_with(&path, options).await;
+    for i in 0..100 {
+        db.counter.insert(Counter::new("counter", i)).await.unwrap();
+    }
+    db.counter.delete(U32::new(50)).await.unwrap();
+    let keys = |records: Vec<(U32, Counter)>| {
+        records
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value.count, *key as usize);
+                *key
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let range = db.counter.range(U32::new(45)..U32::new(55)).await.unwrap();
+    assert_eq!(keys(range), vec![45, 46, 47, 48, 49, 51, 52, 53, 54]);
+
+    let range = db.counter.range_rev(U32::new(95)..).await.unwrap();
+    assert_eq!(keys(range), vec![99, 98, 97, 96, 95]);
+
+    let range = db.counter.range(..=U32::new(2)).await.unwrap();
+    assert_eq!(keys(range), vec![0, 1, 2]);
+
+    let range = db.counter.range(U32::new(10)..U32::new(5)).await.unwrap();
+    assert!(range.is_empty());
+
+    // Page through every record
+    let mut pages = vec![];
+    let mut next = U32::new(0);
+    loop {
+        let page = keys(db.counter.scan_from(next, 30).await.unwrap());
+        match page.last() {
+            Some(last) => next = U32::new(last + 1),
+            None => break,
+        }
+        pages.push(page);
+    }
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![30, 30, 30, 9]
+    );
+    assert_eq!(pages.concat().len(), 99);
+}
+
+#[tokio::test]
+async fn stream_records_in_batches() {
+    let path = clean_dir("tokactordb-list");
+    let options = DatabaseOptions::new().memtable_size(512);
+
+    let db = open_with(&path, options).await;
+    for i in 0..100 {
+        db.counter.insert(Counter::new("counter", i)).await.unwrap();
+    }
+    for i in (0..100).step_by(3) {
+        db.counter.delete(U32::new(i)).await.unwrap();
+    }
+
+    // Batches that don't divide the number of records evenly
+    let keys = db
+        .counter
+        .list()
+        .batch_size(7)
+        .map_ok(|(key, value)| {
+            assert_eq!(value.count, *key as usize);
+            *key
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(keys, (0..100).filter(|i| i % 3 != 0).collect::<Vec<_>>());
+
+    // Stop reading part way through the tree
+    let first = db.counter.list().batch_size(10).take(5).collect::<Vec<_>>();
+    let first = first.await.into_iter().map(|r| *r.unwrap().0);
+    assert_eq!(first.collect::<Vec<_>>(), vec![1, 2, 4, 5, 7]);
+}
+
+#[tokio::test]
+async fn commit_writes_to_many_trees_together() {
+    let path = clean_dir("tokactordb-batch");
+    let open = || async {
+        let db = Database::new(FileSystem::system(&path)).await.unwrap();
+        let counter = db.create::<U32, Counter>("counter").unwrap();
+        let counter = counter.unwrap().await.unwrap();
+        let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+        let tickets = tickets.unwrap().await.unwrap();
+        let board_tickets = db
+            .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+            .await
+            .unwrap();
+        db.restore().await.unwrap();
+        (db, counter, tickets, board_tickets)
+    };
+    let board = U32::new(3);
+    let ticket = |name: &str| Ticket {
+        board,
+        name: name.to_string(),
+    };
+
+    let (db, counter, tickets, board_tickets) = open().await;
+    let old = tickets.insert(ticket("old")).await.unwrap();
+    let count = counter.insert(Counter::new("tickets", 1)).await.unwrap();
+
+    let mut batch = db.batch();
+    let first = batch.insert(&tickets, ticket("first")).await.unwrap();
+    let second = batch.insert(&tickets, ticket("second")).await.unwrap();
+    batch.delete(&tickets, old).await.unwrap();
+    batch
+        .update(&counter, count, Counter::new("tickets", 2))
+        .await
+        .unwrap();
+    assert!(batch
+        .update(&tickets, first, ticket("again"))
+        .await
+        .is_err());
+    assert_eq!(batch.len(), 4);
+
+    // Nothing is visible until the batch is committed
+    assert_eq!(tickets.get(first).await.unwrap(), None);
+    assert_eq!(tickets.get(old).await.unwrap(), Some(ticket("old")));
+    batch.commit().await.unwrap();
+
+    assert_eq!(tickets.get(first).await.unwrap(), Some(ticket("first")));
+    assert_eq!(tickets.get(old).await.unwrap(), None);
+    assert_eq!(
+        board_tickets.list(board).await.unwrap(),
+        vec![ticket("first"), ticket("second")]
+    );
+    drop((db, counter, tickets, board_tickets));
+
+    let (_db, counter, tickets, _) = open().await;
+    assert_eq!(
+        counter.get(count).await.unwrap(),
+        Some(Counter::new("tickets", 2))
+    );
+    assert_eq!(tickets.get(old).await.unwrap(), None);
+    assert_eq!(tickets.get(first).await.unwrap(), Some(ticket("first")));
+    assert_eq!(tickets.get(second).await.unwrap(), Some(ticket("second")));
+}
+
+#[tokio::test]
+async fn retry_transactions_that_conflict() {
+    let path = clean_dir("tokactordb-transaction");
+    let db = open(&path).await;
+    let key = db.counter.insert(Counter::new("count", 0)).await.unwrap();
+
+    // Every increment reads the count and writes it back, so concurrent
+    // increments conflict with each other
+    let increment = || {
+        db.db.transaction(|tx| {
+            let counter = &db.counter;
+            async move {
+                let mut value = tx.get(counter, key).await?.unwrap();
+                value.count += 1;
+                tx.update(counter, key, value).await?;
+                Ok(())
+            }
+        })
+    };
+    let results = futures::future::join_all((0..10).map(|_| increment())).await;
+    assert!(results.iter().all(Result::is_ok));
+    assert_eq!(db.counter.get(key).await.unwrap().unwrap().count, 10);
+
+    // A failed transaction writes nothing
+    let result: anyhow::Result<()> = db
+        .db
+        .transaction(|tx| {
+            let counter = &db.counter;
+            async move {
+                tx.insert(counter, Counter::new("never", 0)).await?;
+                tx.update(counter, key, Counter::new("count", 0)).await?;
+                anyhow::bail!("abort")
+            }
+        })
+        .await;
+    assert!(result.is_err());
+    assert_eq!(db.counter.get(key).await.unwrap().unwrap().count, 10);
+    assert_eq!(db.counter.list().count().await, 1);
+}
+
+#[tokio::test]
+async fn return_conflicts_once_out_of_retries() {
+    let path = clean_dir("tokactordb-transaction-conflict");
+    let db = open_with(&path, DatabaseOptions::new().transaction_retries(0)).await;
+    let key = db.counter.insert(Counter::new("count", 0)).await.unwrap();
+
+    let result = db
+        .db
+        .transaction(|tx| {
+            let counter = &db.counter;
+            async move {
+                let value = tx.get(counter, key).await?.unwrap();
+                // Another writer changes the record after it was read
+                counter.update(key, Counter::new("other", 5)).await?;
+                tx.update(counter, key, Counter::new("tx", value.count + 1))
+                    .await?;
+                Ok(())
+            }
+        })
+        .await;
+    let err = result.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<TransactionConflict>().unwrap().tree(),
+        "counter"
+    );
+    assert_eq!(
+        db.counter.get(key).await.unwrap(),
+        Some(Counter::new("other", 5))
+    );
+}
+
+#[tokio::test]
+async fn conflict_with_records_that_were_changed_back() {
+    let path = clean_dir("tokactordb-transaction-aba");
+    let db = open_with(&path, DatabaseOptions::new().transaction_retries(0)).await;
+    let key = db.counter.insert(Counter::new("count", 0)).await.unwrap();
+
+    let result = db
+        .db
+        .transaction(|tx| {
+            let counter = &db.counter;
+            async move {
+                let value = tx.get(counter, key).await?.unwrap();
+                // The record holds the same value again by the time it commits
+                counter.update(key, Counter::new("count", 5)).await?;
+                counter.update(key, Counter::new("count", 0)).await?;
+                tx.update(counter, key, Counter::new("count", value.count + 1))
+                    .await?;
+                Ok(())
+            }
+        })
+        .await;
+    assert!(result.unwrap_err().is::<TransactionConflict>());
+    assert_eq!(db.counter.get(key).await.unwrap().unwrap().count, 0);
+}
+
+#[tokio::test]
+async fn modify_records_without_losing_writes() {
+    let path = clean_dir("tokactordb-modify");
+    let db = open(&path).await;
+    let key = db.counter.insert(Counter::new("count", 0)).await.unwrap();
+
+    let increments = (0..50).map(|_| db.counter.modify(key, |value| value.count += 1));
+    let results = futures::future::join_all(increments).await;
+    assert!(results.into_iter().all(|result| result.unwrap().is_some()));
+    assert_eq!(db.counter.get(key).await.unwrap().unwrap().count, 50);
+
+    let missing = db.counter.modify(U32::new(1000), |value| value.count += 1);
+    assert_eq!(missing.await.unwrap(), None);
+    assert_eq!(db.counter.get(U32::new(1000)).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn compare_and_swap_records() {
+    let path = clean_dir("tokactordb-compare-and-swap");
+    let db = open(&path).await;
+    let key = db.counter.insert(Counter::new("open", 0)).await.unwrap();
+
+    let open = Counter::new("open", 0);
+    let closed = Counter::new("closed", 0);
+    assert!(db
+        .counter
+        .compare_and_swap(key, Some(&open), closed.clone())
+        .await
+        .unwrap());
+    // The record no longer holds the expected value
+    assert!(!db
+        .counter
+        .compare_and_swap(key, Some(&open), Counter::new("reopened", 0))
+        .await
+        .unwrap());
+    assert_eq!(db.counter.get(key).await.unwrap(), Some(closed));
+
+    // Only create a record if it doesn't exist yet
+    let new = U32::new(1000);
+    let create = || {
+        db.counter
+            .compare_and_swap(new, None, Counter::new("new", 1))
+    };
+    assert!(create().await.unwrap());
+    assert!(!create().await.unwrap());
+}
+
+#[tokio::test]
+async fn write_records_with_keys_picked_by_the_caller() {
+    let path = clean_dir("tokactordb-natural-keys");
+    let open = || async {
+        let db = Database::new(FileSystem::system(&path)).await.unwrap();
+        let users = db.create::<String, Counter>("users").unwrap();
+        let users = users.unwrap().await.unwrap();
+        let scores = db.create::<(u32, String), Counter>("scores").unwrap();
+        let scores = scores.unwrap().await.unwrap();
+        db.restore().await.unwrap();
+        (db, users, scores)
+    };
+
+    let (db, users, scores) = open().await;
+    users
+        .insert_new("alice@example.com", Counter::new("alice", 1))
+        .await
+        .unwrap();
+    let err = users
+        .insert_new("alice@example.com", Counter::new("impostor", 0))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KeyAlreadyExists>().unwrap().tree(),
+        "users"
+    );
+    users
+        .put("bob@example.com", Counter::new("bob", 2))
+        .await
+        .unwrap();
+    users
+        .put("alice@example.com", Counter::new("alice", 3))
+        .await
+        .unwrap();
+
+    for (board, name) in [(2, "b"), (1, "z"), (1, "a")] {
+        let key = (board, name.to_string());
+        scores.put(key, Counter::new(name, 0)).await.unwrap();
+    }
+    drop((db, users, scores));
+
+    let (_db, users, scores) = open().await;
+    assert_eq!(
+        users.get("alice@example.com").await.unwrap(),
+        Some(Counter::new("alice", 3))
+    );
+    let emails = users.list().map_ok(|(key, _)| key).try_collect::<Vec<_>>();
+    assert_eq!(
+        emails.await.unwrap(),
+        vec!["alice@example.com", "bob@example.com"]
+    );
+
+    // Composite keys are ordered by their first component and then the next
+    let board = (1, String::new())..(2, String::new());
+    let keys = scores.range(board).await.unwrap();
+    let keys = keys.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    assert_eq!(keys, vec![(1, "a".to_string()), (1, "z".to_string())]);
+}
+
+#[tokio::test]
+async fn tell_indexes_about_puts_before_returning() {
+    let path = clean_dir("tokactordb-put-index");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db
+        .create::<U32, Ticket>("tickets")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    let board_tickets = db
+        .create_index("board tickets", &tickets, |ticket| Some(&ticket.board))
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let ticket = |board: u32| Ticket {
+        board: U32::new(board),
+        name: "moved".to_string(),
+    };
+    let key = U32::new(1);
+    tickets.put(key, ticket(1)).await.unwrap();
+    assert_eq!(
+        board_tickets.list(U32::new(1)).await.unwrap(),
+        vec![ticket(1)]
+    );
+
+    // The index moves the key out of the bucket of the old value
+    tickets.put(key, ticket(2)).await.unwrap();
+    assert!(board_tickets.list(U32::new(1)).await.unwrap().is_empty());
+    assert_eq!(
+        board_tickets.list(U32::new(2)).await.unwrap(),
+        vec![ticket(2)]
+    );
+}
+
+/// Board that can't be encoded into a key once it has been archived
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+struct ArchivableBoard(u32);
+
+impl serde::Serialize for ArchivableBoard {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 == u32::MAX {
+            return Err(serde::ser::Error::custom("the board was archived"));
+        }
+        serializer.serialize_u32(self.0)
+    }
+}
+
+#[tokio::test]
+async fn return_failed_index_writes_to_the_writer() {
+    let path = clean_dir("tokactordb-failed-index");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let tickets = db.create::<U32, Ticket>("tickets").unwrap();
+    let tickets = tickets.unwrap().await.unwrap();
+    let board = |ticket: &Ticket| Some(ArchivableBoard(*ticket.board));
+    let board_tickets = db
+        .create_computed_index("board tickets", &tickets, board)
+        .await
+        .unwrap();
+    let board_stats = db
+        .create_computed_aggregate("board stats", &tickets, BoardStats::default(), board)
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let ticket = |board: u32| Ticket {
+        board: U32::new(board),
+        name: "ticket".to_string(),
+    };
+    assert!(tickets.insert(ticket(u32::MAX)).await.is_err());
+
+    // Both sub trees keep working after a write to them failed
+    tickets.insert(ticket(1)).await.unwrap();
+    assert_eq!(
+        board_tickets.list(ArchivableBoard(1)).await.unwrap(),
+        vec![ticket(1)]
+    );
+    let stats = board_stats.get(ArchivableBoard(1)).await.unwrap();
+    assert_eq!(stats.unwrap().total, 1);
+}
+
+struct HasName;
+
+impl Constraint<Counter> for HasName {
+    fn check(&self, counter: &Counter) -> bool {
+        !counter.name.is_empty()
+    }
+
+    fn name(&self) -> &str {
+        "has name"
+    }
+}
+
+#[tokio::test]
+async fn reject_writes_that_break_constraints() {
+    let path = clean_dir("tokactordb-constraints");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let counters = db
+        .create::<U32, Counter>("counters")
+        .unwrap()
+        .constraint(HasName)
+        .unwrap()
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let assert_rejected = |result: anyhow::Error| {
+        let violation = result.downcast::<ConstraintViolation>().unwrap();
+        assert_eq!(violation.tree(), "counters");
+        assert_eq!(violation.constraint(), "has name");
+    };
+
+    let key = counters.insert(Counter::new("first", 1)).await.unwrap();
+    assert_rejected(counters.insert(Counter::new("", 2)).await.unwrap_err());
+    assert_rejected(counters.update(key, Counter::new("", 3)).await.unwrap_err());
+    assert_rejected(
+        counters
+            .modify(key, |counter| counter.name.clear())
+            .await
+            .unwrap_err(),
+    );
+
+    let mut batch = db.batch();
+    assert_rejected(
+        batch
+            .insert(&counters, Counter::new("", 4))
+            .await
+            .unwrap_err(),
+    );
+    assert!(batch.is_empty());
+
+    // Nothing that was rejected was written
+    let records = counters.list().try_collect::<Vec<_>>().await.unwrap();
+    assert_eq!(records, vec![(key, Counter::new("first", 1))]);
+}
+
+struct ByCount;
+
+impl SecondaryIndex<Counter> for ByCount {
+    fn compare(&self, a: &Counter, b: &Counter) -> Ordering {
+        a.count.cmp(&b.count)
+    }
+}
+
+#[tokio::test]
+async fn sort_records_with_a_secondary_index() {
+    let path = clean_dir("tokactordb-sorted-index");
+    let open = || async {
+        let db = Database::new(FileSystem::system(&path)).await.unwrap();
+        let counters = db
+            .create::<U32, Counter>("counters")
+            .unwrap()
+            .unwrap()
+            .await
+            .unwrap();
+        let by_count = db
+            .create_sorted_index("by count", &counters, ByCount)
+            .await
+            .unwrap();
+        db.restore().await.unwrap();
+        (db, counters, by_count)
+    };
+    let count = |count: usize| Counter::new("", count);
+    let names = |records: Vec<(U32, Counter)>| {
+        records
+            .into_iter()
+            .map(|(_, counter)| counter.name)
+            .collect::<Vec<_>>()
+    };
+
+    let (db, counters, by_count) = open().await;
+    let c = counters.insert(Counter::new("c", 30)).await.unwrap();
+    counters.insert(Counter::new("a", 10)).await.unwrap();
+    let d = counters.insert(Counter::new("d", 40)).await.unwrap();
+    assert_eq!(by_count.first().await.unwrap().unwrap().1.name, "a");
+    assert_eq!(
+        by_count.last().await.unwrap(),
+        Some((d, Counter::new("d", 40)))
+    );
+
+    // Writes that arrive after the index is built keep it sorted
+    counters.insert(Counter::new("b", 20)).await.unwrap();
+    counters.delete(d).await.unwrap();
+    counters
+        .modify(c, |counter| counter.count = 5)
+        .await
+        .unwrap();
+    assert_eq!(
+        names(by_count.page(None, 10).await.unwrap()),
+        ["c", "a", "b"]
+    );
+    assert_eq!(
+        names(by_count.range(count(10)..=count(20)).await.unwrap()),
+        ["a", "b"]
+    );
+    assert_eq!(
+        names(by_count.range(count(10)..count(20)).await.unwrap()),
+        ["a"]
+    );
+    drop((db, counters, by_count));
+
+    // The index is built again from the restored records
+    let (_db, counters, by_count) = open().await;
+    counters.insert(Counter::new("e", 50)).await.unwrap();
+    let first = by_count.page(None, 2).await.unwrap();
+    let after = first.last().cloned();
+    assert_eq!(names(first), ["c", "a"]);
+    assert_eq!(names(by_count.page(after, 2).await.unwrap()), ["b", "e"]);
+}
+
+#[tokio::test]
+async fn sort_records_of_populated_trees_when_the_index_is_created() {
+    let path = clean_dir("tokactordb-sorted-backfill");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let counters = db
+        .create::<U32, Counter>("counters")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+    for (name, count) in [("b", 20), ("c", 30), ("a", 10)] {
+        counters.insert(Counter::new(name, count)).await.unwrap();
+    }
+
+    let by_count = db
+        .create_sorted_index("by count", &counters, ByCount)
+        .await
+        .unwrap();
+    // Writes made before the index is first read are kept in order
+    counters.insert(Counter::new("d", 5)).await.unwrap();
+    let names = by_count
+        .page(None, 10)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(_, counter)| counter.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["d", "a", "b", "c"]);
+}
+
+#[tokio::test]
+async fn reject_duplicate_identities_of_unique_indexes() {
+    let path = clean_dir("tokactordb-unique-index");
+    let open = || async {
+        let db = Database::new(FileSystem::system(&path)).await.unwrap();
+        let counters = db
+            .create::<U32, Counter>("counters")
+            .unwrap()
+            .unwrap()
+            .await
+            .unwrap();
+        let by_name = db
+            .create_unique_index("counter names", &counters, |counter| Some(&counter.name))
+            .await
+            .unwrap();
+        db.restore().await.unwrap();
+        (db, std::sync::Arc::new(counters), by_name)
+    };
+    let assert_rejected = |err: anyhow::Error, name: &str| {
+        let violation = err.downcast::<UniqueViolation>().unwrap();
+        assert_eq!(violation.index(), "counter names");
+        assert_eq!(violation.id(), format!("{:?}", name));
+    };
+
+    let (db, counters, by_name) = open().await;
+    let a = counters.insert(Counter::new("a", 1)).await.unwrap();
+    let b = counters.insert(Counter::new("b", 2)).await.unwrap();
+    assert_rejected(
+        counters.insert(Counter::new("a", 3)).await.unwrap_err(),
+        "a",
+    );
+    assert_rejected(
+        counters.update(b, Counter::new("a", 2)).await.unwrap_err(),
+        "a",
+    );
+    let renamed = counters.modify(b, |counter| counter.name = "a".to_string());
+    assert_rejected(renamed.await.unwrap_err(), "a");
+
+    let mut batch = db.batch();
+    batch
+        .insert(&*counters, Counter::new("a", 4))
+        .await
+        .unwrap();
+    assert_rejected(batch.commit().await.unwrap_err(), "a");
+
+    // Nothing that was rejected was written
+    let records = counters.list().try_collect::<Vec<_>>().await.unwrap();
+    assert_eq!(
+        records,
+        vec![(a, Counter::new("a", 1)), (b, Counter::new("b", 2))]
+    );
+    assert_eq!(
+        by_name.lookup("b".to_string()).await.unwrap(),
+        Some((b, Counter::new("b", 2)))
+    );
+
+    // Only one of many writers can take a free identity
+    let inserts = (0..10).map(|i| {
+        let counters = counters.clone();
+        tokio::spawn(async move { counters.insert(Counter::new("c", i)).await })
+    });
+    let inserted = futures::future::join_all(inserts).await;
+    assert_eq!(
+        inserted
+            .iter()
+            .filter(|insert| insert.as_ref().unwrap().is_ok())
+            .count(),
+        1
+    );
+
+    // Identities are freed by renaming and deleting records
+    counters
+        .modify(a, |counter| counter.name = "d".to_string())
+        .await
+        .unwrap();
+    counters.delete(b).await.unwrap();
+    assert_eq!(by_name.lookup("a".to_string()).await.unwrap(), None);
+    let e = counters.insert(Counter::new("b", 5)).await.unwrap();
+    drop((db, counters, by_name));
+
+    let (_db, counters, by_name) = open().await;
+    assert_eq!(
+        by_name.lookup("d".to_string()).await.unwrap(),
+        Some((a, Counter::new("d", 1)))
+    );
+    assert_eq!(
+        by_name.lookup("b".to_string()).await.unwrap(),
+        Some((e, Counter::new("b", 5)))
+    );
+    assert_rejected(
+        counters.insert(Counter::new("d", 6)).await.unwrap_err(),
+        "d",
+    );
+}
+
+#[tokio::test]
+async fn backfill_unique_indexes_created_on_populated_trees() {
+    let path = clean_dir("tokactordb-unique-backfill");
+    let db = Database::new(FileSystem::system(&path)).await.unwrap();
+    let counters = db
+        .create::<U32, Counter>("counters")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    let users = db
+        .create::<U32, Counter>("users")
+        .unwrap()
+        .unwrap()
+        .await
+        .unwrap();
+    db.restore().await.unwrap();
+
+    let a = counters.insert(Counter::new("a", 1)).await.unwrap();
+    counters.insert(Counter::new("b", 2)).await.unwrap();
+    users.insert(Counter::new("a", 1)).await.unwrap();
+    users.insert(Counter::new("a", 2)).await.unwrap();
+
+    let by_name = db
+        .create_unique_index("counter names", &counters, |counter| Some(&counter.name))
+        .await
+        .unwrap();
+    assert_eq!(
+        by_name.lookup("a".to_string()).await.unwrap(),
+        Some((a, Counter::new("a", 1)))
+    );
+    let err = counters.insert(Counter::new("b", 3)).await.unwrap_err();
+    assert!(err.is::<UniqueViolation>());
+
+    // Records that already share an identity can't be indexed
+    let err = db
+        .create_unique_index("user names", &users, |user| Some(&user.name))
+        .await
+        .err()
+        .unwrap();
+    let violation = err.downcast::<UniqueViolation>().unwrap();
+    assert_eq!(violation.index(), "user names");
+    assert_eq!(violation.id(), format!("{:?}", "a"));
+
+    // The index that failed to build doesn't get in the way of writes
+    users.insert(Counter::new("a", 3)).await.unwrap();
+    assert_eq!(users.list().try_collect::<Vec<_>>().await.unwrap().len(), 3);
+}